@@ -11,32 +11,41 @@ fn main() {
     }
 
     let src = env::current_dir().unwrap();
-    println!("cargo:include={}", src.join("brotli/include").display());
+    println!("cargo:include={}", src.join("brotli/c/include").display());
 
     cc::Build::new()
-        .include("brotli/include")
+        .include("brotli/c/include")
         .warnings(false)
-        .file("brotli/common/dictionary.c")
-        .file("brotli/dec/bit_reader.c")
-        .file("brotli/dec/decode.c")
-        .file("brotli/dec/huffman.c")
-        .file("brotli/dec/state.c")
-        .file("brotli/enc/backward_references.c")
-        .file("brotli/enc/backward_references_hq.c")
-        .file("brotli/enc/bit_cost.c")
-        .file("brotli/enc/block_splitter.c")
-        .file("brotli/enc/brotli_bit_stream.c")
-        .file("brotli/enc/cluster.c")
-        .file("brotli/enc/compress_fragment.c")
-        .file("brotli/enc/compress_fragment_two_pass.c")
-        .file("brotli/enc/dictionary_hash.c")
-        .file("brotli/enc/encode.c")
-        .file("brotli/enc/entropy_encode.c")
-        .file("brotli/enc/histogram.c")
-        .file("brotli/enc/literal_cost.c")
-        .file("brotli/enc/memory.c")
-        .file("brotli/enc/metablock.c")
-        .file("brotli/enc/static_dict.c")
-        .file("brotli/enc/utf8_util.c")
+        .file("brotli/c/common/constants.c")
+        .file("brotli/c/common/context.c")
+        .file("brotli/c/common/dictionary.c")
+        .file("brotli/c/common/platform.c")
+        .file("brotli/c/common/shared_dictionary.c")
+        .file("brotli/c/common/transform.c")
+        .file("brotli/c/dec/bit_reader.c")
+        .file("brotli/c/dec/decode.c")
+        .file("brotli/c/dec/huffman.c")
+        .file("brotli/c/dec/state.c")
+        .file("brotli/c/enc/backward_references.c")
+        .file("brotli/c/enc/backward_references_hq.c")
+        .file("brotli/c/enc/bit_cost.c")
+        .file("brotli/c/enc/block_splitter.c")
+        .file("brotli/c/enc/brotli_bit_stream.c")
+        .file("brotli/c/enc/cluster.c")
+        .file("brotli/c/enc/command.c")
+        .file("brotli/c/enc/compound_dictionary.c")
+        .file("brotli/c/enc/compress_fragment.c")
+        .file("brotli/c/enc/compress_fragment_two_pass.c")
+        .file("brotli/c/enc/dictionary_hash.c")
+        .file("brotli/c/enc/encode.c")
+        .file("brotli/c/enc/encoder_dict.c")
+        .file("brotli/c/enc/entropy_encode.c")
+        .file("brotli/c/enc/fast_log.c")
+        .file("brotli/c/enc/histogram.c")
+        .file("brotli/c/enc/literal_cost.c")
+        .file("brotli/c/enc/memory.c")
+        .file("brotli/c/enc/metablock.c")
+        .file("brotli/c/enc/static_dict.c")
+        .file("brotli/c/enc/utf8_util.c")
         .compile("libbrotli.a");
 }
@@ -1,5 +1,5 @@
 #![allow(bad_style)]
-#![doc(html_root_url = "https://docs.rs/brotli-sys/0.2")]
+#![doc(html_root_url = "https://docs.rs/brotli-sys/0.4")]
 
 extern crate libc;
 
@@ -16,11 +16,21 @@ pub type __enum_ty_s = libc::c_int;
 
 pub type brotli_alloc_func = Option<extern "C" fn(*mut c_void, size_t) -> *mut c_void>;
 pub type brotli_free_func = Option<extern "C" fn(*mut c_void, *mut c_void)>;
+pub type brotli_decoder_metadata_start_func = Option<extern "C" fn(*mut c_void, size_t)>;
+pub type brotli_decoder_metadata_chunk_func = Option<extern "C" fn(*mut c_void, *const u8, size_t)>;
+
+// ========== Shared dictionary functionality ==========
+
+pub type BrotliSharedDictionaryType = __enum_ty;
+
+pub const BROTLI_SHARED_DICTIONARY_RAW: BrotliSharedDictionaryType = 0;
+pub const BROTLI_SHARED_DICTIONARY_SERIALIZED: BrotliSharedDictionaryType = 1;
 
 // ========== Decoder functionality ==========
 
 pub type BrotliDecoderResult = __enum_ty;
 pub type BrotliDecoderErrorCode = __enum_ty_s;
+pub type BrotliDecoderParameter = __enum_ty;
 
 pub enum BrotliDecoderState {}
 
@@ -48,6 +58,9 @@ pub const BROTLI_DECODER_ERROR_FORMAT_DICTIONARY: BrotliDecoderErrorCode = -12;
 pub const BROTLI_DECODER_ERROR_FORMAT_WINDOW_BITS: BrotliDecoderErrorCode = -13;
 pub const BROTLI_DECODER_ERROR_FORMAT_PADDING_1: BrotliDecoderErrorCode = -14;
 pub const BROTLI_DECODER_ERROR_FORMAT_PADDING_2: BrotliDecoderErrorCode = -15;
+pub const BROTLI_DECODER_ERROR_FORMAT_DISTANCE: BrotliDecoderErrorCode = -16;
+pub const BROTLI_DECODER_ERROR_COMPOUND_DICTIONARY: BrotliDecoderErrorCode = -18;
+pub const BROTLI_DECODER_ERROR_DICTIONARY_NOT_SET: BrotliDecoderErrorCode = -19;
 pub const BROTLI_DECODER_ERROR_INVALID_ARGUMENTS: BrotliDecoderErrorCode = -20;
 pub const BROTLI_DECODER_ERROR_ALLOC_CONTEXT_MODES: BrotliDecoderErrorCode = -21;
 pub const BROTLI_DECODER_ERROR_ALLOC_TREE_GROUPS: BrotliDecoderErrorCode = -22;
@@ -57,12 +70,19 @@ pub const BROTLI_DECODER_ERROR_ALLOC_RING_BUFFER_2: BrotliDecoderErrorCode = -27
 pub const BROTLI_DECODER_ERROR_ALLOC_BLOCK_TYPE_TREES: BrotliDecoderErrorCode = -30;
 pub const BROTLI_DECODER_ERROR_UNREACHABLE: BrotliDecoderErrorCode = -31;
 
+pub const BROTLI_DECODER_PARAM_DISABLE_RING_BUFFER_REALLOCATION: BrotliDecoderParameter = 0;
+pub const BROTLI_DECODER_PARAM_LARGE_WINDOW: BrotliDecoderParameter = 1;
+
 extern "C" {
     pub fn BrotliDecoderCreateInstance(alloc_func: brotli_alloc_func,
                                        free_func: brotli_free_func,
                                        opaque: *mut c_void)
                                        -> *mut BrotliDecoderState;
     pub fn BrotliDecoderDestroyInstance(state: *mut BrotliDecoderState);
+    pub fn BrotliDecoderSetParameter(state: *mut BrotliDecoderState,
+                                     param: BrotliDecoderParameter,
+                                     value: u32)
+                                     -> c_int;
     pub fn BrotliDecoderDecompress(encoded_size: size_t,
                                    encoded_buffer: *const u8,
                                    decoded_size: *mut size_t,
@@ -75,9 +95,11 @@ extern "C" {
                                          next_out: *mut *mut u8,
                                          total_out: *mut size_t)
                                          -> BrotliDecoderResult;
-    pub fn BrotliDecoderSetCustomDictionary(state: *mut BrotliDecoderState,
-                                            size: size_t,
-                                            dict: *const u8);
+    pub fn BrotliDecoderAttachDictionary(state: *mut BrotliDecoderState,
+                                         dict_type: BrotliSharedDictionaryType,
+                                         data_size: size_t,
+                                         data: *const u8)
+                                         -> c_int;
     pub fn BrotliDecoderHasMoreOutput(state: *const BrotliDecoderState) -> c_int;
     pub fn BrotliDecoderTakeOutput(state: *mut BrotliDecoderState,
                                    size: *mut size_t)
@@ -87,6 +109,10 @@ extern "C" {
     pub fn BrotliDecoderGetErrorCode(state: *const BrotliDecoderState) -> BrotliDecoderErrorCode;
     pub fn BrotliDecoderErrorString(c: BrotliDecoderErrorCode) -> *const c_char;
     pub fn BrotliDecoderVersion() -> u32;
+    pub fn BrotliDecoderSetMetadataCallbacks(state: *mut BrotliDecoderState,
+                                             start_func: brotli_decoder_metadata_start_func,
+                                             chunk_func: brotli_decoder_metadata_chunk_func,
+                                             opaque: *mut c_void);
 }
 
 
@@ -107,17 +133,30 @@ pub const BROTLI_PARAM_LGWIN: BrotliEncoderParameter = 2;
 pub const BROTLI_PARAM_LGBLOCK: BrotliEncoderParameter = 3;
 pub const BROTLI_PARAM_DISABLE_LITERAL_CONTEXT_MODELING: BrotliEncoderParameter = 4;
 pub const BROTLI_PARAM_SIZE_HINT: BrotliEncoderParameter = 5;
+pub const BROTLI_PARAM_LARGE_WINDOW: BrotliEncoderParameter = 6;
+pub const BROTLI_PARAM_NPOSTFIX: BrotliEncoderParameter = 7;
+pub const BROTLI_PARAM_NDIRECT: BrotliEncoderParameter = 8;
+pub const BROTLI_PARAM_STREAM_OFFSET: BrotliEncoderParameter = 9;
 
 pub const BROTLI_OPERATION_PROCESS: BrotliEncoderOperation = 0;
 pub const BROTLI_OPERATION_FLUSH: BrotliEncoderOperation = 1;
 pub const BROTLI_OPERATION_FINISH: BrotliEncoderOperation = 2;
 pub const BROTLI_OPERATION_EMIT_METADATA: BrotliEncoderOperation = 3;
 
+pub const BROTLI_MIN_QUALITY: u32 = 0;
+pub const BROTLI_MAX_QUALITY: u32 = 11;
+pub const BROTLI_MIN_WINDOW_BITS: u32 = 10;
+pub const BROTLI_MAX_WINDOW_BITS: u32 = 24;
+pub const BROTLI_LARGE_MAX_WINDOW_BITS: u32 = 30;
+pub const BROTLI_MIN_INPUT_BLOCK_BITS: u32 = 16;
+pub const BROTLI_MAX_INPUT_BLOCK_BITS: u32 = 24;
+
 pub const BROTLI_DEFAULT_QUALITY: u32 = 11;
 pub const BROTLI_DEFAULT_WINDOW: u32 = 22;
 pub const BROTLI_DEFAULT_MODE: u32 = 0;
 
 pub enum BrotliEncoderState {}
+pub enum BrotliEncoderPreparedDictionary {}
 
 extern "C" {
     pub fn BrotliEncoderSetParameter(state: *mut BrotliEncoderState,
@@ -140,9 +179,18 @@ extern "C" {
     //                              out_size: *mut size_t,
     //                              output: *mut *mut u8)
     //                              -> c_int;
-    pub fn BrotliEncoderSetCustomDictionary(state: *mut BrotliEncoderState,
-                                            size: size_t,
-                                            dict: *const u8);
+    pub fn BrotliEncoderPrepareDictionary(dict_type: BrotliSharedDictionaryType,
+                                          data_size: size_t,
+                                          data: *const u8,
+                                          quality: c_int,
+                                          alloc_func: brotli_alloc_func,
+                                          free_func: brotli_free_func,
+                                          opaque: *mut c_void)
+                                          -> *mut BrotliEncoderPreparedDictionary;
+    pub fn BrotliEncoderDestroyPreparedDictionary(dictionary: *mut BrotliEncoderPreparedDictionary);
+    pub fn BrotliEncoderAttachPreparedDictionary(state: *mut BrotliEncoderState,
+                                                 dictionary: *const BrotliEncoderPreparedDictionary)
+                                                 -> c_int;
     pub fn BrotliEncoderMaxCompressedSize(input_size: size_t) -> size_t;
     pub fn BrotliEncoderCompress(quality: c_int,
                                  lgwin: c_int,
@@ -167,3 +215,48 @@ extern "C" {
                                    -> *const u8;
     pub fn BrotliEncoderVersion() -> u32;
 }
+
+// ========== Removed from libbrotli ==========
+
+/// Sets a raw prefix dictionary for a decoder.
+///
+/// libbrotli 1.1 removed this function in favor of the shared dictionary API,
+/// and this forwards to `BrotliDecoderAttachDictionary`. As before, `dict`
+/// must stay alive and unchanged for as long as `state` is used.
+#[deprecated(since = "0.4.0", note = "use `BrotliDecoderAttachDictionary` instead")]
+pub unsafe fn BrotliDecoderSetCustomDictionary(state: *mut BrotliDecoderState,
+                                               size: size_t,
+                                               dict: *const u8) {
+    BrotliDecoderAttachDictionary(state, BROTLI_SHARED_DICTIONARY_RAW, size, dict);
+}
+
+/// Sets a raw prefix dictionary for an encoder.
+///
+/// libbrotli 1.1 removed this function in favor of the shared dictionary API.
+/// This copies `dict` and prepares it with `BrotliEncoderPrepareDictionary`,
+/// since the encoder refers to both for as long as it is used, and neither is
+/// ever freed. Use `BrotliEncoderPrepareDictionary` and
+/// `BrotliEncoderAttachPreparedDictionary` directly to manage their lifetime.
+#[deprecated(since = "0.4.0",
+             note = "use `BrotliEncoderPrepareDictionary` and \
+                     `BrotliEncoderAttachPreparedDictionary` instead")]
+pub unsafe fn BrotliEncoderSetCustomDictionary(state: *mut BrotliEncoderState,
+                                               size: size_t,
+                                               dict: *const u8) {
+    if size == 0 {
+        return;
+    }
+    let data = Box::into_raw(std::slice::from_raw_parts(dict, size)
+                                 .to_vec()
+                                 .into_boxed_slice());
+    let prepared = BrotliEncoderPrepareDictionary(BROTLI_SHARED_DICTIONARY_RAW,
+                                                  size,
+                                                  data as *const u8,
+                                                  BROTLI_MAX_QUALITY as c_int,
+                                                  None,
+                                                  None,
+                                                  std::ptr::null_mut());
+    if !prepared.is_null() {
+        BrotliEncoderAttachPreparedDictionary(state, prepared);
+    }
+}
@@ -37,7 +37,7 @@ fn main() {
         read::BrotliEncoder::from_params(data, params)
             .read_to_end(&mut buf)
             .unwrap();
-        assert!(buf.len() > 0);
+        assert!(!buf.is_empty());
         buf
     }
     fn ioreaddecode(data: &[u8]) -> Vec<u8> {
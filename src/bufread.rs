@@ -1,12 +1,21 @@
 //! I/O streams for wrapping `BufRead` types as encoders/decoders
 
+use std::cmp;
+use std::fmt;
 use std::io;
 use std::io::prelude::*;
+#[cfg(feature = "nightly")]
+use std::io::BorrowedCursor;
+use std::io::IoSliceMut;
+use std::mem;
 
-use super::CompressParams;
-use raw::{self, CoStatus, Compress, CompressOp, DeStatus, Decompress};
+use super::{CompressParams, DecompressParams, Stats};
+use raw::{
+    self, CoStatus, Compress, CompressOp, DeStatus, Decompress, Dictionary, ErrorKind,
+    MetadataPolicy,
+};
 
-#[derive(Clone, Copy, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 enum DoneStatus {
     Processing,
     Finishing,
@@ -22,6 +31,8 @@ pub struct BrotliEncoder<R: BufRead> {
     data: Compress,
     done: DoneStatus,
     err: Option<raw::Error>,
+    flush_on_would_block: bool,
+    stats: Option<Stats>,
 }
 
 /// A brotli decoder, or decompressor.
@@ -32,6 +43,11 @@ pub struct BrotliDecoder<R: BufRead> {
     obj: R,
     data: Decompress,
     err: Option<raw::Error>,
+    strict: bool,
+    tolerant: bool,
+    truncated: bool,
+    max_output: Option<u64>,
+    stats: Option<Stats>,
 }
 
 impl<R: BufRead> BrotliEncoder<R> {
@@ -40,28 +56,92 @@ impl<R: BufRead> BrotliEncoder<R> {
     ///
     /// The `level` argument here is typically 0-11.
     pub fn new(r: R, level: u32) -> BrotliEncoder<R> {
-        let mut data = Compress::new();
-        data.set_params(CompressParams::new().quality(level));
-        BrotliEncoder {
-            obj: r,
-            data: data,
-            done: DoneStatus::Processing,
-            err: None,
-        }
+        BrotliEncoder::build(r, CompressParams::new().quality(level), None)
     }
 
     /// Creates a new encoder with a custom `CompressParams`.
     pub fn from_params(r: R, params: &CompressParams) -> BrotliEncoder<R> {
+        BrotliEncoder::build(r, params, None)
+    }
+
+    /// Creates a new encoder with a custom `CompressParams` which compresses
+    /// against the prefix dictionary `dict`.
+    ///
+    /// The resulting stream can only be decompressed with the same dictionary.
+    pub fn with_dictionary(r: R, params: &CompressParams, dict: &Dictionary) -> BrotliEncoder<R> {
+        BrotliEncoder::build(r, params, Some(dict))
+    }
+
+    pub(crate) fn build(
+        r: R,
+        params: &CompressParams,
+        dict: Option<&Dictionary>,
+    ) -> BrotliEncoder<R> {
         let mut data = Compress::new();
-        data.set_params(params);
+        let err = data
+            .set_params(params)
+            .and_then(|()| dict.map_or(Ok(()), |dict| data.attach_dictionary(dict)))
+            .err();
         BrotliEncoder {
             obj: r,
-            data: data,
+            data,
             done: DoneStatus::Processing,
-            err: None,
+            err,
+            flush_on_would_block: false,
+            stats: None,
         }
     }
 
+    /// Sets whether to flush the compressed stream when the underlying
+    /// stream has no data available.
+    ///
+    /// By default, an `io::ErrorKind::WouldBlock` error from the underlying
+    /// stream is returned as is, and data read before it may stay buffered in
+    /// the compressor until more input arrives. With this enabled, reading
+    /// first flushes the compressed stream so that all input read so far can
+    /// be decompressed, and only returns the error once nothing is left to
+    /// flush. This suits live or non-blocking sources.
+    pub fn set_flush_on_would_block(&mut self, flush: bool) {
+        self.flush_on_would_block = flush;
+    }
+
+    /// Replaces the compression parameters of this encoder.
+    ///
+    /// This allows tuning an encoder created elsewhere, for example by generic
+    /// code, but only before compression starts. Once data has been read from
+    /// the encoder, this fails with an error of kind
+    /// `io::ErrorKind::InvalidInput` wrapping a `raw::Error` of kind
+    /// `ErrorKind::AlreadyStarted`, and the parameters are left unchanged.
+    pub fn set_params(&mut self, params: &CompressParams) -> io::Result<()> {
+        self.data.set_params(params)?;
+        Ok(())
+    }
+
+    /// Starts collecting `Stats` for this encoder, as returned by `stats`.
+    pub fn enable_stats(&mut self) {
+        self.stats.get_or_insert_with(Stats::default);
+    }
+
+    /// Returns the statistics collected since `enable_stats` was called, or
+    /// `None` if it was not.
+    ///
+    /// The byte counts cover the whole stream, and `reset` starts the
+    /// statistics over.
+    pub fn stats(&self) -> Option<Stats> {
+        Stats::with_totals(self.stats, self.data.total_in(), self.data.total_out())
+    }
+
+    /// Returns the number of uncompressed bytes read from the underlying
+    /// stream and compressed so far.
+    pub fn total_in(&self) -> u64 {
+        self.data.total_in()
+    }
+
+    /// Returns the number of compressed bytes produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.data.total_out()
+    }
+
     /// Acquires a reference to the underlying stream
     pub fn get_ref(&self) -> &R {
         &self.obj
@@ -75,14 +155,48 @@ impl<R: BufRead> BrotliEncoder<R> {
         &mut self.obj
     }
 
+    /// Resets this encoder to process a new stream read from `r`, returning
+    /// the previous underlying stream.
+    ///
+    /// The parameters and dictionary it was created with are kept, and any
+    /// data buffered from the previous stream is discarded.
+    pub fn reset(&mut self, r: R) -> R {
+        self.restart();
+        mem::replace(&mut self.obj, r)
+    }
+
+    // Resets the brotli state for a new stream. Errors from configuring it
+    // are kept, since they would otherwise be silently ignored.
+    pub(crate) fn restart(&mut self) {
+        match self.err {
+            Some(ref err) if err.is_config() => {}
+            _ => self.err = self.data.reset().err(),
+        }
+        self.done = DoneStatus::Processing;
+        if self.stats.is_some() {
+            self.stats = Some(Stats::default());
+        }
+    }
+
     /// Consumes this encoder, returning the underlying reader.
     pub fn into_inner(self) -> R {
         self.obj
     }
 }
 
-impl<R: BufRead> Read for BrotliEncoder<R> {
-    fn read(&mut self, mut buf: &mut [u8]) -> io::Result<usize> {
+impl<R: BufRead + fmt::Debug> fmt::Debug for BrotliEncoder<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BrotliEncoder")
+            .field("obj", &self.obj)
+            .field("data", &self.data)
+            .field("done", &self.done)
+            .field("err", &self.err)
+            .finish()
+    }
+}
+
+impl<R: BufRead> BrotliEncoder<R> {
+    fn compress_into(&mut self, mut buf: &mut [u8]) -> io::Result<usize> {
         if buf.is_empty() {
             return Ok(0);
         }
@@ -108,7 +222,17 @@ impl<R: BufRead> Read for BrotliEncoder<R> {
             let amt_in;
             let amt_out;
             {
-                let input = &mut self.obj.fill_buf()?;
+                let input = &mut match self.obj.fill_buf() {
+                    Ok(input) => input,
+                    Err(ref err)
+                        if self.flush_on_would_block && err.kind() == io::ErrorKind::WouldBlock =>
+                    {
+                        let written = flush(&mut self.data, &mut self.err, buf)?;
+                        Stats::count_flush(&mut self.stats);
+                        return Ok(written);
+                    }
+                    Err(err) => return Err(err),
+                };
                 let avail_in = input.len();
                 if avail_in == 0 {
                     break;
@@ -116,7 +240,7 @@ impl<R: BufRead> Read for BrotliEncoder<R> {
                 let output = &mut buf;
                 let avail_out = output.len();
                 if let Err(err) = self.data.compress(CompressOp::Process, input, output) {
-                    self.err = Some(err.clone().into());
+                    self.err = Some(err.clone());
                     return Err(err.into());
                 }
                 amt_in = avail_in - input.len();
@@ -133,6 +257,25 @@ impl<R: BufRead> Read for BrotliEncoder<R> {
         self.done = DoneStatus::Finishing;
         return tryfinish(self, buf);
 
+        // Flushes what has been compressed so far, if anything, or returns
+        // `WouldBlock` again.
+        fn flush(
+            data: &mut Compress,
+            err: &mut Option<raw::Error>,
+            mut buf: &mut [u8],
+        ) -> io::Result<usize> {
+            let output = &mut buf;
+            let avail_out = output.len();
+            if let Err(e) = data.compress(CompressOp::Flush, &mut &[][..], output) {
+                *err = Some(e.clone());
+                return Err(e.into());
+            }
+            match avail_out - output.len() {
+                0 => Err(io::ErrorKind::WouldBlock.into()),
+                written => Ok(written),
+            }
+        }
+
         fn tryfinish<R: BufRead>(
             enc: &mut BrotliEncoder<R>,
             mut buf: &mut [u8],
@@ -142,7 +285,7 @@ impl<R: BufRead> Read for BrotliEncoder<R> {
             let iscomplete = match enc.data.compress(CompressOp::Finish, &mut &[][..], output) {
                 Ok(c) => c,
                 Err(err) => {
-                    enc.err = err.clone().into();
+                    enc.err = Some(err.clone());
                     return Err(err.into());
                 }
             };
@@ -156,17 +299,190 @@ impl<R: BufRead> Read for BrotliEncoder<R> {
     }
 }
 
+impl<R: BufRead> Read for BrotliEncoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = Stats::start(&self.stats);
+        let result = self.compress_into(buf);
+        Stats::record(&mut self.stats, start);
+        result
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        let mut bufs = bufs.iter_mut().filter(|buf| !buf.is_empty());
+        let first = match bufs.next() {
+            Some(buf) => buf,
+            None => return Ok(0),
+        };
+        let mut total = self.read(first)?;
+        if total < first.len() {
+            return Ok(total);
+        }
+        // Fill the rest with output that is already available, without
+        // reading more input.
+        for buf in bufs {
+            let mut pos = 0;
+            while let Some(data) = self.data.take_output(Some(buf.len() - pos)) {
+                buf[pos..pos + data.len()].copy_from_slice(data);
+                pos += data.len();
+            }
+            total += pos;
+            if pos < buf.len() {
+                break;
+            }
+        }
+        Ok(total)
+    }
+}
+
 impl<R: BufRead> BrotliDecoder<R> {
     /// Creates a new decoder which will decompress data read from the given
     /// stream.
     pub fn new(r: R) -> BrotliDecoder<R> {
+        BrotliDecoder::build(r, &DecompressParams::new(), None)
+    }
+
+    /// Creates a new decoder with a custom `DecompressParams`.
+    pub fn from_params(r: R, params: &DecompressParams) -> BrotliDecoder<R> {
+        BrotliDecoder::build(r, params, None)
+    }
+
+    /// Creates a new decoder which will decompress data read from the given
+    /// stream, which was compressed against the prefix dictionary `dict`.
+    pub fn with_dictionary(r: R, dict: &Dictionary) -> BrotliDecoder<R> {
+        BrotliDecoder::build(r, &DecompressParams::new(), Some(dict))
+    }
+
+    pub(crate) fn build(
+        r: R,
+        params: &DecompressParams,
+        dict: Option<&Dictionary>,
+    ) -> BrotliDecoder<R> {
+        let (data, err) = new_decompress(params, dict);
         BrotliDecoder {
-            data: Decompress::new(),
+            data,
             obj: r,
-            err: None,
+            err,
+            strict: false,
+            tolerant: false,
+            truncated: false,
+            max_output: None,
+            stats: None,
+        }
+    }
+
+    /// Sets the maximum number of bytes this decoder may decompress.
+    ///
+    /// Once a stream would decompress to more than `limit` bytes, reading fails
+    /// with an error of kind `io::ErrorKind::InvalidData` wrapping a
+    /// `raw::Error` of kind `ErrorKind::LimitExceeded`, and the data past the
+    /// limit is never returned. This protects against small inputs that expand
+    /// to huge amounts of data. `None`, the default, removes the limit.
+    pub fn set_max_output(&mut self, limit: Option<u64>) {
+        self.max_output = limit;
+    }
+
+    /// Sets whether data following the end of the brotli stream is an
+    /// error.
+    ///
+    /// By default such data is left unread in the underlying stream. In
+    /// strict mode, reading fails with an error of kind
+    /// `io::ErrorKind::InvalidData` once the stream has ended if more data
+    /// follows it.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Sets whether a stream that ends early is decoded as far as possible.
+    ///
+    /// By default, reading fails with an error if the underlying stream ends
+    /// in the middle of the brotli stream. In tolerant mode, the data decoded
+    /// up to that point is returned instead, reading then reports the end of
+    /// the data, and `is_truncated` returns `true`.
+    pub fn set_tolerant(&mut self, tolerant: bool) {
+        self.tolerant = tolerant;
+    }
+
+    /// Returns whether the brotli stream was found to be truncated, which
+    /// is only reported in tolerant mode.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Returns whether the end of the brotli stream has been reached and all
+    /// of its data has been read.
+    ///
+    /// The decoder never consumes input past the end of the stream, so any
+    /// data following it is left in the underlying stream, available through
+    /// `get_mut`, and `total_in` is then the length of the compressed stream.
+    pub fn is_finished(&self) -> bool {
+        self.data.is_finished()
+    }
+
+    /// Decodes the rest of the stream into `w`, returning the number of bytes
+    /// written.
+    ///
+    /// Output is written straight from brotli's output buffer, so unlike
+    /// `io::copy` no intermediate buffer is used. `w` is not flushed.
+    pub fn copy_to<W: Write + ?Sized>(&mut self, w: &mut W) -> io::Result<u64> {
+        self.copy_to_with(w, |_, _| Ok(()))
+    }
+
+    // Like `copy_to`, calling `chunk` with `total_in` and `total_out` after
+    // each chunk is written, and stopping at the first error it returns.
+    pub(crate) fn copy_to_with<W, F>(&mut self, w: &mut W, mut chunk: F) -> io::Result<u64>
+    where
+        W: Write + ?Sized,
+        F: FnMut(u64, u64) -> io::Result<()>,
+    {
+        let mut total = 0;
+        loop {
+            let len = match self.fill_buf() {
+                Ok([]) => return Ok(total),
+                Ok(data) => {
+                    w.write_all(data)?;
+                    data.len()
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            self.consume(len);
+            total += len as u64;
+            chunk(self.total_in(), self.total_out())?;
         }
     }
 
+    /// Sets how metadata blocks in the compressed stream are handled.
+    ///
+    /// By default they are skipped. See `MetadataPolicy` for the choices.
+    pub fn set_metadata_policy(&mut self, policy: MetadataPolicy) {
+        self.data.set_metadata_policy(policy);
+    }
+
+    /// Starts collecting `Stats` for this decoder, as returned by `stats`.
+    pub fn enable_stats(&mut self) {
+        self.stats.get_or_insert_with(Stats::default);
+    }
+
+    /// Returns the statistics collected since `enable_stats` was called, or
+    /// `None` if it was not.
+    ///
+    /// The byte counts cover the whole stream, and `reset` starts the
+    /// statistics over.
+    pub fn stats(&self) -> Option<Stats> {
+        Stats::with_totals(self.stats, self.data.total_in(), self.data.total_out())
+    }
+
+    /// Returns the number of compressed bytes read from the underlying
+    /// stream and decompressed so far.
+    pub fn total_in(&self) -> u64 {
+        self.data.total_in()
+    }
+
+    /// Returns the number of decompressed bytes produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.data.total_out()
+    }
+
     /// Acquires a reference to the underlying stream
     pub fn get_ref(&self) -> &R {
         &self.obj
@@ -180,17 +496,60 @@ impl<R: BufRead> BrotliDecoder<R> {
         &mut self.obj
     }
 
+    /// Resets this decoder to process a new stream read from `r`, returning
+    /// the previous underlying stream.
+    ///
+    /// The parameters and dictionary it was created with are kept, and any
+    /// data buffered from the previous stream is discarded.
+    pub fn reset(&mut self, r: R) -> R {
+        self.restart();
+        mem::replace(&mut self.obj, r)
+    }
+
+    // Resets the brotli state for a new stream. Errors from configuring it
+    // are kept, since they would otherwise be silently ignored.
+    pub(crate) fn restart(&mut self) {
+        match self.err {
+            Some(ref err) if err.is_config() => {}
+            _ => self.err = self.data.reset().err(),
+        }
+        self.truncated = false;
+        if self.stats.is_some() {
+            self.stats = Some(Stats::default());
+        }
+    }
+
     /// Consumes this decoder, returning the underlying reader.
+    ///
+    /// The decoder only consumes the bytes of the brotli stream from the
+    /// reader, so any bytes following the stream can still be read from it.
     pub fn into_inner(self) -> R {
         self.obj
     }
 }
 
-impl<R: BufRead> Read for BrotliDecoder<R> {
-    fn read(&mut self, mut buf: &mut [u8]) -> io::Result<usize> {
-        if buf.is_empty() {
-            return Ok(0);
-        }
+impl<R: BufRead + fmt::Debug> fmt::Debug for BrotliDecoder<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BrotliDecoder")
+            .field("obj", &self.obj)
+            .field("data", &self.data)
+            .field("err", &self.err)
+            .finish()
+    }
+}
+
+impl<R: BufRead> BrotliDecoder<R> {
+    // Decodes until output is ready or the stream has ended, returning the
+    // output straight from the decoder's ring buffer.
+    fn fill_output(&mut self) -> io::Result<&[u8]> {
+        let start = Stats::start(&self.stats);
+        let result = self.decode();
+        Stats::record(&mut self.stats, start);
+        result?;
+        Ok(self.data.fill_output())
+    }
+
+    fn decode(&mut self) -> io::Result<()> {
         // If the decompressor has failed at some point, this is set.
         // Unfortunately we have no idea what status is in the compressor
         // was in when it failed so we can't do anything except bail again.
@@ -198,38 +557,227 @@ impl<R: BufRead> Read for BrotliDecoder<R> {
             return Err(err.clone().into());
         }
 
-        loop {
+        while self.data.fill_output().is_empty() && !self.data.is_finished() {
             let amt_in;
-            let amt_out;
             let status;
             {
                 let mut input = self.obj.fill_buf()?;
                 let avail_in = input.len();
-                let avail_out = buf.len();
-                status = match self.data.decompress(&mut input, &mut buf) {
+                // Leave the output in the decoder, to be taken from there.
+                status = match self.data.decompress(&mut input, &mut &mut [][..]) {
                     Ok(s) => s,
                     Err(err) => {
-                        self.err = Some(err.clone().into());
+                        self.err = Some(err.clone());
                         return Err(err.into());
                     }
                 };
                 amt_in = avail_in - input.len();
-                amt_out = avail_out - buf.len()
             }
             self.obj.consume(amt_in);
 
             if amt_in == 0 && status == DeStatus::NeedInput {
+                if self.tolerant {
+                    self.truncated = true;
+                    break;
+                }
                 return Err(io::Error::new(
                     io::ErrorKind::Other,
                     "corrupted brotli stream",
                 ));
             }
-            if amt_out == 0 && status != DeStatus::Finished {
-                assert!(amt_in != 0);
-                continue;
+        }
+        if self.data.is_finished() && self.strict && !self.obj.fill_buf()?.is_empty() {
+            return Err(trailing_data());
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for BrotliDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let amt = {
+            let output = self.fill_buf()?;
+            let amt = cmp::min(output.len(), buf.len());
+            buf[..amt].copy_from_slice(&output[..amt]);
+            amt
+        };
+        self.consume(amt);
+        Ok(amt)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        let mut bufs = bufs.iter_mut().filter(|buf| !buf.is_empty());
+        let first = match bufs.next() {
+            Some(buf) => buf,
+            None => return Ok(0),
+        };
+        let mut total = self.read(first)?;
+        if total < first.len() {
+            return Ok(total);
+        }
+        // Fill the rest with output that is already decoded, without reading
+        // more input.
+        for buf in bufs {
+            let mut pos = 0;
+            while pos < buf.len() {
+                let remaining = self
+                    .max_output
+                    .map_or(u64::MAX, |max| max.saturating_sub(self.data.total_out()));
+                let amt = {
+                    let output = self.data.fill_output();
+                    let amt = cmp::min(output.len(), buf.len() - pos);
+                    let amt = cmp::min(amt as u64, remaining) as usize;
+                    buf[pos..pos + amt].copy_from_slice(&output[..amt]);
+                    amt
+                };
+                if amt == 0 {
+                    break;
+                }
+                self.data.consume_output(amt);
+                pos += amt;
             }
+            total += pos;
+            if pos < buf.len() {
+                break;
+            }
+        }
+        Ok(total)
+    }
 
-            return Ok(amt_out);
+    #[cfg(feature = "nightly")]
+    fn read_buf(&mut self, mut cursor: BorrowedCursor) -> io::Result<()> {
+        if cursor.capacity() == 0 {
+            return Ok(());
+        }
+        let amt = {
+            let output = self.fill_buf()?;
+            let amt = cmp::min(output.len(), cursor.capacity());
+            cursor.append(&output[..amt]);
+            amt
+        };
+        self.consume(amt);
+        Ok(())
+    }
+}
+
+impl<R: BufRead> BufRead for BrotliDecoder<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let max_output = self.max_output;
+        let total_out = self.data.total_out();
+        let (len, exceeded) = {
+            let output = self.fill_output()?;
+            match max_output {
+                Some(max) => {
+                    let remaining = max.saturating_sub(total_out);
+                    let len = cmp::min(output.len() as u64, remaining) as usize;
+                    (len, output.len() as u64 > remaining)
+                }
+                None => (output.len(), false),
+            }
+        };
+        // Hand out the data up to the limit before failing.
+        if exceeded && len == 0 {
+            let err = raw::Error::new(ErrorKind::LimitExceeded, "decompressing");
+            self.err = Some(err.clone());
+            return Err(err.into());
+        }
+        Ok(&self.data.fill_output()[..len])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.data.consume_output(amt);
+    }
+}
+
+/// A brotli decoder which decodes any number of brotli streams that follow
+/// each other in the underlying stream.
+///
+/// When one stream ends and more input follows it, a new stream is decoded
+/// from that input, with the same parameters and dictionary. This is
+/// appropriate for data made by concatenating independently compressed
+/// segments, while `BrotliDecoder` stops at the end of the first stream.
+#[derive(Debug)]
+pub struct MultiBrotliDecoder<R: BufRead> {
+    inner: BrotliDecoder<R>,
+}
+
+impl<R: BufRead> MultiBrotliDecoder<R> {
+    /// Creates a new decoder which will decompress data read from the given
+    /// stream.
+    pub fn new(r: R) -> MultiBrotliDecoder<R> {
+        MultiBrotliDecoder {
+            inner: BrotliDecoder::new(r),
+        }
+    }
+
+    /// Creates a new decoder with a custom `DecompressParams`.
+    pub fn from_params(r: R, params: &DecompressParams) -> MultiBrotliDecoder<R> {
+        MultiBrotliDecoder {
+            inner: BrotliDecoder::from_params(r, params),
+        }
+    }
+
+    /// Creates a new decoder which will decompress data read from the given
+    /// stream, where every stream was compressed against the prefix
+    /// dictionary `dict`.
+    pub fn with_dictionary(r: R, dict: &Dictionary) -> MultiBrotliDecoder<R> {
+        MultiBrotliDecoder {
+            inner: BrotliDecoder::with_dictionary(r, dict),
+        }
+    }
+
+    /// Acquires a reference to the underlying stream
+    pub fn get_ref(&self) -> &R {
+        self.inner.get_ref()
+    }
+
+    /// Acquires a mutable reference to the underlying stream
+    ///
+    /// Note that mutation of the stream may result in surprising results if
+    /// this decoder is continued to be used.
+    pub fn get_mut(&mut self) -> &mut R {
+        self.inner.get_mut()
+    }
+
+    /// Consumes this decoder, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner()
+    }
+}
+
+impl<R: BufRead> Read for MultiBrotliDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.inner.read(buf)?;
+            if n > 0 || buf.is_empty() || self.inner.obj.fill_buf()?.is_empty() {
+                return Ok(n);
+            }
+            self.inner.restart();
         }
     }
 }
+
+// Creates a decompressor with `params` and `dict`, returning the error to
+// report on first use if that fails.
+pub(crate) fn new_decompress(
+    params: &DecompressParams,
+    dict: Option<&Dictionary>,
+) -> (Decompress, Option<raw::Error>) {
+    match Decompress::with_params(params) {
+        Ok(mut data) => {
+            let err = dict.and_then(|dict| data.attach_dictionary(dict).err());
+            (data, err)
+        }
+        Err(err) => (Decompress::new(), Some(err)),
+    }
+}
+
+pub(crate) fn trailing_data() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "data found after the end of the brotli stream",
+    )
+}
@@ -23,24 +23,140 @@
 //! ```
 
 #![deny(missing_docs)]
-#![doc(html_root_url = "https://docs.rs/brotli2/0.2")]
+#![cfg_attr(feature = "nightly", feature(core_io_borrowed_buf, read_buf))]
+#![doc(html_root_url = "https://docs.rs/brotli2/0.4")]
 
+#[cfg(feature = "fuzzing")]
+extern crate arbitrary;
 extern crate brotli_sys;
+#[cfg(feature = "bytes")]
+extern crate bytes;
 extern crate libc;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
 
 #[cfg(test)]
 extern crate quickcheck;
 #[cfg(test)]
 extern crate rand;
 
+use std::cmp;
+use std::error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, Read, Write};
+use std::ops::{Deref, RangeInclusive};
+use std::path::Path;
+use std::process;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
 pub mod bufread;
+#[cfg(all(feature = "mmap", unix))]
+pub mod mmap;
 pub mod raw;
 pub mod read;
 pub mod write;
 
+use raw::Dictionary;
+
+/// Version numbers of the linked libbrotli encoder and decoder.
+///
+/// The encoder and decoder are separate libraries, which are normally, but not
+/// necessarily, of the same version.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LibraryVersion {
+    encoder: Version,
+    decoder: Version,
+}
+
+/// A libbrotli version number.
+///
+/// Versions compare in release order, so a minimum version can be checked
+/// with `>=`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    major: u32,
+    minor: u32,
+    patch: u32,
+}
+
+/// Returns the versions of the libbrotli encoder and decoder this crate is
+/// linked against.
+///
+/// # Examples
+///
+/// ```
+/// let version = brotli2::version();
+/// println!("using brotli {}", version.decoder());
+/// assert!(version.decoder() >= brotli2::Version::new(1, 0, 0));
+/// ```
+pub fn version() -> LibraryVersion {
+    unsafe {
+        LibraryVersion {
+            encoder: Version::from_raw(brotli_sys::BrotliEncoderVersion()),
+            decoder: Version::from_raw(brotli_sys::BrotliDecoderVersion()),
+        }
+    }
+}
+
+impl LibraryVersion {
+    /// Returns the version of the libbrotli encoder.
+    pub fn encoder(&self) -> Version {
+        self.encoder
+    }
+
+    /// Returns the version of the libbrotli decoder.
+    pub fn decoder(&self) -> Version {
+        self.decoder
+    }
+}
+
+impl Version {
+    /// Creates a version number from its components.
+    pub fn new(major: u32, minor: u32, patch: u32) -> Version {
+        Version {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    // libbrotli packs versions as `major << 24 | minor << 12 | patch`.
+    fn from_raw(v: u32) -> Version {
+        Version::new(v >> 24, (v >> 12) & 0xfff, v & 0xfff)
+    }
+
+    /// Returns the major version number.
+    pub fn major(&self) -> u32 {
+        self.major
+    }
+
+    /// Returns the minor version number.
+    pub fn minor(&self) -> u32 {
+        self.minor
+    }
+
+    /// Returns the patch version number.
+    pub fn patch(&self) -> u32 {
+        self.patch
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
 /// Possible choices for modes of compression
 #[repr(isize)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum CompressMode {
     /// Default compression mode, the compressor does not know anything in
     /// advance about the properties of the input.
@@ -51,19 +167,254 @@ pub enum CompressMode {
     Font = brotli_sys::BROTLI_MODE_FONT as isize,
 }
 
+impl CompressMode {
+    // Unknown modes are treated as generic, as libbrotli does.
+    fn from_raw(mode: u32) -> CompressMode {
+        match mode {
+            brotli_sys::BROTLI_MODE_TEXT => CompressMode::Text,
+            brotli_sys::BROTLI_MODE_FONT => CompressMode::Font,
+            _ => CompressMode::Generic,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            CompressMode::Generic => "generic",
+            CompressMode::Text => "text",
+            CompressMode::Font => "font",
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+mod mode_serde {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::CompressMode;
+
+    pub fn serialize<S: Serializer>(mode: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+        CompressMode::from_raw(*mode).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+        CompressMode::deserialize(deserializer).map(|mode| mode as u32)
+    }
+}
+
+/// Formats the mode as `generic`, `text` or `font`, the names accepted by
+/// its `FromStr` implementation.
+impl fmt::Display for CompressMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Parses `generic`, `text` or `font`, ignoring ASCII case.
+///
+/// # Examples
+///
+/// ```
+/// use brotli2::CompressMode;
+///
+/// let mode: CompressMode = "text".parse().unwrap();
+/// assert_eq!(mode, CompressMode::Text);
+/// assert_eq!(mode.to_string(), "text");
+/// assert!("binary".parse::<CompressMode>().is_err());
+/// ```
+impl FromStr for CompressMode {
+    type Err = ParseModeError;
+
+    fn from_str(s: &str) -> Result<CompressMode, ParseModeError> {
+        [
+            CompressMode::Generic,
+            CompressMode::Text,
+            CompressMode::Font,
+        ]
+        .iter()
+        .find(|mode| mode.name().eq_ignore_ascii_case(s))
+        .cloned()
+        .ok_or(ParseModeError(()))
+    }
+}
+
+/// The error returned when parsing a `CompressMode` from an unknown name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseModeError(());
+
+impl fmt::Display for ParseModeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("unknown brotli compression mode, expected `generic`, `text` or `font`")
+    }
+}
+
+impl error::Error for ParseModeError {}
+
+/// The fastest compression quality.
+pub const MIN_QUALITY: u32 = brotli_sys::BROTLI_MIN_QUALITY;
+/// The densest compression quality.
+pub const MAX_QUALITY: u32 = brotli_sys::BROTLI_MAX_QUALITY;
+/// The smallest `lgwin`.
+pub const MIN_LGWIN: u32 = brotli_sys::BROTLI_MIN_WINDOW_BITS;
+/// The largest `lgwin` of standard brotli streams.
+pub const MAX_LGWIN: u32 = brotli_sys::BROTLI_MAX_WINDOW_BITS;
+/// The largest `lgwin` of large-window brotli streams.
+pub const LARGE_MAX_LGWIN: u32 = brotli_sys::BROTLI_LARGE_MAX_WINDOW_BITS;
+/// The smallest `lgblock` other than 0.
+pub const MIN_LGBLOCK: u32 = brotli_sys::BROTLI_MIN_INPUT_BLOCK_BITS;
+/// The largest `lgblock`.
+pub const MAX_LGBLOCK: u32 = brotli_sys::BROTLI_MAX_INPUT_BLOCK_BITS;
+/// The largest stream offset the encoder accepts.
+pub const MAX_STREAM_OFFSET: u64 = 1 << 30;
+
+/// A compression quality, from 0 to 11.
+///
+/// Higher qualities compress more densely, but more slowly.
+///
+/// # Examples
+///
+/// ```
+/// use brotli2::{CompressParams, Quality};
+///
+/// assert!(Quality::new(12).is_none());
+/// let mut params = CompressParams::new();
+/// params.set_quality(Quality::new(5).unwrap());
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Quality(u32);
+
+/// The base 2 logarithm of the sliding window size, from 10 to 24, or up to 30
+/// for large-window brotli.
+///
+/// Large-window streams are not standard brotli, see `CompressParams::lgwin`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WindowSize(u32);
+
+/// The base 2 logarithm of the maximum input block size, from 16 to 24, or 0
+/// to have it picked based on the quality.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BlockSize(u32);
+
+impl Quality {
+    /// The fastest quality.
+    pub const MIN: Quality = Quality(brotli_sys::BROTLI_MIN_QUALITY);
+    /// The densest quality.
+    pub const MAX: Quality = Quality(brotli_sys::BROTLI_MAX_QUALITY);
+
+    /// Returns `quality` as a `Quality`, or `None` if it is out of range.
+    pub fn new(quality: u32) -> Option<Quality> {
+        if Quality::range().contains(&quality) {
+            Some(Quality(quality))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the quality as a number.
+    pub fn get(self) -> u32 {
+        self.0
+    }
+
+    /// Returns the range of valid qualities.
+    pub fn range() -> RangeInclusive<u32> {
+        MIN_QUALITY..=MAX_QUALITY
+    }
+}
+
+impl WindowSize {
+    /// The smallest window size.
+    pub const MIN: WindowSize = WindowSize(brotli_sys::BROTLI_MIN_WINDOW_BITS);
+    /// The largest window size of standard brotli streams.
+    pub const MAX: WindowSize = WindowSize(brotli_sys::BROTLI_MAX_WINDOW_BITS);
+    /// The largest window size of large-window brotli streams.
+    pub const LARGE_MAX: WindowSize = WindowSize(brotli_sys::BROTLI_LARGE_MAX_WINDOW_BITS);
+
+    /// Returns `lgwin` as a `WindowSize`, or `None` if it is out of range.
+    pub fn new(lgwin: u32) -> Option<WindowSize> {
+        if WindowSize::large_range().contains(&lgwin) {
+            Some(WindowSize(lgwin))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the base 2 logarithm of the window size.
+    pub fn get(self) -> u32 {
+        self.0
+    }
+
+    /// Returns whether this window size requires large-window brotli.
+    pub fn is_large(self) -> bool {
+        self.0 > brotli_sys::BROTLI_MAX_WINDOW_BITS
+    }
+
+    /// Returns the range of window sizes of standard brotli streams.
+    pub fn range() -> RangeInclusive<u32> {
+        MIN_LGWIN..=MAX_LGWIN
+    }
+
+    /// Returns the range of window sizes of large-window brotli streams.
+    pub fn large_range() -> RangeInclusive<u32> {
+        MIN_LGWIN..=LARGE_MAX_LGWIN
+    }
+}
+
+impl BlockSize {
+    /// Picks the block size based on the quality.
+    pub const AUTO: BlockSize = BlockSize(0);
+
+    /// Returns `lgblock` as a `BlockSize`, or `None` if it is out of range.
+    pub fn new(lgblock: u32) -> Option<BlockSize> {
+        if lgblock == 0 || BlockSize::range().contains(&lgblock) {
+            Some(BlockSize(lgblock))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the base 2 logarithm of the block size, or 0 for `AUTO`.
+    pub fn get(self) -> u32 {
+        self.0
+    }
+
+    /// Returns the range of block sizes other than `AUTO`.
+    pub fn range() -> RangeInclusive<u32> {
+        MIN_LGBLOCK..=MAX_LGBLOCK
+    }
+}
+
 /// Parameters passed to various compression routines.
-#[derive(Clone, Debug)]
+///
+/// With the `serde` feature these can be serialized and deserialized, with
+/// the mode written as its name and missing fields taking their defaults.
+/// Deserialized parameters are not checked, so call `validate` on them.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default = "CompressParams::new"))]
 pub struct CompressParams {
     /// Compression mode.
+    #[cfg_attr(feature = "serde", serde(with = "mode_serde"))]
     mode: u32,
     /// Controls the compression-speed vs compression-density tradeoffs. The higher the `quality`,
     /// the slower the compression. Range is 0 to 11.
     quality: u32,
-    /// Base 2 logarithm of the sliding window size. Range is 10 to 24.
+    /// Base 2 logarithm of the sliding window size. Range is 10 to 24, or up to 30 for large-window
+    /// brotli.
     lgwin: u32,
     /// Base 2 logarithm of the maximum input block size. Range is 16 to 24. If set to 0, the value
     /// will be set based on the quality.
     lgblock: u32,
+    /// Estimated total size of the input, or 0 if unknown.
+    size_hint: u32,
+    /// Whether the "literal context modeling" format feature is disabled.
+    disable_literal_context_modeling: bool,
+    /// Number of postfix bits used in distance codes. Range is 0 to 3.
+    npostfix: u32,
+    /// Number of direct distance codes. Must be a multiple of
+    /// `1 << npostfix`, and at most `15 << npostfix`.
+    ndirect: u32,
+    /// Number of bytes of the logical stream already compressed by other
+    /// encoders. At most `1 << 30`.
+    stream_offset: u32,
 }
 
 impl CompressParams {
@@ -74,9 +425,150 @@ impl CompressParams {
             quality: brotli_sys::BROTLI_DEFAULT_QUALITY,
             lgwin: brotli_sys::BROTLI_DEFAULT_WINDOW,
             lgblock: 0,
+            size_hint: 0,
+            disable_literal_context_modeling: false,
+            npostfix: 0,
+            ndirect: 0,
+            stream_offset: 0,
         }
     }
 
+    /// Parameters for the fastest compression, at quality 0.
+    ///
+    /// Suitable when throughput matters more than the compressed size.
+    pub fn fastest() -> CompressParams {
+        let mut params = CompressParams::new();
+        params.quality(0);
+        params
+    }
+
+    /// Parameters trading speed and density evenly, at quality 5.
+    ///
+    /// Suitable for compressing dynamic content on the fly.
+    pub fn balanced() -> CompressParams {
+        let mut params = CompressParams::new();
+        params.quality(5);
+        params
+    }
+
+    /// Parameters for the densest standard compression, at quality 11 with
+    /// the largest standard window.
+    ///
+    /// Suitable for content that is compressed once and served many times.
+    pub fn best() -> CompressParams {
+        let mut params = CompressParams::new();
+        params
+            .quality(brotli_sys::BROTLI_MAX_QUALITY)
+            .lgwin(brotli_sys::BROTLI_MAX_WINDOW_BITS);
+        params
+    }
+
+    /// Parameters for the densest compression of UTF-8 text, like `best` but
+    /// in `CompressMode::Text`.
+    pub fn text() -> CompressParams {
+        let mut params = CompressParams::best();
+        params.mode(CompressMode::Text);
+        params
+    }
+
+    /// Picks parameters suited to content of a MIME type, such as `text/html`
+    /// or `application/json; charset=utf-8`.
+    ///
+    /// Text formats, including JSON, XML, JavaScript and SVG, get `text`.
+    /// Uncompressed fonts get `best` in `CompressMode::Font`. Formats that
+    /// are already compressed, such as most images, audio, video, archives
+    /// and WOFF fonts, get `fastest`, and anything else gets `balanced`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use brotli2::{CompressMode, CompressParams};
+    ///
+    /// let params = CompressParams::for_mime("text/html; charset=utf-8");
+    /// assert_eq!(params.get_mode(), CompressMode::Text);
+    /// assert_eq!(CompressParams::for_mime("image/png").get_quality(), 0);
+    /// ```
+    pub fn for_mime(mime: &str) -> CompressParams {
+        let essence = mime
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+        let (kind, subtype) = match essence.find('/') {
+            Some(i) => (&essence[..i], &essence[i + 1..]),
+            None => (&essence[..], ""),
+        };
+        let text = kind == "text"
+            || subtype.ends_with("+json")
+            || subtype.ends_with("+xml")
+            || (kind == "application"
+                && matches!(
+                    subtype,
+                    "javascript" | "ecmascript" | "json" | "xml" | "x-sh"
+                ));
+        let font = matches!(
+            (kind, subtype),
+            ("font", "ttf")
+                | ("font", "otf")
+                | ("font", "sfnt")
+                | ("font", "collection")
+                | ("application", "font-sfnt")
+                | ("application", "x-font-ttf")
+                | ("application", "x-font-otf")
+                | ("application", "vnd.ms-fontobject")
+        );
+        let compressed = match kind {
+            "image" | "audio" | "video" => true,
+            "font" => !font,
+            "application" => matches!(
+                subtype,
+                "zip"
+                    | "gzip"
+                    | "x-gzip"
+                    | "zstd"
+                    | "x-7z-compressed"
+                    | "x-bzip2"
+                    | "x-xz"
+                    | "x-rar-compressed"
+                    | "font-woff"
+                    | "font-woff2"
+            ),
+            _ => false,
+        };
+        if text {
+            CompressParams::text()
+        } else if font {
+            let mut params = CompressParams::best();
+            params.mode(CompressMode::Font);
+            params
+        } else if compressed {
+            CompressParams::fastest()
+        } else {
+            CompressParams::balanced()
+        }
+    }
+
+    /// Creates parameters for a numeric compression level, as used by other
+    /// compression backends.
+    ///
+    /// The level is used as the quality, and levels above 11 are clamped to
+    /// 11, so the usual gzip levels 0 to 9 map onto brotli's faster settings.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use brotli2::CompressParams;
+    ///
+    /// assert!(CompressParams::from_level(6).validate().is_ok());
+    /// assert!(CompressParams::from_level(42).validate().is_ok());
+    /// ```
+    pub fn from_level(level: u32) -> CompressParams {
+        let mut params = CompressParams::new();
+        params.quality(cmp::min(level, brotli_sys::BROTLI_MAX_QUALITY));
+        params
+    }
+
     /// Set the mode of this compression.
     pub fn mode(&mut self, mode: CompressMode) -> &mut CompressParams {
         self.mode = mode as u32;
@@ -86,20 +578,63 @@ impl CompressParams {
     /// Controls the compression-speed vs compression-density tradeoffs.
     ///
     /// The higher the quality, the slower the compression. Currently the range
-    /// for the quality is 0 to 11.
+    /// for the quality is 0 to 11, and other values are rejected when the
+    /// parameters are applied with `Compress::set_params`.
     pub fn quality(&mut self, quality: u32) -> &mut CompressParams {
         self.quality = quality;
         self
     }
 
+    /// Sets the quality, like `quality` but with a value that is known to be
+    /// in range.
+    pub fn set_quality(&mut self, quality: Quality) -> &mut CompressParams {
+        self.quality(quality.get())
+    }
+
     /// Sets the base 2 logarithm of the sliding window size.
     ///
-    /// Currently the range is 10 to 24.
+    /// Currently the range is 10 to 24 for standard brotli streams. Values of
+    /// 25 to 30 enable the "large window" extension, which can improve the
+    /// compression of very large inputs. Note that large-window streams are
+    /// not standard brotli and can only be decompressed by decoders that
+    /// enable large-window support with `DecompressParams::large_window`.
     pub fn lgwin(&mut self, lgwin: u32) -> &mut CompressParams {
         self.lgwin = lgwin;
         self
     }
 
+    /// Sets the window size, like `lgwin` but with a value that is known to
+    /// be in range.
+    pub fn set_window_size(&mut self, size: WindowSize) -> &mut CompressParams {
+        self.lgwin(size.get())
+    }
+
+    /// Sets the window size to the smallest standard one that covers an input
+    /// of `len` bytes.
+    ///
+    /// Larger windows cost the encoder and the decoder memory without
+    /// improving the compression of inputs that fit in a smaller one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use brotli2::CompressParams;
+    ///
+    /// let mut params = CompressParams::new();
+    /// params.lgwin_for_input(1000);
+    /// assert_eq!(params.get_lgwin(), 10);
+    /// params.lgwin_for_input(100_000);
+    /// assert_eq!(params.get_lgwin(), 17);
+    /// ```
+    pub fn lgwin_for_input(&mut self, len: usize) -> &mut CompressParams {
+        // The usable window is 16 bytes smaller than `1 << lgwin`.
+        let mut lgwin = MIN_LGWIN;
+        while lgwin < MAX_LGWIN && (1usize << lgwin) - 16 < len {
+            lgwin += 1;
+        }
+        self.lgwin(lgwin)
+    }
+
     /// Sets the base 2 logarithm of the maximum input block size.
     ///
     /// Currently the range is 16 to 24, and if set to 0 the value will be set
@@ -109,6 +644,173 @@ impl CompressParams {
         self
     }
 
+    /// Sets the block size, like `lgblock` but with a value that is known to
+    /// be in range.
+    pub fn set_block_size(&mut self, size: BlockSize) -> &mut CompressParams {
+        self.lgblock(size.get())
+    }
+
+    /// Sets the estimated total size of the input, in bytes.
+    ///
+    /// When the size of the input is known up front, the encoder can use it
+    /// to pick better block splitting and window sizes. The default of 0
+    /// means the size is unknown.
+    pub fn size_hint(&mut self, size_hint: usize) -> &mut CompressParams {
+        self.size_hint = cmp::min(size_hint, u32::MAX as usize) as u32;
+        self
+    }
+
+    /// Disables the "literal context modeling" format feature.
+    ///
+    /// Context modeling improves the compression density of text-like input.
+    /// Disabling it trades some density for faster compression and
+    /// decompression, which is typically worthwhile for binary data.
+    pub fn disable_literal_context_modeling(&mut self, disable: bool) -> &mut CompressParams {
+        self.disable_literal_context_modeling = disable;
+        self
+    }
+
+    /// Sets the number of postfix bits (NPOSTFIX) used in distance codes.
+    ///
+    /// Together with `ndirect` this tunes how backward distances are coded,
+    /// which can pay off for input made of fixed-size records. Currently the
+    /// range is 0 to 3. These parameters only take effect at quality 4 and
+    /// above.
+    ///
+    /// The combination with `ndirect` is checked when the parameters are
+    /// applied with `Compress::set_params`, which fails if it is invalid.
+    pub fn npostfix(&mut self, npostfix: u32) -> &mut CompressParams {
+        self.npostfix = npostfix;
+        self
+    }
+
+    /// Sets the number of direct distance codes (NDIRECT).
+    ///
+    /// This must be a multiple of `1 << npostfix`, and at most
+    /// `15 << npostfix`. See `npostfix` for details.
+    pub fn ndirect(&mut self, ndirect: u32) -> &mut CompressParams {
+        self.ndirect = ndirect;
+        self
+    }
+
+    /// Sets the number of bytes of the logical stream that were already
+    /// compressed by other encoders.
+    ///
+    /// This allows a large input to be split into chunks compressed
+    /// independently, and the results concatenated into a single stream. All
+    /// chunks must be compressed with the same parameters apart from this
+    /// one, every chunk except the last must end with a `Flush` rather than a
+    /// `Finish`, and each chunk after the first sets this to the total size
+    /// of the input preceding it. A non-zero offset also omits the stream
+    /// header from the output.
+    ///
+    /// Any value at least as large as the window size behaves the same, so
+    /// values above `MAX_STREAM_OFFSET`, which the encoder would reject, are
+    /// reduced to it.
+    pub fn stream_offset(&mut self, stream_offset: u64) -> &mut CompressParams {
+        self.stream_offset = cmp::min(stream_offset, MAX_STREAM_OFFSET) as u32;
+        self
+    }
+
+    /// Checks that these parameters are in range and consistent with each
+    /// other, without creating an encoder.
+    ///
+    /// Besides the range of each parameter, this rejects combinations that
+    /// libbrotli would silently adjust: an `lgblock` other than 0 below
+    /// quality 4, where it is ignored, and a large window below quality 3,
+    /// where the window is reduced to 24 bits.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first problem found.
+    pub fn validate(&self) -> Result<ValidatedParams, ParamError> {
+        self.check_ranges()?;
+        if self.lgblock != 0 && self.quality < 4 {
+            return Err(ParamError::Conflict("lgblock", "quality"));
+        }
+        if self.lgwin > brotli_sys::BROTLI_MAX_WINDOW_BITS && self.quality < 3 {
+            return Err(ParamError::Conflict("lgwin", "quality"));
+        }
+        Ok(ValidatedParams {
+            params: self.clone(),
+        })
+    }
+
+    /// Checks the parameters libbrotli itself would reject: each parameter's
+    /// range, and the distance parameters against each other.
+    pub(crate) fn check_ranges(&self) -> Result<(), ParamError> {
+        if Quality::new(self.quality).is_none() {
+            return Err(ParamError::OutOfRange("quality"));
+        }
+        if WindowSize::new(self.lgwin).is_none() {
+            return Err(ParamError::OutOfRange("lgwin"));
+        }
+        if BlockSize::new(self.lgblock).is_none() {
+            return Err(ParamError::OutOfRange("lgblock"));
+        }
+        if self.npostfix > 3 {
+            return Err(ParamError::OutOfRange("npostfix"));
+        }
+        if self.ndirect > 15 << self.npostfix {
+            return Err(ParamError::OutOfRange("ndirect"));
+        }
+        if (self.ndirect >> self.npostfix) << self.npostfix != self.ndirect {
+            return Err(ParamError::Conflict("ndirect", "npostfix"));
+        }
+        Ok(())
+    }
+
+    /// Returns these parameters as the encoder ends up using them, mirroring
+    /// how libbrotli clamps them and derives the block size and distance
+    /// parameters from the quality.
+    ///
+    /// libbrotli has no way to query these, so this follows `SanitizeParams`,
+    /// `ComputeLgBlock` and `ChooseDistanceParams` in `c/enc/encode.c` of
+    /// libbrotli 1.1, the version `brotli-sys` binds, along with the 18-bit
+    /// minimum window that the fast qualities write in `EnsureInitialized`. The window size is checked
+    /// against the linked libbrotli by the `effective_lgwin_matches_library`
+    /// test in `raw`.
+    fn effective(&self) -> CompressParams {
+        let mut params = self.clone();
+        params.quality = cmp::min(params.quality, MAX_QUALITY);
+        let max_lgwin = if params.quality <= 2 {
+            MAX_LGWIN
+        } else {
+            LARGE_MAX_LGWIN
+        };
+        params.lgwin = params.lgwin.clamp(MIN_LGWIN, max_lgwin);
+        params.lgblock = match params.quality {
+            0 | 1 => params.lgwin,
+            2 | 3 => 14,
+            _ if params.lgblock == 0 => {
+                if params.quality >= 9 && params.lgwin > 16 {
+                    cmp::min(18, params.lgwin)
+                } else {
+                    16
+                }
+            }
+            _ => params.lgblock.clamp(MIN_LGBLOCK, MAX_LGBLOCK),
+        };
+        if params.quality < 4 {
+            params.npostfix = 0;
+            params.ndirect = 0;
+        } else if params.mode == brotli_sys::BROTLI_MODE_FONT {
+            params.npostfix = 1;
+            params.ndirect = 12;
+        } else if params.npostfix > 3
+            || params.ndirect > 15 << params.npostfix
+            || (params.ndirect >> params.npostfix) << params.npostfix != params.ndirect
+        {
+            params.npostfix = 0;
+            params.ndirect = 0;
+        }
+        if params.quality <= 1 {
+            // The fast qualities always write a window of at least 18 bits.
+            params.lgwin = cmp::max(params.lgwin, 18);
+        }
+        params
+    }
+
     /// Get the current block size
     #[inline]
     pub fn get_lgblock_readable(&self) -> usize {
@@ -118,7 +820,7 @@ impl CompressParams {
     /// Get the native lgblock size
     #[inline]
     pub fn get_lgblock(&self) -> u32 {
-        self.lgblock.clone()
+        self.lgblock
     }
     /// Get the current window size
     #[inline]
@@ -128,6 +830,924 @@ impl CompressParams {
     /// Get the native lgwin value
     #[inline]
     pub fn get_lgwin(&self) -> u32 {
-        self.lgwin.clone()
+        self.lgwin
+    }
+    /// Get the current quality
+    #[inline]
+    pub fn get_quality(&self) -> u32 {
+        self.quality
+    }
+    /// Get the current compression mode
+    #[inline]
+    pub fn get_mode(&self) -> CompressMode {
+        CompressMode::from_raw(self.mode)
+    }
+}
+
+impl Default for CompressParams {
+    fn default() -> CompressParams {
+        CompressParams::new()
+    }
+}
+
+/// Generates parameters that pass `CompressParams::validate`, for use in fuzz
+/// targets.
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for CompressParams {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<CompressParams> {
+        let quality =
+            u.int_in_range(brotli_sys::BROTLI_MIN_QUALITY..=brotli_sys::BROTLI_MAX_QUALITY)?;
+        let max_lgwin = if quality < 3 {
+            brotli_sys::BROTLI_MAX_WINDOW_BITS
+        } else {
+            brotli_sys::BROTLI_LARGE_MAX_WINDOW_BITS
+        };
+        let lgblock = if quality < 4 || bool::arbitrary(u)? {
+            0
+        } else {
+            u.int_in_range(
+                brotli_sys::BROTLI_MIN_INPUT_BLOCK_BITS..=brotli_sys::BROTLI_MAX_INPUT_BLOCK_BITS,
+            )?
+        };
+        let npostfix = u.int_in_range(0..=3)?;
+        // With a stream offset the encoder omits the stream header, producing
+        // output that only decodes appended to another stream, so fuzz
+        // targets that round-trip their input would mostly fail on it.
+        let stream_offset = if u.ratio(1, 16)? {
+            u.int_in_range(1..=1 << 30)?
+        } else {
+            0
+        };
+        let mut params = CompressParams::new();
+        params
+            .mode(*u.choose(&[
+                CompressMode::Generic,
+                CompressMode::Text,
+                CompressMode::Font,
+            ])?)
+            .quality(quality)
+            .lgwin(u.int_in_range(brotli_sys::BROTLI_MIN_WINDOW_BITS..=max_lgwin)?)
+            .lgblock(lgblock)
+            .size_hint(u32::arbitrary(u)? as usize)
+            .disable_literal_context_modeling(bool::arbitrary(u)?)
+            .npostfix(npostfix)
+            .ndirect(u.int_in_range(0..=15)? << npostfix)
+            .stream_offset(stream_offset);
+        Ok(params)
+    }
+}
+
+/// A set of `CompressParams` that passed `CompressParams::validate`.
+///
+/// This dereferences to the parameters, so it can be passed to
+/// `Compress::set_params` and anything else taking `CompressParams`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ValidatedParams {
+    params: CompressParams,
+}
+
+impl ValidatedParams {
+    /// Returns the validated parameters.
+    pub fn into_inner(self) -> CompressParams {
+        self.params
+    }
+}
+
+impl Deref for ValidatedParams {
+    type Target = CompressParams;
+
+    fn deref(&self) -> &CompressParams {
+        &self.params
+    }
+}
+
+/// A problem with a set of `CompressParams`, found by
+/// `CompressParams::validate`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ParamError {
+    /// The named parameter is out of range.
+    OutOfRange(&'static str),
+    /// The two named parameters have values that cannot be used together.
+    Conflict(&'static str, &'static str),
+}
+
+impl ParamError {
+    /// Returns the name of the parameter at fault, or the first of them.
+    pub fn param(&self) -> &'static str {
+        match *self {
+            ParamError::OutOfRange(name) | ParamError::Conflict(name, _) => name,
+        }
+    }
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParamError::OutOfRange(name) => {
+                write!(f, "brotli parameter `{}` is out of range", name)
+            }
+            ParamError::Conflict(a, b) => {
+                write!(f, "brotli parameters `{}` and `{}` conflict", a, b)
+            }
+        }
+    }
+}
+
+impl error::Error for ParamError {}
+
+/// Parameters passed to various decompression routines.
+#[derive(Clone, Debug)]
+pub struct DecompressParams {
+    /// Whether streams using the large-window extension are accepted.
+    large_window: bool,
+    /// Whether the ring buffer is allocated for the full window size up
+    /// front, rather than grown as needed.
+    disable_ring_buffer_reallocation: bool,
+    /// The most memory a decoder created with these parameters may allocate.
+    max_memory: Option<usize>,
+}
+
+impl DecompressParams {
+    /// Creates a new default set of decompression parameters.
+    pub fn new() -> DecompressParams {
+        DecompressParams {
+            large_window: false,
+            disable_ring_buffer_reallocation: false,
+            max_memory: None,
+        }
+    }
+
+    /// Accept streams using the "large window" extension, which are produced
+    /// by encoders configured with an `lgwin` above 24.
+    ///
+    /// Such streams are not standard brotli, so this is disabled by default.
+    pub fn large_window(&mut self, large_window: bool) -> &mut DecompressParams {
+        self.large_window = large_window;
+        self
+    }
+
+    /// Disables the "canny" ring buffer allocation strategy.
+    ///
+    /// By default the decoder starts with a ring buffer sized for the content
+    /// seen so far and reallocates it as the stream grows, up to the window
+    /// size declared by the stream. Disabling this allocates a ring buffer of
+    /// the full window size up front, giving deterministic memory behavior
+    /// with no reallocations at the cost of more memory for small streams.
+    pub fn disable_ring_buffer_reallocation(&mut self, disable: bool) -> &mut DecompressParams {
+        self.disable_ring_buffer_reallocation = disable;
+        self
+    }
+
+    /// Bounds the memory the decoder may allocate for its state and ring
+    /// buffer to `limit` bytes.
+    ///
+    /// A stream that needs more memory than this, for example because it
+    /// declares a large window, fails to decode with an error of kind
+    /// `ErrorKind::Decode(DecodeError::Alloc)` instead of allocating it.
+    /// The memory the decoder already holds when the limit is applied, such
+    /// as its state, counts towards it, and configuring a decoder that holds
+    /// more than `limit` fails with an error of kind `ErrorKind::Alloc`.
+    pub fn max_memory(&mut self, limit: Option<usize>) -> &mut DecompressParams {
+        self.max_memory = limit;
+        self
+    }
+}
+
+impl Default for DecompressParams {
+    fn default() -> DecompressParams {
+        DecompressParams::new()
+    }
+}
+
+/// How much data an encoder processed, returned along with the inner stream
+/// when it is finished.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Summary {
+    total_in: u64,
+    total_out: u64,
+}
+
+impl Summary {
+    fn new(total_in: u64, total_out: u64) -> Summary {
+        Summary {
+            total_in,
+            total_out,
+        }
+    }
+
+    /// Returns the number of uncompressed bytes that were compressed.
+    pub fn total_in(&self) -> u64 {
+        self.total_in
+    }
+
+    /// Returns the number of compressed bytes produced.
+    pub fn total_out(&self) -> u64 {
+        self.total_out
+    }
+
+    /// Returns the compression ratio, the number of uncompressed bytes per
+    /// compressed byte, or zero if no output was produced.
+    pub fn ratio(&self) -> f64 {
+        if self.total_out == 0 {
+            0.0
+        } else {
+            self.total_in as f64 / self.total_out as f64
+        }
+    }
+}
+
+/// Statistics about a stream, collected by an I/O wrapper once its
+/// `enable_stats` method has been called.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Stats {
+    total_in: u64,
+    total_out: u64,
+    elapsed: Duration,
+    flushes: u64,
+}
+
+impl Stats {
+    // Returns the time an operation starts at, if stats are being collected.
+    fn start(stats: &Option<Stats>) -> Option<Instant> {
+        stats.map(|_| Instant::now())
+    }
+
+    // Adds the time since `start` to the elapsed time.
+    fn record(stats: &mut Option<Stats>, start: Option<Instant>) {
+        if let (Some(stats), Some(start)) = (stats.as_mut(), start) {
+            stats.elapsed += start.elapsed();
+        }
+    }
+
+    fn count_flush(stats: &mut Option<Stats>) {
+        if let Some(stats) = stats.as_mut() {
+            stats.flushes += 1;
+        }
+    }
+
+    fn with_totals(stats: Option<Stats>, total_in: u64, total_out: u64) -> Option<Stats> {
+        stats.map(|stats| Stats {
+            total_in,
+            total_out,
+            ..stats
+        })
+    }
+
+    /// Returns the number of bytes that went into the encoder or decoder.
+    pub fn total_in(&self) -> u64 {
+        self.total_in
+    }
+
+    /// Returns the number of bytes that the encoder or decoder produced.
+    pub fn total_out(&self) -> u64 {
+        self.total_out
+    }
+
+    /// Returns the wall time spent in calls on the wrapper since stats were
+    /// enabled, including time spent waiting on the underlying stream.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Returns the number of times the compressed stream was flushed.
+    pub fn flushes(&self) -> u64 {
+        self.flushes
+    }
+}
+
+/// Configures and creates the encoders of the `read`, `write` and `bufread`
+/// modules, as an alternative to picking between their constructors.
+///
+/// # Examples
+///
+/// ```
+/// use std::io::prelude::*;
+/// use brotli2::{BrotliDecoderBuilder, BrotliEncoderBuilder, CompressParams};
+///
+/// let mut builder = BrotliEncoderBuilder::new();
+/// builder.params(CompressParams::new().quality(9)).capacity(4096);
+/// let mut encoder = builder.build_write(Vec::new());
+/// encoder.write_all(b"Hello, World!").unwrap();
+/// let compressed = encoder.finish().unwrap();
+///
+/// let mut decoder = BrotliDecoderBuilder::new().strict(true).build_read(&compressed[..]);
+/// let mut contents = String::new();
+/// decoder.read_to_string(&mut contents).unwrap();
+/// assert_eq!(contents, "Hello, World!");
+/// ```
+#[derive(Clone, Default)]
+pub struct BrotliEncoderBuilder {
+    params: CompressParams,
+    capacity: Option<usize>,
+    dictionary: Option<Dictionary>,
+    flush_on_would_block: bool,
+}
+
+impl BrotliEncoderBuilder {
+    /// Creates a builder for encoders with the default parameters.
+    pub fn new() -> BrotliEncoderBuilder {
+        BrotliEncoderBuilder::default()
+    }
+
+    /// Sets the compression parameters of the encoders.
+    pub fn params(&mut self, params: &CompressParams) -> &mut BrotliEncoderBuilder {
+        self.params = params.clone();
+        self
+    }
+
+    /// Sets the capacity of the encoders' internal buffer, which holds
+    /// uncompressed data read for `read` encoders and compressed data not yet
+    /// written for `write` encoders.
+    ///
+    /// By default each encoder picks its own. `bufread` encoders have no
+    /// buffer of their own and ignore this.
+    pub fn capacity(&mut self, capacity: usize) -> &mut BrotliEncoderBuilder {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// Compresses against the prefix dictionary `dict`.
+    ///
+    /// The resulting streams can only be decompressed with the same
+    /// dictionary.
+    pub fn dictionary(&mut self, dict: &Dictionary) -> &mut BrotliEncoderBuilder {
+        self.dictionary = Some(dict.clone());
+        self
+    }
+
+    /// Sets whether `read` and `bufread` encoders flush the compressed stream
+    /// when the underlying stream has no data available, as by their
+    /// `set_flush_on_would_block` methods.
+    pub fn flush_on_would_block(&mut self, flush: bool) -> &mut BrotliEncoderBuilder {
+        self.flush_on_would_block = flush;
+        self
+    }
+
+    /// Creates an encoder which reads uncompressed data from `r`.
+    pub fn build_read<R: Read>(&self, r: R) -> read::BrotliEncoder<R> {
+        let dict = self.dictionary.as_ref();
+        let mut encoder = read::BrotliEncoder::build(self.capacity, r, &self.params, dict);
+        encoder.set_flush_on_would_block(self.flush_on_would_block);
+        encoder
+    }
+
+    /// Creates an encoder which reads uncompressed data from the buffered
+    /// stream `r`.
+    pub fn build_bufread<R: BufRead>(&self, r: R) -> bufread::BrotliEncoder<R> {
+        let dict = self.dictionary.as_ref();
+        let mut encoder = bufread::BrotliEncoder::build(r, &self.params, dict);
+        encoder.set_flush_on_would_block(self.flush_on_would_block);
+        encoder
+    }
+
+    /// Creates an encoder which writes compressed data to `w`.
+    pub fn build_write<W: Write>(&self, w: W) -> write::BrotliEncoder<W> {
+        let buf = Vec::with_capacity(self.capacity.unwrap_or(0));
+        write::BrotliEncoder::build(buf, w, &self.params, self.dictionary.as_ref())
+    }
+}
+
+impl fmt::Debug for BrotliEncoderBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BrotliEncoderBuilder")
+            .field("params", &self.params)
+            .field("capacity", &self.capacity)
+            .field("dictionary", &self.dictionary.is_some())
+            .field("flush_on_would_block", &self.flush_on_would_block)
+            .finish()
+    }
+}
+
+/// Configures and creates the decoders of the `read`, `write` and `bufread`
+/// modules, as an alternative to picking between their constructors.
+///
+/// See `BrotliEncoderBuilder` for an example.
+#[derive(Clone, Default)]
+pub struct BrotliDecoderBuilder {
+    params: DecompressParams,
+    capacity: Option<usize>,
+    dictionary: Option<Dictionary>,
+    strict: bool,
+    tolerant: bool,
+}
+
+impl BrotliDecoderBuilder {
+    /// Creates a builder for decoders with the default parameters.
+    pub fn new() -> BrotliDecoderBuilder {
+        BrotliDecoderBuilder::default()
+    }
+
+    /// Sets the decompression parameters of the decoders.
+    pub fn params(&mut self, params: &DecompressParams) -> &mut BrotliDecoderBuilder {
+        self.params = params.clone();
+        self
+    }
+
+    /// Sets the capacity of the decoders' internal buffer, which holds
+    /// compressed data read for `read` decoders and decompressed data not yet
+    /// written for `write` decoders.
+    ///
+    /// By default each decoder picks its own. `bufread` decoders have no
+    /// buffer of their own and ignore this.
+    pub fn capacity(&mut self, capacity: usize) -> &mut BrotliDecoderBuilder {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// Decompresses streams that were compressed against the prefix
+    /// dictionary `dict`.
+    pub fn dictionary(&mut self, dict: &Dictionary) -> &mut BrotliDecoderBuilder {
+        self.dictionary = Some(dict.clone());
+        self
+    }
+
+    /// Sets whether data following the end of a stream is an error, as by the
+    /// decoders' `set_strict` methods.
+    pub fn strict(&mut self, strict: bool) -> &mut BrotliDecoderBuilder {
+        self.strict = strict;
+        self
+    }
+
+    /// Sets whether a stream cut short is accepted, as by the decoders'
+    /// `set_tolerant` methods.
+    pub fn tolerant(&mut self, tolerant: bool) -> &mut BrotliDecoderBuilder {
+        self.tolerant = tolerant;
+        self
+    }
+
+    /// Creates a decoder which reads compressed data from `r`.
+    pub fn build_read<R: Read>(&self, r: R) -> read::BrotliDecoder<R> {
+        let dict = self.dictionary.as_ref();
+        let mut decoder = read::BrotliDecoder::build(self.capacity, r, &self.params, dict);
+        decoder.set_strict(self.strict);
+        decoder.set_tolerant(self.tolerant);
+        decoder
+    }
+
+    /// Creates a decoder which reads compressed data from the buffered stream
+    /// `r`.
+    pub fn build_bufread<R: BufRead>(&self, r: R) -> bufread::BrotliDecoder<R> {
+        let dict = self.dictionary.as_ref();
+        let mut decoder = bufread::BrotliDecoder::build(r, &self.params, dict);
+        decoder.set_strict(self.strict);
+        decoder.set_tolerant(self.tolerant);
+        decoder
+    }
+
+    /// Creates a decoder which writes decompressed data to `w`.
+    pub fn build_write<W: Write>(&self, w: W) -> write::BrotliDecoder<W> {
+        let buf = Vec::with_capacity(self.capacity.unwrap_or(0));
+        let dict = self.dictionary.as_ref();
+        let mut decoder = write::BrotliDecoder::build(buf, w, &self.params, dict);
+        decoder.set_strict(self.strict);
+        decoder.set_tolerant(self.tolerant);
+        decoder
+    }
+}
+
+impl fmt::Debug for BrotliDecoderBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BrotliDecoderBuilder")
+            .field("params", &self.params)
+            .field("capacity", &self.capacity)
+            .field("dictionary", &self.dictionary.is_some())
+            .field("strict", &self.strict)
+            .field("tolerant", &self.tolerant)
+            .finish()
+    }
+}
+
+/// A hash or checksum computed over a stream of bytes, such as the CRC-32 or
+/// SHA-256 state of another crate.
+///
+/// `write::DigestWriter` and `read::DigestReader` feed it the data that flows
+/// through them.
+pub trait Digest {
+    /// The final value of the digest.
+    type Output;
+
+    /// Adds `data` to the digest.
+    fn update(&mut self, data: &[u8]);
+
+    /// Returns the digest of all data added.
+    fn finalize(self) -> Self::Output;
+}
+
+/// Options for `copy_encode_with` and `copy_decode_with`.
+///
+/// # Examples
+///
+/// ```
+/// use brotli2::{CompressParams, CopyOptions};
+///
+/// let params = CompressParams::new();
+/// let mut reported = Vec::new();
+/// {
+///     let mut options = CopyOptions::new();
+///     options.progress(1000, |total_in, _total_out| reported.push(total_in));
+///     let input = vec![0; 2500];
+///     brotli2::copy_encode_with(&input[..], Vec::new(), &params, &mut options).unwrap();
+/// }
+/// assert_eq!(reported.last(), Some(&2500));
+/// ```
+#[derive(Default)]
+pub struct CopyOptions<'a> {
+    interval: u64,
+    next: u64,
+    last: Option<(u64, u64)>,
+    progress: Option<Box<dyn FnMut(u64, u64) + 'a>>,
+    cancel: Option<Rc<dyn Fn() -> bool + 'a>>,
+}
+
+impl<'a> CopyOptions<'a> {
+    /// Creates options that copy without reporting progress, and cannot be
+    /// cancelled.
+    pub fn new() -> CopyOptions<'a> {
+        CopyOptions::default()
+    }
+
+    /// Calls `progress` with the cumulative number of bytes read and written
+    /// so far each time at least another `interval` bytes have been read,
+    /// and with the final counts when the copy is complete.
+    pub fn progress<F>(&mut self, interval: u64, progress: F) -> &mut CopyOptions<'a>
+    where
+        F: FnMut(u64, u64) + 'a,
+    {
+        self.interval = cmp::max(interval, 1);
+        self.next = self.interval;
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Cancels the copy as soon as `cancel` returns `true`.
+    ///
+    /// This is checked before each chunk of data is read from the reader or
+    /// copied to the writer. A cancelled copy fails with a `Cancelled` error,
+    /// leaving an incomplete stream in the writer, which is not flushed.
+    pub fn cancel_when<F>(&mut self, cancel: F) -> &mut CopyOptions<'a>
+    where
+        F: Fn() -> bool + 'a,
+    {
+        self.cancel = Some(Rc::new(cancel));
+        self
+    }
+
+    /// Cancels the copy as soon as `flag` is set, like `cancel_when`.
+    ///
+    /// The flag can be set from another thread, or a signal handler.
+    pub fn cancel_on(&mut self, flag: &'a AtomicBool) -> &mut CopyOptions<'a> {
+        self.cancel_when(move || flag.load(Ordering::Relaxed))
+    }
+
+    fn check_cancelled(&self) -> io::Result<()> {
+        check_cancelled(&self.cancel)
+    }
+
+    /// Wraps `reader` so that reading from it also checks for cancellation.
+    fn cancellable<R: Read>(&self, reader: R) -> CancellableRead<'a, R> {
+        CancellableRead {
+            inner: reader,
+            cancel: self.cancel.clone(),
+        }
+    }
+
+    fn report(&mut self, total_in: u64, total_out: u64, done: bool) {
+        let counts = Some((total_in, total_out));
+        if let Some(ref mut progress) = self.progress {
+            if total_in >= self.next || (done && self.last != counts) {
+                progress(total_in, total_out);
+                self.next = (total_in / self.interval + 1) * self.interval;
+                self.last = counts;
+            }
+        }
+    }
+}
+
+fn check_cancelled(cancel: &Option<Rc<dyn Fn() -> bool + '_>>) -> io::Result<()> {
+    match *cancel {
+        Some(ref cancel) if cancel() => Err(io::Error::new(io::ErrorKind::Other, Cancelled(()))),
+        _ => Ok(()),
+    }
+}
+
+/// The reader of a copy, checked for cancellation before each read so that
+/// a copy that reads much more than it writes can still be cancelled.
+struct CancellableRead<'a, R> {
+    inner: R,
+    cancel: Option<Rc<dyn Fn() -> bool + 'a>>,
+}
+
+impl<'a, R: Read> Read for CancellableRead<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        check_cancelled(&self.cancel)?;
+        self.inner.read(buf)
+    }
+}
+
+impl<'a> fmt::Debug for CopyOptions<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CopyOptions")
+            .field("interval", &self.interval)
+            .field("progress", &self.progress.is_some())
+            .field("cancel", &self.cancel.is_some())
+            .finish()
+    }
+}
+
+/// The error returned by the copy helpers when a copy is cancelled through
+/// `CopyOptions`.
+///
+/// It is wrapped in an `io::Error` of kind `io::ErrorKind::Other`, and can be
+/// told apart from other errors with `Cancelled::is_cancelled`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cancelled(());
+
+impl Cancelled {
+    /// Returns whether `err` reports a cancelled copy.
+    pub fn is_cancelled(err: &io::Error) -> bool {
+        err.get_ref().map_or(false, |e| e.is::<Cancelled>())
+    }
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("brotli copy was cancelled")
+    }
+}
+
+impl error::Error for Cancelled {}
+
+/// Compresses all data read from `reader` with `params` and writes the
+/// compressed stream to `writer`, returning the number of uncompressed bytes
+/// read.
+///
+/// Input is read in chunks sized for the window of `params`, and `writer` is
+/// flushed once the stream is complete.
+///
+/// # Examples
+///
+/// ```
+/// use brotli2::CompressParams;
+///
+/// let params = CompressParams::new();
+/// let mut compressed = Vec::new();
+/// let n = brotli2::copy_encode(&b"Hello, World!"[..], &mut compressed, &params).unwrap();
+/// assert_eq!(n, 13);
+///
+/// let mut decompressed = Vec::new();
+/// brotli2::copy_decode(&compressed[..], &mut decompressed).unwrap();
+/// assert_eq!(decompressed, b"Hello, World!");
+/// ```
+pub fn copy_encode<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    params: &CompressParams,
+) -> io::Result<u64> {
+    copy_encode_with(reader, writer, params, &mut CopyOptions::new())
+}
+
+/// Compresses `reader` into `writer` like `copy_encode`, with the given
+/// options.
+///
+/// Fails with a `Cancelled` error if the copy is cancelled through `options`.
+pub fn copy_encode_with<R: Read, W: Write>(
+    reader: R,
+    mut writer: W,
+    params: &CompressParams,
+    options: &mut CopyOptions,
+) -> io::Result<u64> {
+    let mut encoder = read::BrotliEncoder::from_params(options.cancellable(reader), params);
+    let mut buf = vec![0; 32 * 1024];
+    loop {
+        options.check_cancelled()?;
+        let len = match encoder.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..len])?;
+        options.report(encoder.total_in(), encoder.total_out(), false);
+    }
+    writer.flush()?;
+    options.report(encoder.total_in(), encoder.total_out(), true);
+    Ok(encoder.total_in())
+}
+
+/// Decompresses the brotli stream read from `reader` and writes the
+/// decompressed data to `writer`, returning the number of bytes written.
+///
+/// Decompressed data is written straight from the decoder's buffer, and
+/// `writer` is flushed at the end of the stream.
+///
+/// # Errors
+///
+/// Returns an error if the stream is invalid or incomplete, or if reading or
+/// writing fails.
+pub fn copy_decode<R: Read, W: Write>(reader: R, writer: W) -> io::Result<u64> {
+    copy_decode_with(reader, writer, &mut CopyOptions::new())
+}
+
+/// Decompresses `reader` into `writer` like `copy_decode`, with the given
+/// options.
+///
+/// Fails with a `Cancelled` error if the copy is cancelled through `options`.
+pub fn copy_decode_with<R: Read, W: Write>(
+    reader: R,
+    mut writer: W,
+    options: &mut CopyOptions,
+) -> io::Result<u64> {
+    let mut decoder = read::BrotliDecoder::new(options.cancellable(reader));
+    options.check_cancelled()?;
+    decoder.copy_to_with(&mut writer, |total_in, total_out| {
+        options.report(total_in, total_out, false);
+        options.check_cancelled()
+    })?;
+    writer.flush()?;
+    options.report(decoder.total_in(), decoder.total_out(), true);
+    Ok(decoder.total_out())
+}
+
+/// Buffer size used for the files opened by `compress_file` and
+/// `decompress_file`.
+const FILE_BUF_SIZE: usize = 64 * 1024;
+
+/// Compresses the file at `src` into a new file at `dst`, returning the number
+/// of uncompressed bytes read.
+///
+/// When `params` is `None` the default parameters are used, with the window
+/// and size hint tuned to the length of `src`.
+///
+/// The output is written to a temporary file next to `dst` which is renamed
+/// over `dst` only once the stream is complete and synced to disk, so `dst` is
+/// never left holding a partial stream. On failure the temporary file is
+/// removed and any existing `dst` is left untouched.
+///
+/// # Examples
+///
+/// ```no_run
+/// brotli2::compress_file("data.txt", "data.txt.br", None).unwrap();
+/// brotli2::decompress_file("data.txt.br", "data.txt").unwrap();
+/// ```
+pub fn compress_file<P, Q>(src: P, dst: Q, params: Option<&CompressParams>) -> io::Result<u64>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let input = File::open(src)?;
+    let params = file_params(&input, params)?;
+    replace_file(dst.as_ref(), |out| copy_encode(input, out, &params))
+}
+
+/// Decompresses the brotli file at `src` into a new file at `dst`, returning
+/// the number of decompressed bytes written.
+///
+/// Like `compress_file`, the output only replaces `dst` once the whole stream
+/// has been decoded successfully.
+///
+/// # Errors
+///
+/// Returns an error if `src` can't be read, is not a complete brotli stream,
+/// or if writing the output fails.
+pub fn decompress_file<P, Q>(src: P, dst: Q) -> io::Result<u64>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let input = io::BufReader::with_capacity(FILE_BUF_SIZE, File::open(src)?);
+    replace_file(dst.as_ref(), |out| copy_decode(input, out))
+}
+
+/// Compresses the file at `src` into `dst` like `compress_file`, memory
+/// mapping `src` and handing all of it to the encoder at once.
+///
+/// This saves copying the input through a buffer, which adds up for very
+/// large files. It is only available on Unix with the `mmap` feature.
+///
+/// # Safety
+///
+/// `src` must not be modified or truncated, by this or any other process,
+/// until this returns. See `mmap::Mmap::map`.
+#[cfg(all(feature = "mmap", unix))]
+pub unsafe fn compress_file_mapped<P, Q>(
+    src: P,
+    dst: Q,
+    params: Option<&CompressParams>,
+) -> io::Result<u64>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let input = File::open(src)?;
+    let params = file_params(&input, params)?;
+    let map = mmap::Mmap::map(&input)?;
+    replace_file(dst.as_ref(), |out| {
+        let mut data = &map[..];
+        let mut compress = raw::Compress::new();
+        compress.set_params(&params)?;
+        loop {
+            let status = compress.compress(raw::CompressOp::Finish, &mut data, &mut &mut [][..])?;
+            while let Some(buf) = compress.take_output(None) {
+                out.write_all(buf)?;
+            }
+            match status {
+                raw::CoStatus::Finished => break,
+                raw::CoStatus::Unfinished => {}
+            }
+        }
+        out.flush()?;
+        Ok(map.len() as u64)
+    })
+}
+
+/// Decompresses the brotli file at `src` into `dst` like `decompress_file`,
+/// memory mapping `src`.
+///
+/// It is only available on Unix with the `mmap` feature.
+///
+/// # Safety
+///
+/// `src` must not be modified or truncated, by this or any other process,
+/// until this returns. See `mmap::Mmap::map`.
+#[cfg(all(feature = "mmap", unix))]
+pub unsafe fn decompress_file_mapped<P, Q>(src: P, dst: Q) -> io::Result<u64>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let map = mmap::Mmap::open(src)?;
+    replace_file(dst.as_ref(), |out| {
+        let mut data = &map[..];
+        let mut decompress = raw::Decompress::new();
+        loop {
+            let status = decompress.decompress(&mut data, &mut &mut [][..])?;
+            while let Some(buf) = decompress.take_output(None) {
+                out.write_all(buf)?;
+            }
+            match status {
+                raw::DeStatus::Finished => break,
+                raw::DeStatus::NeedInput => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "corrupted brotli stream",
+                    ))
+                }
+                raw::DeStatus::NeedOutput => {}
+            }
+        }
+        out.flush()?;
+        Ok(decompress.total_out())
+    })
+}
+
+/// Returns `params`, or the defaults tuned to the length of `input`.
+fn file_params(input: &File, params: Option<&CompressParams>) -> io::Result<CompressParams> {
+    if let Some(params) = params {
+        return Ok(params.clone());
+    }
+    let len = cmp::min(input.metadata()?.len(), usize::MAX as u64) as usize;
+    let mut params = CompressParams::new();
+    params.lgwin_for_input(len).size_hint(len);
+    Ok(params)
+}
+
+/// Runs `f` against a temporary file next to `dst`, then moves it over `dst`
+/// if `f` succeeds.
+fn replace_file<F>(dst: &Path, f: F) -> io::Result<u64>
+where
+    F: FnOnce(&mut io::BufWriter<File>) -> io::Result<u64>,
+{
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let name = match dst.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "output path has no file name",
+            ))
+        }
+    };
+    let (tmp, file) = loop {
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let tmp = dst.with_file_name(format!(".{}.{}-{}.tmp", name, process::id(), n));
+        match OpenOptions::new().write(true).create_new(true).open(&tmp) {
+            Ok(file) => break (tmp, file),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    };
+
+    let res = (|| {
+        let mut out = io::BufWriter::with_capacity(FILE_BUF_SIZE, file);
+        let n = f(&mut out)?;
+        let file = out.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&tmp, dst)?;
+        Ok(n)
+    })();
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
     }
+    res
 }
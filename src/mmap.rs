@@ -0,0 +1,143 @@
+//! Read-only memory maps of input files.
+//!
+//! This module is only available on Unix with the `mmap` feature enabled. A
+//! mapped file derefs to `&[u8]`, so it can be handed straight to the one-shot
+//! functions in `raw` such as `compress_vec` and `decompress_cb`, without
+//! reading the whole file into memory first.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+use std::slice;
+
+use libc::{self, c_void};
+
+/// A read-only memory map of a whole file.
+pub struct Mmap {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Maps the whole of `file` into memory.
+    ///
+    /// Empty files are supported and map to an empty slice.
+    ///
+    /// # Safety
+    ///
+    /// The mapping reflects later changes to the file, so the caller must make
+    /// sure the file is not modified or truncated while the map is alive.
+    /// Doing so can change bytes behind an immutable slice or kill the process
+    /// with `SIGBUS`.
+    pub unsafe fn map(file: &File) -> io::Result<Mmap> {
+        let len = file.metadata()?.len();
+        if len > usize::MAX as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "file is too large to map",
+            ));
+        }
+        let len = len as usize;
+        if len == 0 {
+            return Ok(Mmap {
+                ptr: ptr::null_mut(),
+                len: 0,
+            });
+        }
+        let ptr = libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ,
+            libc::MAP_PRIVATE,
+            file.as_raw_fd(),
+            0,
+        );
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // The input is read front to back exactly once.
+        libc::madvise(ptr, len, libc::MADV_SEQUENTIAL);
+        Ok(Mmap { ptr, len })
+    }
+
+    /// Opens the file at `path` and maps it into memory.
+    ///
+    /// # Safety
+    ///
+    /// The same requirements as for `map` apply.
+    pub unsafe fn open<P: AsRef<Path>>(path: P) -> io::Result<Mmap> {
+        Mmap::map(&File::open(path)?)
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl AsRef<[u8]> for Mmap {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for Mmap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Mmap")
+            .field("ptr", &self.ptr)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.len != 0 {
+            unsafe {
+                libc::munmap(self.ptr, self.len);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mmap;
+    use raw;
+    use std::env;
+    use std::fs::{self, File};
+    use CompressParams;
+
+    #[test]
+    fn map_file() {
+        let path = env::temp_dir().join(format!("brotli2-mmap-{}", ::std::process::id()));
+        let data = (0..100_000u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+            .collect::<Vec<_>>();
+        fs::write(&path, &data).unwrap();
+        let map = unsafe { Mmap::open(&path).unwrap() };
+        assert_eq!(&map[..], &data[..]);
+
+        let compressed = raw::compress_vec(&CompressParams::new(), &map).unwrap();
+        assert_eq!(raw::decompress_vec(&compressed).unwrap(), data);
+        drop(map);
+
+        File::create(&path).unwrap();
+        let map = unsafe { Mmap::open(&path).unwrap() };
+        assert!(map.is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
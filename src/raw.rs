@@ -1,16 +1,27 @@
 //! Raw interface to in-memory compression/decompression streams
 
+use std::any::Any;
+use std::cmp;
 use std::error;
+use std::ffi::CStr;
 use std::fmt;
 use std::io;
-use std::mem;
+use std::io::prelude::*;
+use std::io::IoSlice;
+use std::mem::{self, MaybeUninit};
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
 use std::ptr;
 use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 
 use brotli_sys;
-use libc::c_int;
+#[cfg(feature = "bytes")]
+use bytes::{Bytes, BytesMut};
+use libc::{c_int, c_void, size_t};
 
-use super::CompressParams;
+use super::{CompressParams, DecompressParams, ParamError, Quality};
 
 /// In-memory state for decompressing brotli-encoded data.
 ///
@@ -18,6 +29,19 @@ use super::CompressParams;
 /// incoming brotli stream.
 pub struct Decompress {
     state: *mut brotli_sys::BrotliDecoderState,
+    dicts: Vec<Dictionary>,
+    params: DecompressParams,
+    total_in: u64,
+    total_out: u64,
+    peeked: Peeked,
+    #[cfg(feature = "bytes")]
+    bytes: BytesMut,
+    // Referenced by `state`, like `allocator`.
+    metadata: Option<Box<Metadata>>,
+    // Whether `state` has finished a message and could not be replaced yet.
+    stale: bool,
+    // Referenced by `state`, and dropped only after `drop` destroys it.
+    allocator: Box<Recycler>,
 }
 
 unsafe impl Send for Decompress {}
@@ -29,11 +53,671 @@ unsafe impl Sync for Decompress {}
 /// data.
 pub struct Compress {
     state: *mut brotli_sys::BrotliEncoderState,
+    dicts: Vec<Dictionary>,
+    params: Option<CompressParams>,
+    total_in: u64,
+    total_out: u64,
+    // Whether input has been consumed since the last completed flush.
+    unflushed: bool,
+    // The stream data consumed, not counting metadata, or `None` for a state
+    // adopted with `from_raw`, where it is unknown.
+    data_in: Option<u64>,
+    peeked: Peeked,
+    #[cfg(feature = "bytes")]
+    bytes: BytesMut,
+    // Whether `state` has finished a message and could not be replaced yet.
+    stale: bool,
+    allocator: Box<Recycler>,
 }
 
 unsafe impl Send for Compress {}
 unsafe impl Sync for Compress {}
 
+/// A compressor that has not compressed anything yet, to be configured and
+/// then turned into a `Compress` with `start`.
+///
+/// libbrotli only accepts parameters and dictionaries before compression
+/// starts, so `Compress::set_params` fails at runtime when called too late.
+/// This type has no way to compress data, so configuring it can only fail
+/// because of the values given, and once `start` has been called the
+/// compressor can no longer be configured by mistake.
+///
+/// # Examples
+///
+/// ```
+/// use brotli2::CompressParams;
+/// use brotli2::raw::{decompress_vec, CompressBuilder};
+///
+/// let mut builder = CompressBuilder::new();
+/// builder.params(CompressParams::new().quality(9)).unwrap();
+/// let mut compress = builder.start();
+/// let data = compress.compress_msg(b"hello, hello!").unwrap();
+/// assert_eq!(decompress_vec(&data).unwrap(), b"hello, hello!");
+/// ```
+#[derive(Debug)]
+pub struct CompressBuilder {
+    inner: Compress,
+}
+
+/// A custom memory allocator for the internal state of a `Compress` or
+/// `Decompress`.
+///
+/// All memory brotli allocates for a stream created with `with_allocator` is
+/// obtained from the allocator, which allows it to be routed to a dedicated
+/// pool or tracked. Memory that brotli frees is kept by the stream to be
+/// reused, including after `reset`, and is returned to the allocator at the
+/// latest when the stream is dropped. A panic in either method aborts the
+/// process, as it cannot unwind through libbrotli.
+///
+/// # Safety
+///
+/// `alloc` must return either null, signalling allocation failure, or a
+/// pointer to at least `size` bytes suitably aligned for any type, like
+/// `malloc`. That memory must stay valid until it is passed to `free`.
+pub unsafe trait Allocator: Send + Sync {
+    /// Allocates `size` bytes of memory, returning null on failure.
+    fn alloc(&self, size: usize) -> *mut u8;
+
+    /// Frees memory previously returned by `alloc`.
+    ///
+    /// libbrotli does not pass the size of the allocation back, so allocators
+    /// that need it must record it themselves.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be null, in which case this does nothing, or a pointer
+    /// returned by `alloc` on this allocator that has not been freed yet.
+    unsafe fn free(&self, ptr: *mut u8);
+}
+
+/// The C library's `malloc` and `free`, which streams use by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Malloc;
+
+unsafe impl Allocator for Malloc {
+    fn alloc(&self, size: usize) -> *mut u8 {
+        unsafe { libc::malloc(size) as *mut u8 }
+    }
+
+    unsafe fn free(&self, ptr: *mut u8) {
+        libc::free(ptr as *mut c_void)
+    }
+}
+
+/// An `Allocator` that wraps another and keeps track of how much memory is
+/// allocated through it.
+///
+/// Giving each stream its own `Tracking` allocator attributes memory to
+/// individual compression sessions:
+///
+/// ```
+/// use brotli2::raw::{Compress, Malloc, Tracking};
+///
+/// let allocator = Tracking::new(Malloc);
+/// let usage = allocator.usage();
+/// let compress = Compress::with_allocator(allocator).unwrap();
+/// assert!(usage.current() > 0);
+/// drop(compress);
+/// assert_eq!(usage.current(), 0);
+/// ```
+#[derive(Debug)]
+pub struct Tracking<A> {
+    inner: A,
+    usage: MemoryUsage,
+    limit: usize,
+}
+
+/// A handle to the memory usage counters of a `Tracking` allocator.
+///
+/// Handles are cheap to clone and stay valid after the allocator and its
+/// stream are dropped.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryUsage {
+    inner: Arc<MemoryCounters>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryCounters {
+    current: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+// Room in front of each allocation to record its size, keeping the returned
+// pointer aligned for any type.
+const SIZE_HEADER: usize = 16;
+
+impl<A: Allocator> Tracking<A> {
+    /// Wraps `inner`, with memory usage counters starting at zero.
+    pub fn new(inner: A) -> Tracking<A> {
+        Tracking::with_limit(inner, usize::MAX)
+    }
+
+    /// Wraps `inner`, failing any allocation that would bring the memory
+    /// allocated through this allocator above `limit` bytes.
+    pub fn with_limit(inner: A, limit: usize) -> Tracking<A> {
+        Tracking {
+            inner,
+            usage: MemoryUsage::default(),
+            limit,
+        }
+    }
+
+    /// Returns a handle to the memory usage counters of this allocator.
+    pub fn usage(&self) -> MemoryUsage {
+        self.usage.clone()
+    }
+}
+
+unsafe impl<A: Allocator> Allocator for Tracking<A> {
+    fn alloc(&self, size: usize) -> *mut u8 {
+        let total = match size.checked_add(SIZE_HEADER) {
+            Some(total) => total,
+            None => return ptr::null_mut(),
+        };
+        let counters = &self.usage.inner;
+        let current = counters.current.fetch_add(size, Ordering::Relaxed) + size;
+        if current > self.limit {
+            counters.current.fetch_sub(size, Ordering::Relaxed);
+            return ptr::null_mut();
+        }
+        let ptr = self.inner.alloc(total);
+        if ptr.is_null() {
+            counters.current.fetch_sub(size, Ordering::Relaxed);
+            return ptr;
+        }
+        counters.peak.fetch_max(current, Ordering::Relaxed);
+        unsafe {
+            (ptr as *mut usize).write(size);
+            ptr.add(SIZE_HEADER)
+        }
+    }
+
+    unsafe fn free(&self, ptr: *mut u8) {
+        if ptr.is_null() {
+            return;
+        }
+        let ptr = ptr.sub(SIZE_HEADER);
+        let size = (ptr as *const usize).read();
+        self.usage.inner.current.fetch_sub(size, Ordering::Relaxed);
+        self.inner.free(ptr)
+    }
+}
+
+impl MemoryUsage {
+    /// Returns the number of bytes currently allocated.
+    pub fn current(&self) -> usize {
+        self.inner.current.load(Ordering::Relaxed)
+    }
+
+    /// Returns the largest number of bytes allocated at any one time.
+    pub fn peak(&self) -> usize {
+        self.inner.peak.load(Ordering::Relaxed)
+    }
+}
+
+// Runs `f` on behalf of libbrotli, aborting the process if it panics, since
+// unwinding out of an `extern "C"` callback is undefined behavior before Rust
+// 1.81.
+fn abort_on_panic<T, F: FnOnce() -> T>(f: F) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(value) => value,
+        Err(_) => process::abort(),
+    }
+}
+
+extern "C" fn alloc_func(opaque: *mut c_void, size: size_t) -> *mut c_void {
+    let allocator = unsafe { &*(opaque as *const Box<dyn Allocator>) };
+    abort_on_panic(|| allocator.alloc(size) as *mut c_void)
+}
+
+extern "C" fn free_func(opaque: *mut c_void, ptr: *mut c_void) {
+    let allocator = unsafe { &*(opaque as *const Box<dyn Allocator>) };
+    abort_on_panic(|| unsafe { allocator.free(ptr as *mut u8) })
+}
+
+// The allocator behind the state of a `Compress` or `Decompress`. It keeps
+// freed memory to hand it out again, both to the state itself and to the one
+// replacing it, since libbrotli cannot clear a state in place and allocates
+// again for every stream.
+struct Recycler {
+    // The allocator given by the user, or `malloc` if `None`.
+    inner: Option<Box<dyn Allocator>>,
+    spare: Mutex<Spare>,
+}
+
+struct Spare {
+    // Freed blocks, as addresses, sizes and the stream they were freed in.
+    blocks: Vec<(usize, usize, u64)>,
+    stream: u64,
+    // The bytes obtained from `inner`, whether in use or spare, and how many
+    // may be.
+    held: usize,
+    limit: usize,
+}
+
+impl Recycler {
+    fn new(inner: Option<Box<dyn Allocator>>) -> Box<Recycler> {
+        Box::new(Recycler {
+            inner,
+            spare: Mutex::new(Spare {
+                blocks: Vec::new(),
+                stream: 0,
+                held: 0,
+                limit: usize::MAX,
+            }),
+        })
+    }
+
+    fn spare(&self) -> MutexGuard<'_, Spare> {
+        self.spare.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn alloc(&self, size: usize) -> *mut u8 {
+        let mut spare = self.spare();
+        // libbrotli asks for the same sizes again for similar streams, so
+        // only blocks of exactly the requested size are reused.
+        let fit = spare.blocks.iter().position(|&(_, block, _)| block == size);
+        if let Some(i) = fit {
+            return spare.blocks.swap_remove(i).0 as *mut u8;
+        }
+        let total = match size.checked_add(SIZE_HEADER) {
+            Some(total) => total,
+            None => return ptr::null_mut(),
+        };
+        // The spare blocks may be what keeps the allocation from fitting in
+        // the limit, or in a limited allocator.
+        if spare.held.saturating_add(size) > spare.limit {
+            self.release(&mut spare, |_| true);
+            if spare.held.saturating_add(size) > spare.limit {
+                return ptr::null_mut();
+            }
+        }
+        let mut ptr = self.alloc_inner(total);
+        if ptr.is_null() {
+            self.release(&mut spare, |_| true);
+            ptr = self.alloc_inner(total);
+        }
+        if ptr.is_null() {
+            return ptr;
+        }
+        spare.held += size;
+        unsafe {
+            (ptr as *mut usize).write(size);
+            ptr.add(SIZE_HEADER)
+        }
+    }
+
+    fn alloc_inner(&self, total: usize) -> *mut u8 {
+        match self.inner {
+            Some(ref inner) => inner.alloc(total),
+            None => unsafe { libc::malloc(total) as *mut u8 },
+        }
+    }
+
+    unsafe fn free(&self, ptr: *mut u8) {
+        if ptr.is_null() {
+            return;
+        }
+        let size = (ptr.sub(SIZE_HEADER) as *const usize).read();
+        let mut spare = self.spare();
+        let stream = spare.stream;
+        spare.blocks.push((ptr as usize, size, stream));
+    }
+
+    // Marks the start of a new stream, releasing the blocks that the last
+    // one did not use.
+    fn next_stream(&self) {
+        let mut spare = self.spare();
+        let last = spare.stream;
+        self.release(&mut spare, |stream| stream < last);
+        spare.stream += 1;
+    }
+
+    // Bounds the memory held to `limit` bytes from now on, returning whether
+    // what is already held fits. The limit is left unchanged if not.
+    fn set_limit(&self, limit: usize) -> bool {
+        let mut spare = self.spare();
+        if spare.held > limit {
+            self.release(&mut spare, |_| true);
+            if spare.held > limit {
+                return false;
+            }
+        }
+        spare.limit = limit;
+        true
+    }
+
+    // Returns the spare blocks freed in a stream matching `which` to the
+    // underlying allocator.
+    fn release<F: Fn(u64) -> bool>(&self, spare: &mut Spare, which: F) {
+        let mut i = 0;
+        while i < spare.blocks.len() {
+            let (ptr, size, stream) = spare.blocks[i];
+            if !which(stream) {
+                i += 1;
+                continue;
+            }
+            spare.blocks.swap_remove(i);
+            spare.held -= size;
+            unsafe {
+                let ptr = (ptr as *mut u8).sub(SIZE_HEADER);
+                match self.inner {
+                    Some(ref inner) => inner.free(ptr),
+                    None => libc::free(ptr as *mut c_void),
+                }
+            }
+        }
+    }
+
+    // Returns the libbrotli callbacks and opaque pointer for this allocator.
+    fn hooks(
+        &self,
+    ) -> (
+        brotli_sys::brotli_alloc_func,
+        brotli_sys::brotli_free_func,
+        *mut c_void,
+    ) {
+        (
+            Some(recycler_alloc),
+            Some(recycler_free),
+            self as *const Recycler as *mut c_void,
+        )
+    }
+}
+
+impl Drop for Recycler {
+    fn drop(&mut self) {
+        let mut spare = self.spare();
+        self.release(&mut spare, |_| true);
+    }
+}
+
+extern "C" fn recycler_alloc(opaque: *mut c_void, size: size_t) -> *mut c_void {
+    let recycler = unsafe { &*(opaque as *const Recycler) };
+    abort_on_panic(|| recycler.alloc(size) as *mut c_void)
+}
+
+extern "C" fn recycler_free(opaque: *mut c_void, ptr: *mut c_void) {
+    let recycler = unsafe { &*(opaque as *const Recycler) };
+    abort_on_panic(|| unsafe { recycler.free(ptr as *mut u8) })
+}
+
+/// A custom prefix dictionary, prepared once and shareable between many
+/// compression and decompression streams.
+///
+/// Preparing a dictionary for the encoder involves hashing its contents, so
+/// services compressing many messages against the same dictionary should
+/// create one `Dictionary` up front and attach it to each stream with
+/// `Compress::attach_dictionary` and `Decompress::attach_dictionary`. Cloning
+/// a `Dictionary` is cheap and shares the prepared state.
+#[derive(Clone)]
+pub struct Dictionary {
+    inner: Arc<DictionaryInner>,
+}
+
+struct DictionaryInner {
+    prepared: *mut brotli_sys::BrotliEncoderPreparedDictionary,
+    storage: DictionaryStorage,
+}
+
+enum DictionaryStorage {
+    // Prepared by libbrotli, with its hash tables in memory obtained from
+    // `allocator`, at `tables` if they could be found. Both the prepared
+    // dictionary and attached decoders refer to `data` rather than copying it.
+    Prepared {
+        data: Box<[u8]>,
+        tables: Option<(*const u8, usize)>,
+        _allocator: Box<Box<dyn Allocator>>,
+    },
+    // Loaded by `Dictionary::read_from`: the hash tables followed by the
+    // dictionary contents, in the self-contained layout libbrotli accepts
+    // as a prepared dictionary. `prepared` points to the start of `blob`.
+    Loaded {
+        blob: Box<[u32]>,
+        tables_len: usize,
+        data_len: usize,
+    },
+}
+
+unsafe impl Send for DictionaryInner {}
+unsafe impl Sync for DictionaryInner {}
+
+const DICTIONARY_MAGIC: &[u8; 8] = b"BROTDICT";
+
+// Written in native byte order after the libbrotli version of a serialized
+// dictionary, so that tables written on a machine of the other byte order
+// are recognized.
+const BYTE_ORDER_MARK: u32 = 0x0102_0304;
+
+// libbrotli's magic numbers for a prepared raw dictionary that contains its
+// contents, and for one that points to them instead.
+const PREPARED_MAGIC: u32 = 0xDEBC_EDE0;
+const LEAN_PREPARED_MAGIC: u32 = 0xDEBC_EDE3;
+
+// The prepared dictionary header: the magic number, the number of hash
+// items, the contents size, and the hash, bucket and slot bit counts. The
+// slot offsets, bucket heads and items follow.
+const PREPARED_HEADER: usize = 24;
+
+/// An `Allocator` that keeps track of the allocations still alive, used to
+/// find the hash tables of a dictionary libbrotli has prepared.
+#[derive(Default)]
+struct Recording {
+    live: Arc<Mutex<Vec<(usize, usize)>>>,
+}
+
+unsafe impl Allocator for Recording {
+    fn alloc(&self, size: usize) -> *mut u8 {
+        let ptr = Malloc.alloc(size);
+        if !ptr.is_null() {
+            let mut live = self.live.lock().unwrap_or_else(|e| e.into_inner());
+            live.push((ptr as usize, size));
+        }
+        ptr
+    }
+
+    unsafe fn free(&self, ptr: *mut u8) {
+        if !ptr.is_null() {
+            let mut live = self.live.lock().unwrap_or_else(|e| e.into_inner());
+            live.retain(|&(p, _)| p != ptr as usize);
+        }
+        Malloc.free(ptr)
+    }
+}
+
+fn read_u32(bytes: &[u8], index: usize) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&bytes[index * 4..index * 4 + 4]);
+    u32::from_ne_bytes(word)
+}
+
+/// Returns the length of the hash tables described by the prepared
+/// dictionary header at the start of `tables`, if the header is one this
+/// crate can read.
+fn prepared_tables_len(tables: &[u8]) -> Option<usize> {
+    if tables.len() < PREPARED_HEADER {
+        return None;
+    }
+    let num_items = read_u32(tables, 1) as usize;
+    let hash_bits = read_u32(tables, 3);
+    let bucket_bits = read_u32(tables, 4);
+    let slot_bits = read_u32(tables, 5);
+    if !(1..=64).contains(&hash_bits)
+        || !(1..=16).contains(&slot_bits)
+        || !(slot_bits..=24).contains(&bucket_bits)
+    {
+        return None;
+    }
+    num_items
+        .checked_mul(4)?
+        .checked_add(PREPARED_HEADER + (4 << slot_bits) + (2 << bucket_bits))
+}
+
+/// Checks that the prepared dictionary tables in `tables` only refer to
+/// items and contents that exist, so that libbrotli can use them safely.
+fn check_prepared_tables(tables: &[u8], data_len: usize) -> bool {
+    if prepared_tables_len(tables) != Some(tables.len())
+        || read_u32(tables, 0) != PREPARED_MAGIC
+        || read_u32(tables, 2) as usize != data_len
+    {
+        return false;
+    }
+    let num_items = read_u32(tables, 1) as usize;
+    let num_slots = 1 << read_u32(tables, 5);
+    let num_buckets = 1 << read_u32(tables, 4);
+    let slot_offsets = PREPARED_HEADER / 4;
+    let heads = slot_offsets + num_slots;
+    let items = heads + num_buckets / 2;
+    if (0..num_slots).any(|i| read_u32(tables, slot_offsets + i) as usize > num_items) {
+        return false;
+    }
+    for key in 0..num_buckets {
+        let at = heads * 4 + key * 2;
+        let head = u16::from_ne_bytes([tables[at], tables[at + 1]]) as usize;
+        let slot = read_u32(tables, slot_offsets + (key & (num_slots - 1))) as usize;
+        if head != 0xFFFF && slot + head >= num_items {
+            return false;
+        }
+    }
+    // Every chain of items ends at an item with the top bit set, so the
+    // last item must have it for chains to stay in bounds.
+    num_items == 0
+        || read_u32(tables, items + num_items - 1) & 0x8000_0000 != 0
+            && (0..num_items).all(|i| (read_u32(tables, items + i) & 0x7FFF_FFFF) < data_len as u32)
+}
+
+/// Iterator over the pending output of a stream, returned by
+/// `Compress::output_chunks` and `Decompress::output_chunks`.
+///
+/// As an `Iterator` it yields each chunk copied into a vector of its own.
+/// `take_chunk` and `append_to` instead lend chunks straight from the
+/// stream's output buffer, which avoids allocating for every chunk but
+/// only keeps each one valid until the next call.
+pub struct OutputChunks<'a> {
+    stream: Stream<'a>,
+}
+
+impl<'a> OutputChunks<'a> {
+    /// Takes the next chunk of output that is ready, as `take_output` would,
+    /// or returns `None` once there is none left.
+    pub fn take_chunk(&mut self) -> Option<&[u8]> {
+        match self.stream {
+            Stream::Compress(ref mut c) => c.take_output(None),
+            Stream::Decompress(ref mut d) => d.take_output(None),
+        }
+    }
+
+    /// Appends all remaining chunks to `buf`, returning how many bytes were
+    /// appended.
+    ///
+    /// `buf` is only grown as needed, so reusing one buffer across calls
+    /// avoids allocating for every chunk.
+    pub fn append_to(mut self, buf: &mut Vec<u8>) -> usize {
+        let start = buf.len();
+        while let Some(chunk) = self.take_chunk() {
+            buf.extend_from_slice(chunk);
+        }
+        buf.len() - start
+    }
+}
+
+impl<'a> Iterator for OutputChunks<'a> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        self.take_chunk().map(|chunk| chunk.to_vec())
+    }
+}
+
+enum Stream<'a> {
+    Compress(&'a mut Compress),
+    Decompress(&'a mut Decompress),
+}
+
+/// Output moved out of libbrotli by `peek_output`, or lent from its ring
+/// buffer by `Decompress::fill_output`, but not yet handed to the caller.
+///
+/// Lent output is only valid until the next call into the decoder, so it is
+/// copied into `buf` by `stash` before any such call.
+struct Peeked {
+    buf: Vec<u8>,
+    pos: usize,
+    lent: *const u8,
+    lent_len: usize,
+}
+
+impl Default for Peeked {
+    fn default() -> Peeked {
+        Peeked {
+            buf: Vec::new(),
+            pos: 0,
+            lent: ptr::null(),
+            lent_len: 0,
+        }
+    }
+}
+
+impl Peeked {
+    fn is_empty(&self) -> bool {
+        self.lent_len == 0 && self.pos == self.buf.len()
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        if self.lent_len > 0 {
+            unsafe { slice::from_raw_parts(self.lent, self.lent_len) }
+        } else {
+            &self.buf[self.pos..]
+        }
+    }
+
+    fn extend(&mut self, data: &[u8]) {
+        self.stash();
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        self.buf.extend_from_slice(data);
+    }
+
+    // Holds on to `data`, which must stay valid until the output is taken or
+    // stashed, without copying it.
+    fn lend(&mut self, data: &[u8]) {
+        debug_assert!(self.is_empty());
+        self.lent = data.as_ptr();
+        self.lent_len = data.len();
+    }
+
+    // Copies lent output into `buf`, so that the decoder can be called again.
+    fn stash(&mut self) {
+        if self.lent_len > 0 {
+            let lent = unsafe { slice::from_raw_parts(self.lent, self.lent_len) };
+            self.buf.clear();
+            self.pos = 0;
+            self.buf.extend_from_slice(lent);
+            self.lent_len = 0;
+        }
+    }
+
+    fn take(&mut self, size_limit: Option<usize>) -> &[u8] {
+        if self.lent_len > 0 {
+            let n = cmp::min(size_limit.unwrap_or(usize::MAX), self.lent_len);
+            let out = unsafe { slice::from_raw_parts(self.lent, n) };
+            self.lent = unsafe { self.lent.add(n) };
+            self.lent_len -= n;
+            return out;
+        }
+        let start = self.pos;
+        let n = cmp::min(size_limit.unwrap_or(usize::MAX), self.buf.len() - start);
+        self.pos += n;
+        &self.buf[start..start + n]
+    }
+
+    // Copies as much as fits into the `len` bytes at `out`.
+    unsafe fn copy_to(&mut self, out: *mut u8, len: usize) -> usize {
+        let n = cmp::min(len, self.as_slice().len());
+        ptr::copy_nonoverlapping(self.take(Some(n)).as_ptr(), out, n);
+        n
+    }
+}
+
 /// Possible choices for the operation performed by the compressor.
 ///
 /// When using any operation except `Process`, you must *not* alter the
@@ -58,9 +742,157 @@ pub enum CompressOp {
     EmitMetadata = brotli_sys::BROTLI_OPERATION_EMIT_METADATA as isize,
 }
 
+/// The largest metadata block that can be written with
+/// `CompressOp::EmitMetadata`, 16MiB.
+pub const MAX_METADATA_LEN: usize = 1 << 24;
+
+type MetadataCallback = Box<dyn FnMut(&[u8]) + Send>;
+
+/// How a decoder handles metadata blocks, opaque out-of-band data that a
+/// stream can carry alongside its contents.
+#[derive(Default)]
+pub enum MetadataPolicy {
+    /// Skip metadata blocks. This is the default.
+    #[default]
+    Skip,
+    /// Pass the contents of each metadata block to a callback, as described
+    /// for `Decompress::set_metadata_callback`.
+    Callback(MetadataCallback),
+    /// Fail with an error of kind `ErrorKind::Metadata` at the first
+    /// metadata block, for consumers that do not accept hidden side channels.
+    Reject,
+}
+
+impl fmt::Debug for MetadataPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            MetadataPolicy::Skip => "Skip",
+            MetadataPolicy::Callback(_) => "Callback(..)",
+            MetadataPolicy::Reject => "Reject",
+        })
+    }
+}
+
+// Collects metadata blocks for a decoder, passing them to `callback` or
+// rejecting them if there is none.
+struct Metadata {
+    callback: Option<MetadataCallback>,
+    block: Vec<u8>,
+    remaining: usize,
+    rejected: bool,
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+impl Metadata {
+    fn deliver(&mut self) {
+        if self.panic.is_some() {
+            return;
+        }
+        let block = &self.block;
+        if let Some(ref mut callback) = self.callback {
+            if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| callback(block))) {
+                self.panic = Some(panic);
+            }
+        }
+    }
+}
+
+extern "C" fn metadata_start(opaque: *mut c_void, size: size_t) {
+    let metadata = unsafe { &mut *(opaque as *mut Metadata) };
+    if metadata.callback.is_none() {
+        metadata.rejected = true;
+        return;
+    }
+    metadata.block.clear();
+    metadata.remaining = size;
+    if size == 0 {
+        metadata.deliver();
+    }
+}
+
+extern "C" fn metadata_chunk(opaque: *mut c_void, data: *const u8, size: size_t) {
+    let metadata = unsafe { &mut *(opaque as *mut Metadata) };
+    if metadata.callback.is_none() {
+        return;
+    }
+    let chunk = unsafe { slice::from_raw_parts(data, size) };
+    metadata.block.extend_from_slice(chunk);
+    metadata.remaining -= cmp::min(size, metadata.remaining);
+    if metadata.remaining == 0 {
+        metadata.deliver();
+    }
+}
+
 /// Error that can happen from decompressing or compressing a brotli stream.
+///
+/// Errors record the operation that failed, and errors reported by libbrotli
+/// with an error code have a `LibraryError` as their `source`. When converted
+/// to an `io::Error` the original `Error` is kept, and can be recovered with
+/// `io::Error::get_ref` and `downcast_ref`.
 #[derive(Debug, Clone, PartialEq)]
-pub struct Error(());
+pub struct Error {
+    kind: ErrorKind,
+    op: &'static str,
+    source: Option<LibraryError>,
+    // Whether this error comes from configuring parameters or dictionaries.
+    config: bool,
+}
+
+/// An error code reported by libbrotli, along with its description.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LibraryError {
+    code: i32,
+    message: &'static str,
+}
+
+/// The general category of an `Error`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// A compression or decompression parameter was rejected because its
+    /// value is out of range. Contains the name of the parameter.
+    InvalidParameter(&'static str),
+    /// Two compression parameters have values libbrotli cannot use together.
+    /// Contains the names of both parameters.
+    ConflictingParameters(&'static str, &'static str),
+    /// Parameters can no longer be changed, because the stream has already
+    /// started.
+    AlreadyStarted,
+    /// The decoder rejected its input.
+    Decode(DecodeError),
+    /// libbrotli returned a result code this crate does not know about,
+    /// contained here. This may happen when linking against a newer
+    /// libbrotli.
+    UnknownResult(i32),
+    /// libbrotli failed to allocate memory.
+    Alloc,
+    /// The output would exceed a size limit set by the caller.
+    LimitExceeded,
+    /// The stream contains a metadata block, and the decoder was configured
+    /// with `MetadataPolicy::Reject`.
+    Metadata,
+    /// Any other error reported by brotli.
+    Other,
+}
+
+/// The reason a decoder rejected its input, as reported by libbrotli.
+///
+/// The precise libbrotli error is available from `Error::code` and
+/// `Error::source`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DecodeError {
+    /// The input is not a valid brotli stream, for example because it is
+    /// truncated, corrupted, or was not brotli-compressed at all.
+    Format,
+    /// The stream requires a custom dictionary that was not attached, or the
+    /// attached dictionaries could not be used.
+    Dictionary,
+    /// The decoder failed to allocate memory.
+    Alloc,
+    /// Any other decoder error, such as an internal error in libbrotli.
+    Other,
+}
 
 /// Indication of whether a compression operation is 'complete'. This does
 /// not indicate whether the whole stream is complete - see `Compress::compress`
@@ -74,6 +906,32 @@ pub enum CoStatus {
     Unfinished,
 }
 
+/// The outcome of a call to `Compress::compress_detailed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressResult {
+    status: CoStatus,
+    consumed: usize,
+    produced: usize,
+}
+
+impl CompressResult {
+    /// Returns whether the operation is complete, as returned by
+    /// `Compress::compress`.
+    pub fn status(&self) -> CoStatus {
+        self.status
+    }
+
+    /// Returns the number of bytes of input consumed.
+    pub fn consumed(&self) -> usize {
+        self.consumed
+    }
+
+    /// Returns the number of bytes written to the output buffer.
+    pub fn produced(&self) -> usize {
+        self.produced
+    }
+}
+
 /// Possible status results returned from decompressing.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DeStatus {
@@ -88,382 +946,3182 @@ pub enum DeStatus {
 impl Decompress {
     /// Creates a new brotli decompression/decoding stream ready to receive
     /// data.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the decoder state cannot be allocated. See `try_new` for a
+    /// fallible alternative.
     pub fn new() -> Decompress {
-        unsafe {
-            let state = brotli_sys::BrotliDecoderCreateInstance(None, None, 0 as *mut _);
-            assert!(!state.is_null());
-            Decompress { state: state }
-        }
+        Decompress::try_new().expect("failed to allocate brotli decoder")
     }
 
-    /// Decompress some input data and write it to a buffer of output data.
+    /// Creates a new brotli decompression/decoding stream ready to receive
+    /// data.
     ///
-    /// This function will decompress the data in `input` and place the output
-    /// in `output`, returning the result. Possible statuses that can be
-    /// returned are that the stream is finished, more input is needed, or more
-    /// output space is needed.
+    /// # Errors
     ///
-    /// The `input` slice is updated to point to the remaining data that was not
-    /// consumed, and the `output` slice is updated to point to the portion of
-    /// the output slice that still needs to be filled in.
+    /// Returns an error of kind `ErrorKind::Alloc` if the decoder state cannot
+    /// be allocated.
+    pub fn try_new() -> Result<Decompress, Error> {
+        Decompress::create(Recycler::new(None))
+    }
+
+    /// Creates a new brotli decompression/decoding stream whose internal
+    /// memory is allocated with `allocator`.
     ///
     /// # Errors
     ///
-    /// If the input stream is not a valid brotli stream, then an error is
-    /// returned.
-    pub fn decompress(
-        &mut self,
-        input: &mut &[u8],
-        output: &mut &mut [u8],
-    ) -> Result<DeStatus, Error> {
-        let mut available_in = input.len();
-        let mut next_in = input.as_ptr();
-        let mut available_out = output.len();
-        let mut next_out = output.as_mut_ptr();
-        let r = unsafe {
-            brotli_sys::BrotliDecoderDecompressStream(
-                self.state,
-                &mut available_in,
-                &mut next_in,
-                &mut available_out,
-                &mut next_out,
-                ptr::null_mut(),
-            )
-        };
-        *input = &input[input.len() - available_in..];
-        let out_len = output.len();
-        *output = &mut mem::replace(output, &mut [])[out_len - available_out..];
-        Decompress::rc(r)
+    /// Returns an error of kind `ErrorKind::Alloc` if the decoder state cannot
+    /// be allocated.
+    pub fn with_allocator<A: Allocator + 'static>(allocator: A) -> Result<Decompress, Error> {
+        Decompress::create(Recycler::new(Some(Box::new(allocator))))
     }
 
-    /// Retrieve a slice of the internal decompressor buffer up to `size_limit` in length
-    /// (unlimited length if `None`), consuming it. As the internal buffer may not be
-    /// contiguous, consecutive calls may return more output until this function returns
-    /// `None`.
-    pub fn take_output(&mut self, size_limit: Option<usize>) -> Option<&[u8]> {
-        if let Some(0) = size_limit {
-            return None;
-        }
-        let mut size_limit = size_limit.unwrap_or(0); // 0 now means unlimited
-        unsafe {
-            let ptr = brotli_sys::BrotliDecoderTakeOutput(self.state, &mut size_limit);
-            if size_limit == 0 {
-                // ptr may or may not be null
-                None
-            } else {
-                assert!(!ptr.is_null());
-                Some(slice::from_raw_parts(ptr, size_limit))
-            }
-        }
+    /// Creates a new decompressor configured with `params`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `ErrorKind::Alloc` if the decoder state cannot
+    /// be allocated, or does not fit in the memory limit of `params`.
+    pub fn with_params(params: &DecompressParams) -> Result<Decompress, Error> {
+        let mut decompress = Decompress::try_new()?;
+        decompress.set_params(params)?;
+        Ok(decompress)
     }
 
-    fn rc(rc: brotli_sys::BrotliDecoderResult) -> Result<DeStatus, Error> {
-        match rc {
-            // TODO: get info from BrotliDecoderGetErrorCode/BrotliDecoderErrorString
-            // for these decode errors
-            brotli_sys::BROTLI_DECODER_RESULT_ERROR => Err(Error(())),
-            brotli_sys::BROTLI_DECODER_RESULT_SUCCESS => Ok(DeStatus::Finished),
-            brotli_sys::BROTLI_DECODER_RESULT_NEEDS_MORE_INPUT => Ok(DeStatus::NeedInput),
-            brotli_sys::BROTLI_DECODER_RESULT_NEEDS_MORE_OUTPUT => Ok(DeStatus::NeedOutput),
-            n => panic!("unknown return code: {}", n),
-        }
+    /// Takes ownership of a decoder state created with brotli-sys, so that it
+    /// can be driven through this type.
+    ///
+    /// The state is destroyed when the returned value is dropped. `total_in`
+    /// and `total_out` only count data processed after this call, and any
+    /// parameters or dictionaries already applied to the state are not
+    /// known to it.
+    ///
+    /// # Safety
+    ///
+    /// `state` must be a valid pointer returned by
+    /// `brotli_sys::BrotliDecoderCreateInstance` that is not used or destroyed
+    /// elsewhere afterwards, and anything it references, such as attached
+    /// dictionaries or custom allocators, must outlive the returned value.
+    pub unsafe fn from_raw(state: *mut brotli_sys::BrotliDecoderState) -> Decompress {
+        Decompress::from_state(state, Recycler::new(None))
     }
-}
 
-impl Drop for Decompress {
-    fn drop(&mut self) {
-        unsafe {
-            brotli_sys::BrotliDecoderDestroyInstance(self.state);
+    /// Returns the underlying brotli-sys state, to call into libbrotli
+    /// directly.
+    ///
+    /// The pointer stays owned by this value. It is valid until this value
+    /// is dropped or its state is replaced by `reset` or by the message
+    /// methods.
+    /// Output made available through `peek_output` is held outside the
+    /// state, so it is not visible to direct calls.
+    pub fn as_raw(&self) -> *mut brotli_sys::BrotliDecoderState {
+        self.state
+    }
+
+    fn create(allocator: Box<Recycler>) -> Result<Decompress, Error> {
+        let state = Decompress::create_state(&allocator, "creating the decoder")?;
+        Ok(Decompress::from_state(state, allocator))
+    }
+
+    fn from_state(
+        state: *mut brotli_sys::BrotliDecoderState,
+        allocator: Box<Recycler>,
+    ) -> Decompress {
+        Decompress {
+            state,
+            dicts: Vec::new(),
+            params: DecompressParams::new(),
+            total_in: 0,
+            total_out: 0,
+            peeked: Peeked::default(),
+            #[cfg(feature = "bytes")]
+            bytes: BytesMut::new(),
+            metadata: None,
+            stale: false,
+            allocator,
         }
     }
-}
 
-/// Decompress data in one go in memory.
-///
-/// Decompresses the data in `input` into the `output` buffer. The `output`
-/// buffer is updated to point to the actual output slice if successful, or
-/// an error is returned. The output buffer being too small is considered
-/// to be an error.
-pub fn decompress_buf(input: &[u8], output: &mut &mut [u8]) -> Result<usize, Error> {
-    let mut size = output.len();
-    let r = unsafe {
-        brotli_sys::BrotliDecoderDecompress(
-            input.len(),
-            input.as_ptr(),
-            &mut size,
-            output.as_mut_ptr(),
-        )
-    };
-    *output = &mut mem::replace(output, &mut [])[..size];
-    if r == 0 {
-        Err(Error(()))
-    } else {
-        Ok(size)
+    fn create_state(
+        allocator: &Recycler,
+        op: &'static str,
+    ) -> Result<*mut brotli_sys::BrotliDecoderState, Error> {
+        let (alloc, free, opaque) = allocator.hooks();
+        let state = unsafe { brotli_sys::BrotliDecoderCreateInstance(alloc, free, opaque) };
+        if state.is_null() {
+            return Err(Error::new(ErrorKind::Alloc, op));
+        }
+        Ok(state)
     }
-}
 
-impl Compress {
-    /// Creates a new compressor ready to encode data into brotli
-    pub fn new() -> Compress {
+    /// Resets this decompressor so that it can decode a new, independent
+    /// stream.
+    ///
+    /// The dictionaries, the metadata policy and the parameters set with
+    /// `set_params` and its other setters are kept, but not raw parameters.
+    /// libbrotli cannot clear a decoder in place, so this replaces the
+    /// underlying state, but the memory of the old state is handed to the
+    /// new one rather than freed, so that decoding many streams one after
+    /// the other does not allocate for each of them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `ErrorKind::Alloc`, leaving this decompressor
+    /// unchanged, if the new state cannot be allocated.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        let state = Decompress::create_state(&self.allocator, "resetting the decoder")?;
         unsafe {
-            let state = brotli_sys::BrotliEncoderCreateInstance(None, None, 0 as *mut _);
-            assert!(!state.is_null());
+            brotli_sys::BrotliDecoderDestroyInstance(mem::replace(&mut self.state, state));
+        }
+        self.allocator.next_stream();
+        self.total_in = 0;
+        self.total_out = 0;
+        self.peeked = Peeked::default();
+        if let Some(mut metadata) = self.metadata.take() {
+            metadata.block.clear();
+            metadata.remaining = 0;
+            metadata.rejected = false;
+            self.install_metadata(metadata);
+        }
+        self.stale = false;
+        let result = self.configure();
+        self.stale = result.is_err();
+        result
+    }
 
-            Compress { state: state }
+    // Applies the parameters and dictionaries of this decompressor to a new
+    // state.
+    fn configure(&mut self) -> Result<(), Error> {
+        let params = self.params.clone();
+        self.set_params(&params)?;
+        for dict in self.dicts.clone().iter() {
+            self.attach(dict)?;
         }
+        Ok(())
     }
 
-    // TODO: add the BrotliEncoderOperation variants of
-    // BrotliEncoderCompressStream here
+    // Replaces the state if it was left finished by a message, so that it
+    // can be used again.
+    fn prepare(&mut self) -> Result<(), Error> {
+        if self.stale {
+            self.reset()?;
+        }
+        Ok(())
+    }
 
-    /// Pass some input data to the compressor and write it to a buffer of
-    /// output data, compressing or otherwise handling it as instructed by
-    /// the specified operation.
-    ///
-    /// This function will handle the data in `input` and place the output
-    /// in `output`, returning the Result. Possible statuses are that the
-    /// operation is complete or incomplete.
-    ///
-    /// The `input` slice is updated to point to the remaining data that was not
-    /// consumed, and the `output` slice is updated to point to the portion of
-    /// the output slice that still needs to be filled in.
+    /// Decompresses `input`, which must be exactly one complete brotli
+    /// stream, and prepares this decompressor for the next one.
     ///
-    /// If the result of a compress operation is `Unfinished` (which it may be
-    /// for any operation except `Process`), you *must* call the operation again
-    /// with the same operation and input buffer and more space to output to.
-    /// `Process` will never return `Unfinished`, but it is a logic error to end
-    /// a buffer without calling either `Flush` or `Finish` as some output data
-    /// may not have been written.
+    /// This is meant for decoding many small, independent messages with a
+    /// single decompressor. Afterwards, whether or not decoding succeeded,
+    /// the decompressor is back in its initial state as with `reset`, which
+    /// keeps its memory for the next message.
     ///
     /// # Errors
     ///
-    /// Returns an error if brotli encountered an error while processing the stream.
+    /// Returns an error if `input` is not exactly one complete brotli stream.
+    /// If the decompressor cannot be prepared for the next message, the
+    /// result of this message is still returned and the error is reported by
+    /// the next call instead.
+    pub fn decompress_msg(&mut self, mut input: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut output = Vec::new();
+        let result = loop {
+            let status = match self.decompress(&mut input, &mut &mut [][..]) {
+                Ok(status) => status,
+                Err(e) => break Err(e),
+            };
+            while let Some(buf) = self.take_output(None) {
+                output.extend_from_slice(buf);
+            }
+            match status {
+                DeStatus::Finished if input.is_empty() => break Ok(output),
+                DeStatus::Finished | DeStatus::NeedInput => {
+                    break Err(Error::new(
+                        ErrorKind::Decode(DecodeError::Format),
+                        "decompressing",
+                    ))
+                }
+                DeStatus::NeedOutput => {}
+            }
+        };
+        self.end_msg();
+        result
+    }
+
+    // Replaces the state after a message. An error is left for the next use
+    // of the decompressor to report, rather than hiding the message's result.
+    fn end_msg(&mut self) {
+        if self.reset().is_err() {
+            self.stale = true;
+        }
+    }
+
+    /// Registers `callback` to receive the contents of each metadata block in
+    /// the stream, replacing any earlier callback.
+    ///
+    /// Metadata blocks carry opaque out-of-band data, written for example
+    /// with `Compress::emit_metadata`, that is otherwise skipped. The callback
+    /// is called from within `decompress` once a whole block has been read,
+    /// and a panic in it is resumed when libbrotli returns.
     ///
     /// # Examples
     ///
     /// ```
-    /// use brotli2::raw::{Error, Compress, CompressOp, CoStatus, decompress_buf};
-    ///
-    /// // An example of compressing `input` into the destination vector
-    /// // `output`, expanding as necessary
-    /// fn compress_vec(mut input: &[u8],
-    ///                 output: &mut Vec<u8>) -> Result<(), Error> {
-    ///     let mut compress = Compress::new();
-    ///     let nilbuf = &mut &mut [][..];
-    ///     loop {
-    ///         // Compressing to a buffer is easiest when the slice is already
-    ///         // available - since we need to grow, extend from compressor
-    ///         // internal buffer.
-    ///         let status = compress.compress(CompressOp::Finish, &mut input, nilbuf)?;
-    ///         while let Some(buf) = compress.take_output(None) {
-    ///             output.extend_from_slice(buf)
-    ///         }
-    ///         match status {
-    ///             CoStatus::Finished => break,
-    ///             CoStatus::Unfinished => (),
-    ///         }
-    ///     }
-    ///     Ok(())
-    /// }
+    /// use std::sync::{Arc, Mutex};
+    /// use brotli2::raw::{Compress, Decompress};
     ///
-    /// fn assert_roundtrip(data: &[u8]) {
-    ///     let mut compressed = Vec::new();
-    ///     compress_vec(data, &mut compressed).unwrap();
-    ///
-    ///     let mut decompressed = [0; 2048];
-    ///     let mut decompressed = &mut decompressed[..];
-    ///     decompress_buf(&compressed, &mut decompressed).unwrap();
-    ///     assert_eq!(decompressed, data);
-    /// }
+    /// let mut compress = Compress::new();
+    /// let mut data = Vec::new();
+    /// compress.emit_metadata(b"built by ci", &mut data).unwrap();
+    /// data.extend(compress.compress_msg(b"payload").unwrap());
     ///
-    /// assert_roundtrip(b"Hello, World!");
-    /// assert_roundtrip(b"");
-    /// assert_roundtrip(&[6; 1024]);
+    /// let blocks = Arc::new(Mutex::new(Vec::new()));
+    /// let sink = blocks.clone();
+    /// let mut decompress = Decompress::new();
+    /// decompress.set_metadata_callback(move |block| sink.lock().unwrap().push(block.to_vec()));
+    /// assert_eq!(decompress.decompress_msg(&data).unwrap(), b"payload");
+    /// assert_eq!(*blocks.lock().unwrap(), [b"built by ci".to_vec()]);
     /// ```
-    pub fn compress(
-        &mut self,
-        op: CompressOp,
-        input: &mut &[u8],
-        output: &mut &mut [u8],
-    ) -> Result<CoStatus, Error> {
-        let mut available_in = input.len();
-        let mut next_in = input.as_ptr();
-        let mut available_out = output.len();
-        let mut next_out = output.as_mut_ptr();
-        let r = unsafe {
-            brotli_sys::BrotliEncoderCompressStream(
-                self.state,
-                op as brotli_sys::BrotliEncoderOperation,
-                &mut available_in,
-                &mut next_in,
-                &mut available_out,
-                &mut next_out,
-                ptr::null_mut(),
-            )
-        };
-        *input = &input[input.len() - available_in..];
-        let out_len = output.len();
-        *output = &mut mem::replace(output, &mut [])[out_len - available_out..];
-        if r == 0 {
-            return Err(Error(()));
-        }
-        Ok(if op == CompressOp::Process {
-            CoStatus::Finished
-        } else if available_in != 0 {
-            CoStatus::Unfinished
-        } else if unsafe { brotli_sys::BrotliEncoderHasMoreOutput(self.state) } == 1 {
-            CoStatus::Unfinished
-        } else if op == CompressOp::Finish
-            && unsafe { brotli_sys::BrotliEncoderIsFinished(self.state) } == 0
-        {
-            CoStatus::Unfinished
-        } else {
-            CoStatus::Finished
-        })
+    pub fn set_metadata_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(&[u8]) + Send + 'static,
+    {
+        self.set_metadata_policy(MetadataPolicy::Callback(Box::new(callback)));
     }
 
-    /// Retrieve a slice of the internal compressor buffer up to `size_limit` in length
-    /// (unlimited length if `None`), consuming it. As the internal buffer may not be
-    /// contiguous, consecutive calls may return more output until this function returns
-    /// `None`.
-    pub fn take_output(&mut self, size_limit: Option<usize>) -> Option<&[u8]> {
-        if let Some(0) = size_limit {
-            return None;
-        }
-        let mut size_limit = size_limit.unwrap_or(0); // 0 now means unlimited
-        unsafe {
-            let ptr = brotli_sys::BrotliEncoderTakeOutput(self.state, &mut size_limit);
-            if size_limit == 0 {
-                // ptr may or may not be null
-                None
-            } else {
-                assert!(!ptr.is_null());
-                Some(slice::from_raw_parts(ptr, size_limit))
+    /// Sets how metadata blocks in the stream are handled, replacing any
+    /// earlier policy or callback.
+    ///
+    /// With `MetadataPolicy::Reject`, decompression fails at the first
+    /// metadata block, and keeps failing until the decompressor is reset or,
+    /// with `decompress_msg`, until the next message.
+    pub fn set_metadata_policy(&mut self, policy: MetadataPolicy) {
+        let callback = match policy {
+            MetadataPolicy::Skip => {
+                unsafe {
+                    brotli_sys::BrotliDecoderSetMetadataCallbacks(
+                        self.state,
+                        None,
+                        None,
+                        ptr::null_mut(),
+                    );
+                }
+                self.metadata = None;
+                return;
             }
-        }
+            MetadataPolicy::Callback(callback) => Some(callback),
+            MetadataPolicy::Reject => None,
+        };
+        self.install_metadata(Box::new(Metadata {
+            callback,
+            block: Vec::new(),
+            remaining: 0,
+            rejected: false,
+            panic: None,
+        }));
     }
 
-    /// Configure the parameters of this compression session.
-    ///
-    /// Note that this is likely to only successful if called before compression
-    /// starts.
-    pub fn set_params(&mut self, params: &CompressParams) {
+    fn install_metadata(&mut self, mut metadata: Box<Metadata>) {
         unsafe {
-            brotli_sys::BrotliEncoderSetParameter(
-                self.state,
-                brotli_sys::BROTLI_PARAM_MODE,
-                params.mode,
-            );
-            brotli_sys::BrotliEncoderSetParameter(
-                self.state,
-                brotli_sys::BROTLI_PARAM_QUALITY,
-                params.quality,
-            );
-            brotli_sys::BrotliEncoderSetParameter(
-                self.state,
-                brotli_sys::BROTLI_PARAM_LGWIN,
-                params.lgwin,
-            );
-            brotli_sys::BrotliEncoderSetParameter(
+            brotli_sys::BrotliDecoderSetMetadataCallbacks(
                 self.state,
-                brotli_sys::BROTLI_PARAM_LGBLOCK,
-                params.lgblock,
+                Some(metadata_start),
+                Some(metadata_chunk),
+                &mut *metadata as *mut Metadata as *mut c_void,
             );
-            // TODO: add these two
-            // brotli_sys::BrotliEncoderSetParameter(self.state,
-            //                                       brotli_sys::BROTLI_PARAM_DISABLE_LITERAL_CONTEXT_MODELING,
-            //                                       params.lgblock);
-            // brotli_sys::BrotliEncoderSetParameter(self.state,
-            //                                       brotli_sys::BROTLI_PARAM_SIZE_HINT,
-            //                                       params.lgblock);
         }
+        self.metadata = Some(metadata);
     }
-}
 
-impl Drop for Compress {
-    fn drop(&mut self) {
-        unsafe {
-            brotli_sys::BrotliEncoderDestroyInstance(self.state);
+    fn metadata_rejected(&self) -> bool {
+        self.metadata.as_ref().map_or(false, |m| m.rejected)
+    }
+
+    /// Configure the parameters of this decompression session.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if decompression has already started, or an error
+    /// of kind `ErrorKind::Alloc` if the decoder already holds more memory
+    /// than the memory limit of `params`.
+    pub fn set_params(&mut self, params: &DecompressParams) -> Result<(), Error> {
+        self.prepare()?;
+        self.set_large_window(params.large_window)?;
+        self.set_disable_ring_buffer_reallocation(params.disable_ring_buffer_reallocation)?;
+        self.set_max_memory(params.max_memory)
+    }
+
+    /// Bounds the memory the decoder may allocate for its state and ring
+    /// buffer to `limit` bytes, or lifts the bound if `None`.
+    ///
+    /// See `DecompressParams::max_memory` for details. Unlike the other
+    /// parameters, this can be changed at any time, and applies to the
+    /// allocations made from then on.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `ErrorKind::Alloc`, leaving the previous
+    /// bound in place, if the decoder already holds more memory than `limit`.
+    pub fn set_max_memory(&mut self, limit: Option<usize>) -> Result<(), Error> {
+        if !self.allocator.set_limit(limit.unwrap_or(usize::MAX)) {
+            return Err(Error::config(ErrorKind::Alloc, "configuring the decoder"));
         }
+        self.params.max_memory = limit;
+        Ok(())
     }
-}
 
-/// Compresses the data in `input` into `output`.
-///
-/// The `output` buffer is updated to point to the exact slice which contains
-/// the output data.
-///
-/// If successful, the amount of compressed bytes are returned (the size of the
-/// `output` slice), or an error is returned. The output buffer being too small
-/// is considered to be an error.
-pub fn compress_buf(
-    params: &CompressParams,
-    input: &[u8],
-    output: &mut &mut [u8],
-) -> Result<usize, Error> {
-    let mut size = output.len();
-    let r = unsafe {
-        brotli_sys::BrotliEncoderCompress(
-            params.quality as c_int,
-            params.lgwin as c_int,
-            params.mode as brotli_sys::BrotliEncoderMode,
-            input.len(),
-            input.as_ptr(),
-            &mut size,
-            output.as_mut_ptr(),
+    /// Enables or disables support for the "large window" extension.
+    ///
+    /// Streams produced by encoders configured with an `lgwin` above 24 can
+    /// only be decoded when this is enabled.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if decompression has already started.
+    pub fn set_large_window(&mut self, large_window: bool) -> Result<(), Error> {
+        self.set_param(
+            brotli_sys::BROTLI_DECODER_PARAM_LARGE_WINDOW,
+            large_window as u32,
         )
-    };
-    *output = &mut mem::replace(output, &mut [])[..size];
-    if r == 0 {
-        Err(Error(()))
-    } else {
-        Ok(size)
+        .map_err(|_| Error::config(ErrorKind::AlreadyStarted, "configuring the decoder"))?;
+        self.params.large_window = large_window;
+        Ok(())
     }
-}
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        error::Error::description(self).fmt(f)
+    /// Enables or disables reallocation of the decoder's ring buffer.
+    ///
+    /// See `DecompressParams::disable_ring_buffer_reallocation` for details.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if decompression has already started.
+    pub fn set_disable_ring_buffer_reallocation(&mut self, disable: bool) -> Result<(), Error> {
+        self.set_param(
+            brotli_sys::BROTLI_DECODER_PARAM_DISABLE_RING_BUFFER_REALLOCATION,
+            disable as u32,
+        )
+        .map_err(|_| Error::config(ErrorKind::AlreadyStarted, "configuring the decoder"))?;
+        self.params.disable_ring_buffer_reallocation = disable;
+        Ok(())
     }
-}
 
-impl error::Error for Error {
-    fn description(&self) -> &str {
-        "brotli error"
+    /// Sets a decoder parameter directly by its libbrotli identifier.
+    ///
+    /// This is a low-level escape hatch for parameters that
+    /// `DecompressParams` does not cover yet. `param` is one of the
+    /// `BROTLI_DECODER_PARAM_*` values of libbrotli's `BrotliDecoderParameter`
+    /// (also available as constants in `brotli-sys`), and `value` is passed
+    /// through unchanged. No validation is done beyond what libbrotli itself
+    /// performs.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if libbrotli rejects the parameter or its value, for
+    /// example because the parameter is unknown or decompression has already
+    /// started.
+    pub fn set_parameter_raw(&mut self, param: u32, value: u32) -> Result<(), Error> {
+        self.set_param(param as brotli_sys::BrotliDecoderParameter, value)
     }
-}
 
-impl From<Error> for io::Error {
-    fn from(_err: Error) -> io::Error {
-        io::Error::new(io::ErrorKind::Other, "brotli error")
+    fn set_param(
+        &mut self,
+        param: brotli_sys::BrotliDecoderParameter,
+        value: u32,
+    ) -> Result<(), Error> {
+        let r = unsafe { brotli_sys::BrotliDecoderSetParameter(self.state, param, value) };
+        if r == 0 {
+            return Err(Error::config(ErrorKind::Other, "configuring the decoder"));
+        }
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Attaches a custom prefix dictionary to this decompressor.
+    ///
+    /// The dictionary must be the same one that was given to
+    /// `Compress::set_dictionary` when the stream was compressed. This must
+    /// be called before any data is decompressed, and may be called several
+    /// times to attach multiple dictionaries.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if decompression has already started or if too many
+    /// dictionaries have been attached.
+    pub fn set_dictionary(&mut self, dict: &[u8]) -> Result<(), Error> {
+        self.attach_dictionary(&Dictionary::new(dict)?)
+    }
+
+    /// Attaches a prepared dictionary to this decompressor.
+    ///
+    /// This is the same as `set_dictionary`, except that the dictionary may be
+    /// shared with any number of other streams.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if decompression has already started or if too many
+    /// dictionaries have been attached.
+    pub fn attach_dictionary(&mut self, dict: &Dictionary) -> Result<(), Error> {
+        self.prepare()?;
+        self.attach(dict)?;
+        // The decoder refers to the dictionary bytes, so keep them alive for
+        // as long as the decoder itself.
+        self.dicts.push(dict.clone());
+        Ok(())
+    }
+
+    fn attach(&mut self, dict: &Dictionary) -> Result<(), Error> {
+        let data = dict.as_bytes();
+        let r = unsafe {
+            brotli_sys::BrotliDecoderAttachDictionary(
+                self.state,
+                brotli_sys::BROTLI_SHARED_DICTIONARY_RAW,
+                data.len(),
+                data.as_ptr(),
+            )
+        };
+        if r == 0 {
+            return Err(Error::config(ErrorKind::Other, "attaching a dictionary"));
+        }
+        Ok(())
+    }
+
+    /// Decompress some input data and write it to a buffer of output data.
+    ///
+    /// This function will decompress the data in `input` and place the output
+    /// in `output`, returning the result. Possible statuses that can be
+    /// returned are that the stream is finished, more input is needed, or more
+    /// output space is needed.
+    ///
+    /// The `input` slice is updated to point to the remaining data that was not
+    /// consumed, and the `output` slice is updated to point to the portion of
+    /// the output slice that still needs to be filled in.
+    ///
+    /// # Errors
+    ///
+    /// If the input stream is not a valid brotli stream, then an error is
+    /// returned.
+    pub fn decompress(
+        &mut self,
+        input: &mut &[u8],
+        output: &mut &mut [u8],
+    ) -> Result<DeStatus, Error> {
+        let (written, status) =
+            unsafe { self.decompress_into(input, output.as_mut_ptr(), output.len()) };
+        *output = &mut mem::take(output)[written..];
+        status
+    }
+
+    /// Same as `decompress`, but writes to a possibly uninitialized output
+    /// buffer, which saves zeroing it first.
+    ///
+    /// Returns the status along with the initialized part of `output` that
+    /// data was written to, which always starts at the beginning of `output`.
+    ///
+    /// # Errors
+    ///
+    /// If the input stream is not a valid brotli stream, then an error is
+    /// returned.
+    pub fn decompress_uninit<'a>(
+        &mut self,
+        input: &mut &[u8],
+        output: &'a mut [MaybeUninit<u8>],
+    ) -> Result<(DeStatus, &'a mut [u8]), Error> {
+        let out = output.as_mut_ptr() as *mut u8;
+        unsafe {
+            let (written, status) = self.decompress_into(input, out, output.len());
+            Ok((status?, slice::from_raw_parts_mut(out, written)))
+        }
+    }
+
+    // Decompresses into the `len` bytes at `out`, returning how many of them
+    // were written.
+    unsafe fn decompress_into(
+        &mut self,
+        input: &mut &[u8],
+        out: *mut u8,
+        len: usize,
+    ) -> (usize, Result<DeStatus, Error>) {
+        if let Err(e) = self.prepare() {
+            return (0, Err(e));
+        }
+        if self.metadata_rejected() {
+            return (0, Err(Error::new(ErrorKind::Metadata, "decompressing")));
+        }
+        let copied = self.peeked.copy_to(out, len);
+        self.peeked.stash();
+        let mut available_in = input.len();
+        let mut next_in = input.as_ptr();
+        let mut available_out = len - copied;
+        let mut next_out = out.add(copied);
+        let r = brotli_sys::BrotliDecoderDecompressStream(
+            self.state,
+            &mut available_in,
+            &mut next_in,
+            &mut available_out,
+            &mut next_out,
+            ptr::null_mut(),
+        );
+        if let Some(panic) = self.metadata.as_mut().and_then(|m| m.panic.take()) {
+            panic::resume_unwind(panic);
+        }
+        let written = len - available_out;
+        self.total_in += (input.len() - available_in) as u64;
+        self.total_out += written as u64;
+        *input = &input[input.len() - available_in..];
+        if self.metadata_rejected() {
+            return (
+                written,
+                Err(Error::new(ErrorKind::Metadata, "decompressing")),
+            );
+        }
+        let status = match self.rc(r) {
+            Ok(DeStatus::Finished) if !self.peeked.is_empty() => Ok(DeStatus::NeedOutput),
+            status => status,
+        };
+        (written, status)
+    }
+
+    /// Retrieve a slice of the internal decompressor buffer up to `size_limit` in length
+    /// (unlimited length if `None`), consuming it. As the internal buffer may not be
+    /// contiguous, consecutive calls may return more output until this function returns
+    /// `None`.
+    pub fn take_output(&mut self, size_limit: Option<usize>) -> Option<&[u8]> {
+        if let Some(0) = size_limit {
+            return None;
+        }
+        let out = if self.peeked.is_empty() {
+            unsafe { Decompress::take_library_output(self.state, size_limit)? }
+        } else {
+            self.peeked.take(size_limit)
+        };
+        self.total_out += out.len() as u64;
+        Some(out)
+    }
+
+    /// Same as `take_output`, but copies the output into a `Bytes`.
+    ///
+    /// The `Bytes` are split off a buffer owned by this decompressor, whose
+    /// memory is reused for later output once all `Bytes` referring to it are
+    /// dropped. Requires the `bytes` feature.
+    #[cfg(feature = "bytes")]
+    pub fn take_output_bytes(&mut self, size_limit: Option<usize>) -> Option<Bytes> {
+        let mut bytes = mem::take(&mut self.bytes);
+        if let Some(buf) = self.take_output(size_limit) {
+            bytes.extend_from_slice(buf);
+        }
+        let out = bytes.split().freeze();
+        self.bytes = bytes;
+        if out.is_empty() {
+            None
+        } else {
+            Some(out)
+        }
+    }
+
+    /// Returns an iterator that takes all output that is ready, one chunk at a
+    /// time, as `take_output` would.
+    ///
+    /// See `OutputChunks::take_chunk` for taking chunks without copying them.
+    pub fn output_chunks(&mut self) -> OutputChunks<'_> {
+        OutputChunks {
+            stream: Stream::Decompress(self),
+        }
+    }
+
+    /// Returns all output that is ready to be taken, without consuming it.
+    ///
+    /// The output is moved out of libbrotli into a buffer owned by this
+    /// decompressor, from which later calls to `take_output` and `decompress`
+    /// return it before any new output.
+    pub fn peek_output(&mut self) -> &[u8] {
+        self.peeked.stash();
+        while let Some(buf) = unsafe { Decompress::take_library_output(self.state, None) } {
+            self.peeked.extend(buf);
+        }
+        self.peeked.as_slice()
+    }
+
+    /// Returns the number of bytes of output that are ready to be taken.
+    ///
+    /// See `peek_output` for details.
+    pub fn output_len(&mut self) -> usize {
+        self.peek_output().len()
+    }
+
+    // Returns output that is ready to be taken, if any, without consuming it.
+    // Unlike `peek_output`, this serves it straight from libbrotli's ring
+    // buffer, one contiguous chunk at a time, and only copies it aside if the
+    // decoder is called into before `consume_output` has used it up.
+    pub(crate) fn fill_output(&mut self) -> &[u8] {
+        if self.peeked.is_empty() {
+            if let Some(buf) = unsafe { Decompress::take_library_output(self.state, None) } {
+                self.peeked.lend(buf);
+            }
+        }
+        self.peeked.as_slice()
+    }
+
+    // Consumes `amt` bytes of the output returned by `fill_output`.
+    pub(crate) fn consume_output(&mut self, amt: usize) {
+        let n = self.peeked.take(Some(amt)).len();
+        self.total_out += n as u64;
+    }
+
+    // The returned slice is only valid until the next call into the decoder.
+    unsafe fn take_library_output<'a>(
+        state: *mut brotli_sys::BrotliDecoderState,
+        size_limit: Option<usize>,
+    ) -> Option<&'a [u8]> {
+        let mut size_limit = size_limit.unwrap_or(0); // 0 now means unlimited
+        let ptr = brotli_sys::BrotliDecoderTakeOutput(state, &mut size_limit);
+        if size_limit == 0 {
+            // ptr may or may not be null
+            None
+        } else {
+            assert!(!ptr.is_null());
+            Some(slice::from_raw_parts(ptr, size_limit))
+        }
+    }
+
+    /// Returns whether the end of the brotli stream has been decoded and all
+    /// of its output has been written out or taken.
+    pub fn is_finished(&self) -> bool {
+        self.peeked.is_empty() && unsafe { brotli_sys::BrotliDecoderIsFinished(self.state) != 0 }
+    }
+
+    /// Returns whether this decompressor has started decoding, that is
+    /// whether it has consumed any input.
+    ///
+    /// Parameters and dictionaries can only be set before this is the case.
+    pub fn is_used(&self) -> bool {
+        unsafe { brotli_sys::BrotliDecoderIsUsed(self.state) != 0 }
+    }
+
+    /// Returns the total number of bytes of input consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.total_in
+    }
+
+    /// Returns the total number of bytes of output produced so far, whether
+    /// written to output buffers or returned by `take_output`.
+    pub fn total_out(&self) -> u64 {
+        self.total_out
+    }
+
+    /// Splits this decompressor into a half that accepts compressed input and
+    /// a half that yields decompressed output, which can be owned by
+    /// different parts of a program, or different threads.
+    ///
+    /// Input fed to the `DecompressFeeder` is buffered until the
+    /// `DecompressDrainer` decompresses it, however much of it there is. Use
+    /// `split_bounded` to make the feeder wait for the drainer instead.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::thread;
+    /// use brotli2::raw::{compress_vec, DeStatus, Decompress};
+    /// use brotli2::CompressParams;
+    ///
+    /// let data = compress_vec(&CompressParams::new(), b"hello world").unwrap();
+    /// let (mut feeder, mut drainer) = Decompress::new().split();
+    /// let t = thread::spawn(move || {
+    ///     for chunk in data.chunks(4) {
+    ///         feeder.feed(chunk);
+    ///     }
+    /// });
+    ///
+    /// let mut buf = [0; 64];
+    /// let mut out = &mut buf[..];
+    /// while drainer.drain(&mut out).unwrap() != DeStatus::Finished {
+    ///     drainer.wait_for_input();
+    /// }
+    /// let n = 64 - out.len();
+    /// assert_eq!(&buf[..n], b"hello world");
+    /// t.join().unwrap();
+    /// ```
+    pub fn split(self) -> (DecompressFeeder, DecompressDrainer) {
+        self.split_bounded(usize::MAX)
+    }
+
+    /// Same as `split`, except that `DecompressFeeder::feed` blocks while
+    /// `capacity` bytes or more of input are buffered, until the drainer
+    /// has consumed some of them.
+    ///
+    /// A single call to `feed` may still buffer more than `capacity` bytes,
+    /// so the buffer is bounded by `capacity` plus the largest chunk fed.
+    pub fn split_bounded(self, capacity: usize) -> (DecompressFeeder, DecompressDrainer) {
+        let shared = Arc::new(Shared {
+            split: Mutex::new(Split {
+                data: self,
+                input: Vec::new(),
+                pos: 0,
+                capacity,
+                closed: false,
+                abandoned: false,
+            }),
+            fed: Condvar::new(),
+            drained: Condvar::new(),
+        });
+        let feeder = DecompressFeeder {
+            shared: shared.clone(),
+        };
+        (feeder, DecompressDrainer { shared })
+    }
+
+    fn rc(&self, rc: brotli_sys::BrotliDecoderResult) -> Result<DeStatus, Error> {
+        match rc {
+            brotli_sys::BROTLI_DECODER_RESULT_ERROR => {
+                let code = unsafe { brotli_sys::BrotliDecoderGetErrorCode(self.state) };
+                Err(Error::decode(code, "decompressing"))
+            }
+            brotli_sys::BROTLI_DECODER_RESULT_SUCCESS => Ok(DeStatus::Finished),
+            brotli_sys::BROTLI_DECODER_RESULT_NEEDS_MORE_INPUT => Ok(DeStatus::NeedInput),
+            brotli_sys::BROTLI_DECODER_RESULT_NEEDS_MORE_OUTPUT => Ok(DeStatus::NeedOutput),
+            n => Err(Error::new(
+                ErrorKind::UnknownResult(n as i32),
+                "decompressing",
+            )),
+        }
+    }
+}
+
+impl Default for Decompress {
+    fn default() -> Decompress {
+        Decompress::new()
+    }
+}
+
+impl fmt::Debug for Decompress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Decompress")
+            .field("params", &self.params)
+            .field("total_in", &self.total_in)
+            .field("total_out", &self.total_out)
+            .field("finished", &self.is_finished())
+            .finish()
+    }
+}
+
+impl Drop for Decompress {
+    fn drop(&mut self) {
+        unsafe {
+            brotli_sys::BrotliDecoderDestroyInstance(self.state);
+        }
+    }
+}
+
+/// The input half of a decompressor, created by `Decompress::split`.
+///
+/// Dropping it marks the end of the input, after which the drainer reports a
+/// stream that has not finished as truncated.
+pub struct DecompressFeeder {
+    shared: Arc<Shared>,
+}
+
+/// The output half of a decompressor, created by `Decompress::split`.
+pub struct DecompressDrainer {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    split: Mutex<Split>,
+    // Signalled when input is fed or the feeder is dropped.
+    fed: Condvar,
+    // Signalled when input is consumed or the drainer is dropped.
+    drained: Condvar,
+}
+
+struct Split {
+    data: Decompress,
+    input: Vec<u8>,
+    pos: usize,
+    capacity: usize,
+    // Set once the feeder is dropped.
+    closed: bool,
+    // Set once the drainer is dropped.
+    abandoned: bool,
+}
+
+fn lock(shared: &Shared) -> MutexGuard<'_, Split> {
+    // A panic while the lock is held leaves nothing half-updated that matters
+    // here, as the decoder refuses to continue after errors itself.
+    shared.split.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl DecompressFeeder {
+    /// Buffers `input` to be decompressed by the drainer.
+    ///
+    /// For a decompressor split with `split_bounded`, this first waits until
+    /// fewer bytes than the capacity are buffered. If the drainer has been
+    /// dropped the input is discarded.
+    pub fn feed(&mut self, input: &[u8]) {
+        let mut split = lock(&self.shared);
+        while split.input.len() - split.pos >= split.capacity && !split.abandoned {
+            split = self
+                .shared
+                .drained
+                .wait(split)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        if split.abandoned {
+            return;
+        }
+        let pos = mem::replace(&mut split.pos, 0);
+        split.input.drain(..pos);
+        split.input.extend_from_slice(input);
+        self.shared.fed.notify_one();
+    }
+
+    /// Returns the number of bytes fed but not yet consumed by the decoder.
+    pub fn buffered(&self) -> usize {
+        let split = lock(&self.shared);
+        split.input.len() - split.pos
+    }
+}
+
+impl fmt::Debug for DecompressFeeder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DecompressFeeder")
+            .field("buffered", &self.buffered())
+            .finish()
+    }
+}
+
+impl Drop for DecompressFeeder {
+    fn drop(&mut self) {
+        lock(&self.shared).closed = true;
+        self.shared.fed.notify_one();
+    }
+}
+
+impl DecompressDrainer {
+    /// Decompresses buffered input into `output`, like
+    /// `Decompress::decompress`.
+    ///
+    /// `DeStatus::NeedInput` means that all input fed so far has been
+    /// consumed and more needs to be fed to continue.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the input is invalid, or, once the feeder has been
+    /// dropped, an error of kind `ErrorKind::Decode(DecodeError::Format)` if
+    /// the input ends before the end of the brotli stream.
+    pub fn drain(&mut self, output: &mut &mut [u8]) -> Result<DeStatus, Error> {
+        let mut split = lock(&self.shared);
+        let split = &mut *split;
+        let mut input = &split.input[split.pos..];
+        let len = input.len();
+        let status = split.data.decompress(&mut input, output);
+        if input.len() != len {
+            split.pos += len - input.len();
+            self.shared.drained.notify_one();
+        }
+        match status {
+            Ok(DeStatus::NeedInput) if split.closed => Err(Error::new(
+                ErrorKind::Decode(DecodeError::Format),
+                "decoding truncated input",
+            )),
+            status => status,
+        }
+    }
+
+    /// Blocks until there is buffered input that has not been consumed, or
+    /// until the feeder has been dropped.
+    ///
+    /// This is meant to be called when `drain` returns `DeStatus::NeedInput`.
+    pub fn wait_for_input(&self) {
+        let mut split = lock(&self.shared);
+        while split.input.len() == split.pos && !split.closed {
+            split = self
+                .shared
+                .fed
+                .wait(split)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Returns whether the end of the brotli stream has been decoded and all
+    /// of its output has been drained.
+    pub fn is_finished(&self) -> bool {
+        lock(&self.shared).data.is_finished()
+    }
+
+    /// Returns the total number of bytes of input consumed so far.
+    pub fn total_in(&self) -> u64 {
+        lock(&self.shared).data.total_in()
+    }
+
+    /// Returns the total number of bytes of output produced so far.
+    pub fn total_out(&self) -> u64 {
+        lock(&self.shared).data.total_out()
+    }
+}
+
+impl Drop for DecompressDrainer {
+    fn drop(&mut self) {
+        lock(&self.shared).abandoned = true;
+        self.shared.drained.notify_one();
+    }
+}
+
+impl fmt::Debug for DecompressDrainer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DecompressDrainer")
+            .field("data", &lock(&self.shared).data)
+            .finish()
+    }
+}
+
+/// Decompress data in one go in memory.
+///
+/// Decompresses the data in `input` into the `output` buffer. The `output`
+/// buffer is updated to point to the actual output slice if successful, or
+/// an error is returned. The output buffer being too small is considered
+/// to be an error.
+pub fn decompress_buf(input: &[u8], output: &mut &mut [u8]) -> Result<usize, Error> {
+    let mut size = output.len();
+    let r = unsafe {
+        brotli_sys::BrotliDecoderDecompress(
+            input.len(),
+            input.as_ptr(),
+            &mut size,
+            output.as_mut_ptr(),
+        )
+    };
+    *output = &mut mem::take(output)[..size];
+    if r == 0 {
+        Err(Error::new(ErrorKind::Other, "decompressing"))
+    } else {
+        Ok(size)
+    }
+}
+
+/// Decompress data in one go into a newly allocated vector.
+///
+/// Unlike `decompress_buf`, this does not require the size of the output to
+/// be known in advance.
+///
+/// # Errors
+///
+/// Returns an error if `input` is not exactly one complete brotli stream.
+pub fn decompress_vec(input: &[u8]) -> Result<Vec<u8>, Error> {
+    decompress_vec_impl(input, usize::MAX)
+}
+
+/// Decompress data in one go into a newly allocated vector, producing at most
+/// `max_output` bytes.
+///
+/// This is the same as `decompress_vec`, but is safe to use on untrusted
+/// input: a small input cannot make this allocate more than `max_output`
+/// bytes of output.
+///
+/// # Errors
+///
+/// Returns an error of kind `ErrorKind::LimitExceeded` if the decompressed
+/// data would be larger than `max_output` bytes, or an error if `input` is not
+/// exactly one complete brotli stream.
+pub fn decompress_vec_limited(input: &[u8], max_output: usize) -> Result<Vec<u8>, Error> {
+    decompress_vec_impl(input, max_output)
+}
+
+/// Decompress data in one go, passing each chunk of output to `sink` as it is
+/// produced.
+///
+/// # Errors
+///
+/// Returns an error if `input` is not exactly one complete brotli stream, in
+/// which case `sink` may already have received some of the output.
+pub fn decompress_cb<F>(mut input: &[u8], mut sink: F) -> Result<(), Error>
+where
+    F: FnMut(&[u8]),
+{
+    let mut decompress = Decompress::try_new()?;
+    loop {
+        let status = decompress.decompress(&mut input, &mut &mut [][..])?;
+        while let Some(buf) = decompress.take_output(None) {
+            sink(buf);
+        }
+        match status {
+            DeStatus::Finished if input.is_empty() => return Ok(()),
+            DeStatus::Finished | DeStatus::NeedInput => {
+                return Err(Error::new(
+                    ErrorKind::Decode(DecodeError::Format),
+                    "decompressing",
+                ))
+            }
+            DeStatus::NeedOutput => {}
+        }
+    }
+}
+
+fn decompress_vec_impl(mut input: &[u8], max_output: usize) -> Result<Vec<u8>, Error> {
+    let mut decompress = Decompress::try_new()?;
+    let mut output = Vec::new();
+    loop {
+        let status = decompress.decompress(&mut input, &mut &mut [][..])?;
+        // Ask for one byte more than allowed to detect going over the limit.
+        let limit = (max_output - output.len()).saturating_add(1);
+        while let Some(buf) = decompress.take_output(Some(limit)) {
+            if buf.len() > max_output - output.len() {
+                return Err(Error::new(ErrorKind::LimitExceeded, "decompressing"));
+            }
+            output.extend_from_slice(buf);
+        }
+        match status {
+            DeStatus::Finished if input.is_empty() => return Ok(output),
+            DeStatus::Finished | DeStatus::NeedInput => {
+                return Err(Error::new(
+                    ErrorKind::Decode(DecodeError::Format),
+                    "decompressing",
+                ))
+            }
+            DeStatus::NeedOutput => {}
+        }
+    }
+}
+
+/// Decompress data in one go in memory, using a custom dictionary.
+///
+/// This is the same as `decompress_buf`, except that the stream in `input`
+/// must have been compressed with the dictionary `dict`, for example with
+/// `compress_with_dict_buf`.
+pub fn decompress_with_dict_buf(
+    dict: &Dictionary,
+    mut input: &[u8],
+    output: &mut &mut [u8],
+) -> Result<usize, Error> {
+    let mut decompress = Decompress::new();
+    decompress.attach_dictionary(dict)?;
+    let len = output.len();
+    let (status, remaining) = {
+        let mut out = &mut output[..];
+        let status = decompress.decompress(&mut input, &mut out)?;
+        (status, out.len())
+    };
+    let size = len - remaining;
+    *output = &mut mem::take(output)[..size];
+    match status {
+        DeStatus::Finished => Ok(size),
+        DeStatus::NeedInput | DeStatus::NeedOutput => {
+            Err(Error::new(ErrorKind::Other, "decompressing"))
+        }
+    }
+}
+
+impl Compress {
+    /// Creates a new compressor ready to encode data into brotli
+    ///
+    /// # Panics
+    ///
+    /// Panics if the encoder state cannot be allocated. See `try_new` for a
+    /// fallible alternative.
+    pub fn new() -> Compress {
+        Compress::try_new().expect("failed to allocate brotli encoder")
+    }
+
+    /// Creates a new compressor ready to encode data into brotli
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `ErrorKind::Alloc` if the encoder state cannot
+    /// be allocated.
+    pub fn try_new() -> Result<Compress, Error> {
+        Compress::create(Recycler::new(None))
+    }
+
+    /// Creates a new compressor whose internal memory is allocated with
+    /// `allocator`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `ErrorKind::Alloc` if the encoder state cannot
+    /// be allocated.
+    pub fn with_allocator<A: Allocator + 'static>(allocator: A) -> Result<Compress, Error> {
+        Compress::create(Recycler::new(Some(Box::new(allocator))))
+    }
+
+    /// Creates a new compressor configured with `params`.
+    ///
+    /// The parameters are applied before any data can flow, so unlike a
+    /// later call to `set_params` this cannot fail because compression has
+    /// started. To also attach dictionaries that way, use `CompressBuilder`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors `Compress::set_params` does for `params`, or an
+    /// error of kind `ErrorKind::Alloc` if the encoder state cannot be
+    /// allocated.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use brotli2::CompressParams;
+    /// use brotli2::raw::Compress;
+    ///
+    /// let compress = Compress::with_params(&CompressParams::best()).unwrap();
+    /// # drop(compress);
+    /// ```
+    pub fn with_params(params: &CompressParams) -> Result<Compress, Error> {
+        let mut compress = Compress::try_new()?;
+        compress.set_params(params)?;
+        Ok(compress)
+    }
+
+    /// Takes ownership of a encoder state created with brotli-sys, so that it
+    /// can be driven through this type.
+    ///
+    /// The state is destroyed when the returned value is dropped. `total_in`
+    /// and `total_out` only count data processed after this call, and any
+    /// parameters or dictionaries already applied to the state are not
+    /// known to it.
+    ///
+    /// # Safety
+    ///
+    /// `state` must be a valid pointer returned by
+    /// `brotli_sys::BrotliEncoderCreateInstance` that is not used or destroyed
+    /// elsewhere afterwards, and anything it references, such as attached
+    /// dictionaries or custom allocators, must outlive the returned value.
+    pub unsafe fn from_raw(state: *mut brotli_sys::BrotliEncoderState) -> Compress {
+        let mut compress = Compress::from_state(state, Recycler::new(None));
+        compress.data_in = None;
+        compress
+    }
+
+    /// Returns the underlying brotli-sys state, to call into libbrotli
+    /// directly.
+    ///
+    /// The pointer stays owned by this value. It is valid until this value
+    /// is dropped or its state is replaced by `reset` or by the message
+    /// methods.
+    /// Output made available through `peek_output` is held outside the
+    /// state, so it is not visible to direct calls.
+    pub fn as_raw(&self) -> *mut brotli_sys::BrotliEncoderState {
+        self.state
+    }
+
+    fn create(allocator: Box<Recycler>) -> Result<Compress, Error> {
+        let state = Compress::create_state(&allocator, "creating the encoder")?;
+        Ok(Compress::from_state(state, allocator))
+    }
+
+    fn from_state(
+        state: *mut brotli_sys::BrotliEncoderState,
+        allocator: Box<Recycler>,
+    ) -> Compress {
+        Compress {
+            state,
+            dicts: Vec::new(),
+            params: None,
+            total_in: 0,
+            total_out: 0,
+            unflushed: false,
+            data_in: Some(0),
+            peeked: Peeked::default(),
+            #[cfg(feature = "bytes")]
+            bytes: BytesMut::new(),
+            stale: false,
+            allocator,
+        }
+    }
+
+    fn create_state(
+        allocator: &Recycler,
+        op: &'static str,
+    ) -> Result<*mut brotli_sys::BrotliEncoderState, Error> {
+        let (alloc, free, opaque) = allocator.hooks();
+        let state = unsafe { brotli_sys::BrotliEncoderCreateInstance(alloc, free, opaque) };
+        if state.is_null() {
+            return Err(Error::new(ErrorKind::Alloc, op));
+        }
+        Ok(state)
+    }
+
+    /// Resets this compressor so that it can encode a new, independent
+    /// stream.
+    ///
+    /// The dictionaries and the parameters set with `set_params` are kept,
+    /// but not raw parameters. libbrotli cannot clear an encoder in place, so
+    /// this replaces the underlying state, but the memory of the old state
+    /// is handed to the new one rather than freed, so that encoding many
+    /// streams one after the other does not allocate for each of them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `ErrorKind::Alloc`, leaving this compressor
+    /// unchanged, if the new state cannot be allocated.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        let state = Compress::create_state(&self.allocator, "resetting the encoder")?;
+        unsafe {
+            brotli_sys::BrotliEncoderDestroyInstance(mem::replace(&mut self.state, state));
+        }
+        self.allocator.next_stream();
+        self.total_in = 0;
+        self.total_out = 0;
+        self.unflushed = false;
+        self.data_in = Some(0);
+        self.peeked = Peeked::default();
+        self.stale = false;
+        let result = self.configure();
+        self.stale = result.is_err();
+        result
+    }
+
+    // Applies the parameters and dictionaries of this compressor to a new
+    // state.
+    fn configure(&mut self) -> Result<(), Error> {
+        if let Some(params) = self.params.clone() {
+            self.apply_params(&params)?;
+        }
+        for dict in self.dicts.clone().iter() {
+            self.attach(dict)?;
+        }
+        Ok(())
+    }
+
+    // Replaces the state if it was left finished by a message, so that it
+    // can be used again.
+    fn prepare(&mut self) -> Result<(), Error> {
+        if self.stale {
+            self.reset()?;
+        }
+        Ok(())
+    }
+
+    // Replaces the state after a message. An error is left for the next use
+    // of the compressor to report, rather than hiding the message's result.
+    fn end_msg(&mut self) {
+        if self.reset().is_err() {
+            self.stale = true;
+        }
+    }
+
+    /// Compresses `input` into a complete, standalone brotli stream, and
+    /// prepares this compressor for the next one.
+    ///
+    /// This is meant for encoding many small, independent messages with a
+    /// single compressor. The compressor must not be in the middle of a
+    /// stream. Afterwards, whether or not encoding succeeded, it is back in
+    /// its initial state as with `reset`, which keeps its memory for the next
+    /// message.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if brotli encountered an error while compressing. If
+    /// the compressor cannot be prepared for the next message, the result of
+    /// this message is still returned and the error is reported by the next
+    /// call instead.
+    pub fn compress_msg(&mut self, mut input: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut output = Vec::new();
+        let result = loop {
+            let status = match self.compress(CompressOp::Finish, &mut input, &mut &mut [][..]) {
+                Ok(status) => status,
+                Err(e) => break Err(e),
+            };
+            while let Some(buf) = self.take_output(None) {
+                output.extend_from_slice(buf);
+            }
+            if status == CoStatus::Finished {
+                break Ok(output);
+            }
+        };
+        self.end_msg();
+        result
+    }
+
+    /// Compresses `input` into a complete, standalone brotli stream written
+    /// to `output`, returning its length, or fails if it does not fit.
+    ///
+    /// This never needs to be retried with more output space: if `output`
+    /// holds at least `max_compressed_size(input.len())` bytes the stream
+    /// always fits. Like `compress_msg`, the compressor must not be in the
+    /// middle of a stream, and is afterwards back in its initial state,
+    /// keeping its dictionaries and parameters.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `ErrorKind::LimitExceeded` if the stream does
+    /// not fit in `output`, in which case the contents of `output` are
+    /// unspecified, or another error if brotli encountered an error while
+    /// compressing.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use brotli2::raw::{decompress_vec, max_compressed_size, Compress, ErrorKind};
+    ///
+    /// let mut compress = Compress::new();
+    /// let mut page = vec![0; max_compressed_size(12)];
+    /// let len = compress.compress_bounded(b"hello, world", &mut page).unwrap();
+    /// assert_eq!(decompress_vec(&page[..len]).unwrap(), b"hello, world");
+    ///
+    /// let err = compress.compress_bounded(&[7; 100], &mut [0; 2]).unwrap_err();
+    /// assert_eq!(err.kind(), ErrorKind::LimitExceeded);
+    /// ```
+    pub fn compress_bounded(
+        &mut self,
+        mut input: &[u8],
+        output: &mut [u8],
+    ) -> Result<usize, Error> {
+        let avail_out = output.len();
+        let mut out = &mut output[..];
+        let result = match self.compress(CompressOp::Finish, &mut input, &mut out) {
+            Ok(CoStatus::Finished) if input.is_empty() => Ok(avail_out - out.len()),
+            Ok(_) => Err(Error::new(ErrorKind::LimitExceeded, "compressing")),
+            Err(e) => Err(e),
+        };
+        self.end_msg();
+        result
+    }
+
+    /// Attaches a custom prefix dictionary to this compressor.
+    ///
+    /// The dictionary is treated as data preceding the input, so the input
+    /// may contain backward references into it. Streams produced this way can
+    /// only be decompressed by a `Decompress` given the same dictionary with
+    /// `Decompress::set_dictionary`. This must be called before any data is
+    /// compressed, and may be called several times to attach multiple
+    /// dictionaries.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if brotli fails to prepare or attach the dictionary.
+    pub fn set_dictionary(&mut self, dict: &[u8]) -> Result<(), Error> {
+        self.attach_dictionary(&Dictionary::new(dict)?)
+    }
+
+    /// Attaches a prepared dictionary to this compressor.
+    ///
+    /// This is the same as `set_dictionary`, except that the dictionary is
+    /// not prepared again and may be shared with any number of other streams.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if brotli fails to attach the dictionary.
+    pub fn attach_dictionary(&mut self, dict: &Dictionary) -> Result<(), Error> {
+        self.prepare()?;
+        self.attach(dict)?;
+        self.dicts.push(dict.clone());
+        Ok(())
+    }
+
+    fn attach(&mut self, dict: &Dictionary) -> Result<(), Error> {
+        let r = unsafe {
+            brotli_sys::BrotliEncoderAttachPreparedDictionary(self.state, dict.inner.prepared)
+        };
+        if r == 0 {
+            return Err(Error::config(ErrorKind::Other, "attaching a dictionary"));
+        }
+        Ok(())
+    }
+
+    // TODO: add the BrotliEncoderOperation variants of
+    // BrotliEncoderCompressStream here
+
+    /// Pass some input data to the compressor and write it to a buffer of
+    /// output data, compressing or otherwise handling it as instructed by
+    /// the specified operation.
+    ///
+    /// This function will handle the data in `input` and place the output
+    /// in `output`, returning the Result. Possible statuses are that the
+    /// operation is complete or incomplete.
+    ///
+    /// The `input` slice is updated to point to the remaining data that was not
+    /// consumed, and the `output` slice is updated to point to the portion of
+    /// the output slice that still needs to be filled in.
+    ///
+    /// If the result of a compress operation is `Unfinished` (which it may be
+    /// for any operation except `Process`), you *must* call the operation again
+    /// with the same operation and input buffer and more space to output to.
+    /// `Process` will never return `Unfinished`, but it is a logic error to end
+    /// a buffer without calling either `Flush` or `Finish` as some output data
+    /// may not have been written.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if brotli encountered an error while processing the stream.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use brotli2::raw::{Error, Compress, CompressOp, CoStatus, decompress_buf};
+    ///
+    /// // An example of compressing `input` into the destination vector
+    /// // `output`, expanding as necessary
+    /// fn compress_vec(mut input: &[u8],
+    ///                 output: &mut Vec<u8>) -> Result<(), Error> {
+    ///     let mut compress = Compress::new();
+    ///     let nilbuf = &mut &mut [][..];
+    ///     loop {
+    ///         // Compressing to a buffer is easiest when the slice is already
+    ///         // available - since we need to grow, extend from compressor
+    ///         // internal buffer.
+    ///         let status = compress.compress(CompressOp::Finish, &mut input, nilbuf)?;
+    ///         while let Some(buf) = compress.take_output(None) {
+    ///             output.extend_from_slice(buf)
+    ///         }
+    ///         match status {
+    ///             CoStatus::Finished => break,
+    ///             CoStatus::Unfinished => (),
+    ///         }
+    ///     }
+    ///     Ok(())
+    /// }
+    ///
+    /// fn assert_roundtrip(data: &[u8]) {
+    ///     let mut compressed = Vec::new();
+    ///     compress_vec(data, &mut compressed).unwrap();
+    ///
+    ///     let mut decompressed = [0; 2048];
+    ///     let mut decompressed = &mut decompressed[..];
+    ///     decompress_buf(&compressed, &mut decompressed).unwrap();
+    ///     assert_eq!(decompressed, data);
+    /// }
+    ///
+    /// assert_roundtrip(b"Hello, World!");
+    /// assert_roundtrip(b"");
+    /// assert_roundtrip(&[6; 1024]);
+    /// ```
+    pub fn compress(
+        &mut self,
+        op: CompressOp,
+        input: &mut &[u8],
+        output: &mut &mut [u8],
+    ) -> Result<CoStatus, Error> {
+        self.compress_detailed(op, input, output)
+            .map(|result| result.status())
+    }
+
+    /// Same as `compress`, but also reports how many bytes of input were
+    /// consumed and how many bytes of output were written by this call.
+    ///
+    /// Bytes retrieved afterwards with `take_output` are not included.
+    pub fn compress_detailed(
+        &mut self,
+        op: CompressOp,
+        input: &mut &[u8],
+        output: &mut &mut [u8],
+    ) -> Result<CompressResult, Error> {
+        let (produced, result) =
+            unsafe { self.compress_into(op, input, output.as_mut_ptr(), output.len()) };
+        *output = &mut mem::take(output)[produced..];
+        result
+    }
+
+    /// Same as `compress_detailed`, but takes the input as a sequence of
+    /// slices, which are compressed as if they were concatenated.
+    ///
+    /// `op` only applies once the last slice is reached, the slices before it
+    /// are compressed with `CompressOp::Process`. If the returned number of
+    /// bytes consumed is less than the total length of `input`, the call must
+    /// be repeated with the remaining input.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if brotli encountered an error while processing the stream.
+    pub fn compress_gather(
+        &mut self,
+        op: CompressOp,
+        input: &[IoSlice],
+        output: &mut &mut [u8],
+    ) -> Result<CompressResult, Error> {
+        let last = match input.iter().rposition(|slice| !slice.is_empty()) {
+            Some(last) => last,
+            None => return self.compress_detailed(op, &mut &[][..], output),
+        };
+        let mut consumed = 0;
+        let mut produced = 0;
+        for (i, slice) in input[..=last].iter().enumerate() {
+            let slice_op = if i == last { op } else { CompressOp::Process };
+            let mut data = &slice[..];
+            let result = self.compress_detailed(slice_op, &mut data, output)?;
+            consumed += result.consumed;
+            produced += result.produced;
+            if i == last || !data.is_empty() {
+                let status = if i == last || op == CompressOp::Process {
+                    result.status
+                } else {
+                    CoStatus::Unfinished
+                };
+                return Ok(CompressResult {
+                    status,
+                    consumed,
+                    produced,
+                });
+            }
+        }
+        unreachable!()
+    }
+
+    /// Flushes all input so far and returns the pending output, which then
+    /// ends on a byte boundary.
+    ///
+    /// Once the returned bytes have been delivered, everything passed to this
+    /// compressor so far can be decoded, so protocols can use this to frame
+    /// their messages. This drives `CompressOp::Flush` until it finishes and
+    /// also returns output that was produced earlier but not yet taken.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if brotli encountered an error while compressing, for
+    /// example because the stream is finished.
+    pub fn sync_flush(&mut self) -> Result<Vec<u8>, Error> {
+        let mut output = Vec::new();
+        loop {
+            let status = self.compress(CompressOp::Flush, &mut &[][..], &mut &mut [][..])?;
+            while let Some(buf) = self.take_output(None) {
+                output.extend_from_slice(buf);
+            }
+            if status == CoStatus::Finished {
+                return Ok(output);
+            }
+        }
+    }
+
+    /// Writes `metadata` to the stream as a metadata block, appending the
+    /// encoded bytes to `output`.
+    ///
+    /// Metadata blocks carry opaque out-of-band data that decoders skip.
+    /// Input passed to earlier calls is flushed first. This drives
+    /// `CompressOp::EmitMetadata` until the whole block is written, keeping
+    /// the input buffer stable as libbrotli requires.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `ErrorKind::InvalidParameter("metadata")` if
+    /// `metadata` is longer than `MAX_METADATA_LEN`, or an error if brotli
+    /// rejects the operation, for example because the stream is finished.
+    pub fn emit_metadata(&mut self, metadata: &[u8], output: &mut Vec<u8>) -> Result<(), Error> {
+        if metadata.len() > MAX_METADATA_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidParameter("metadata"),
+                "emitting metadata",
+            ));
+        }
+        let mut input = metadata;
+        loop {
+            let status = self.compress(CompressOp::EmitMetadata, &mut input, &mut &mut [][..])?;
+            while let Some(buf) = self.take_output(None) {
+                output.extend_from_slice(buf);
+            }
+            if status == CoStatus::Finished {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Same as `compress`, but writes to a possibly uninitialized output
+    /// buffer, which saves zeroing it first.
+    ///
+    /// Returns the status along with the initialized part of `output` that
+    /// data was written to, which always starts at the beginning of `output`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if brotli encountered an error while processing the stream.
+    pub fn compress_uninit<'a>(
+        &mut self,
+        op: CompressOp,
+        input: &mut &[u8],
+        output: &'a mut [MaybeUninit<u8>],
+    ) -> Result<(CoStatus, &'a mut [u8]), Error> {
+        let out = output.as_mut_ptr() as *mut u8;
+        unsafe {
+            let (produced, result) = self.compress_into(op, input, out, output.len());
+            Ok((result?.status, slice::from_raw_parts_mut(out, produced)))
+        }
+    }
+
+    // Compresses into the `len` bytes at `out`, returning how many of them
+    // were written.
+    unsafe fn compress_into(
+        &mut self,
+        op: CompressOp,
+        input: &mut &[u8],
+        out: *mut u8,
+        len: usize,
+    ) -> (usize, Result<CompressResult, Error>) {
+        if let Err(e) = self.prepare() {
+            return (0, Err(e));
+        }
+        let copied = self.peeked.copy_to(out, len);
+        let mut available_in = input.len();
+        let mut next_in = input.as_ptr();
+        let mut available_out = len - copied;
+        let mut next_out = out.add(copied);
+        let r = brotli_sys::BrotliEncoderCompressStream(
+            self.state,
+            op as brotli_sys::BrotliEncoderOperation,
+            &mut available_in,
+            &mut next_in,
+            &mut available_out,
+            &mut next_out,
+            ptr::null_mut(),
+        );
+        let consumed = input.len() - available_in;
+        let produced = len - available_out;
+        self.total_in += consumed as u64;
+        self.total_out += produced as u64;
+        *input = &input[consumed..];
+        if r == 0 {
+            return (produced, Err(Error::new(ErrorKind::Other, "compressing")));
+        }
+        let status = if op == CompressOp::Process {
+            CoStatus::Finished
+        } else if available_in != 0
+            || self.has_more_output()
+            || (op == CompressOp::Finish && !self.is_finished())
+        {
+            CoStatus::Unfinished
+        } else {
+            CoStatus::Finished
+        };
+        if op != CompressOp::EmitMetadata {
+            if let Some(ref mut data_in) = self.data_in {
+                *data_in += consumed as u64;
+            }
+        }
+        if op != CompressOp::Process && status == CoStatus::Finished {
+            self.unflushed = false;
+        } else if consumed != 0 {
+            self.unflushed = true;
+        }
+        let result = CompressResult {
+            status,
+            consumed,
+            produced,
+        };
+        (produced, Ok(result))
+    }
+
+    /// Returns whether the stream has been finished with `CompressOp::Finish`
+    /// and all of its output has been written out or taken.
+    pub fn is_finished(&self) -> bool {
+        self.peeked.is_empty() && unsafe { brotli_sys::BrotliEncoderIsFinished(self.state) != 0 }
+    }
+
+    /// Returns whether the compressor has output that has not been written to
+    /// an output buffer yet, and can be retrieved with `take_output` or by
+    /// calling `compress` again.
+    pub fn has_more_output(&self) -> bool {
+        !self.peeked.is_empty()
+            || unsafe { brotli_sys::BrotliEncoderHasMoreOutput(self.state) != 0 }
+    }
+
+    /// Retrieve a slice of the internal compressor buffer up to `size_limit` in length
+    /// (unlimited length if `None`), consuming it. As the internal buffer may not be
+    /// contiguous, consecutive calls may return more output until this function returns
+    /// `None`.
+    pub fn take_output(&mut self, size_limit: Option<usize>) -> Option<&[u8]> {
+        if let Some(0) = size_limit {
+            return None;
+        }
+        let out = if self.peeked.is_empty() {
+            unsafe { Compress::take_library_output(self.state, size_limit)? }
+        } else {
+            self.peeked.take(size_limit)
+        };
+        self.total_out += out.len() as u64;
+        Some(out)
+    }
+
+    /// Same as `take_output`, but copies the output into a `Bytes`.
+    ///
+    /// The `Bytes` are split off a buffer owned by this compressor, whose
+    /// memory is reused for later output once all `Bytes` referring to it are
+    /// dropped. Requires the `bytes` feature.
+    #[cfg(feature = "bytes")]
+    pub fn take_output_bytes(&mut self, size_limit: Option<usize>) -> Option<Bytes> {
+        let mut bytes = mem::take(&mut self.bytes);
+        if let Some(buf) = self.take_output(size_limit) {
+            bytes.extend_from_slice(buf);
+        }
+        let out = bytes.split().freeze();
+        self.bytes = bytes;
+        if out.is_empty() {
+            None
+        } else {
+            Some(out)
+        }
+    }
+
+    /// Returns an iterator that takes all output that is ready, one chunk at a
+    /// time, as `take_output` would.
+    ///
+    /// See `OutputChunks::take_chunk` for taking chunks without copying them.
+    pub fn output_chunks(&mut self) -> OutputChunks<'_> {
+        OutputChunks {
+            stream: Stream::Compress(self),
+        }
+    }
+
+    /// Returns all output that is ready to be taken, without consuming it.
+    ///
+    /// The output is moved out of libbrotli into a buffer owned by this
+    /// compressor, from which later calls to `take_output` and `compress`
+    /// return it before any new output.
+    pub fn peek_output(&mut self) -> &[u8] {
+        while let Some(buf) = unsafe { Compress::take_library_output(self.state, None) } {
+            self.peeked.extend(buf);
+        }
+        self.peeked.as_slice()
+    }
+
+    /// Returns the number of bytes of output that are ready to be taken.
+    ///
+    /// See `peek_output` for details.
+    pub fn output_len(&mut self) -> usize {
+        self.peek_output().len()
+    }
+
+    // The returned slice is only valid until the next call into the encoder.
+    unsafe fn take_library_output<'a>(
+        state: *mut brotli_sys::BrotliEncoderState,
+        size_limit: Option<usize>,
+    ) -> Option<&'a [u8]> {
+        let mut size_limit = size_limit.unwrap_or(0); // 0 now means unlimited
+        let ptr = brotli_sys::BrotliEncoderTakeOutput(state, &mut size_limit);
+        if size_limit == 0 {
+            // ptr may or may not be null
+            None
+        } else {
+            assert!(!ptr.is_null());
+            Some(slice::from_raw_parts(ptr, size_limit))
+        }
+    }
+
+    /// Returns the total number of bytes of input consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.total_in
+    }
+
+    /// Returns the total number of bytes of output produced so far, whether
+    /// written to output buffers or returned by `take_output`.
+    pub fn total_out(&self) -> u64 {
+        self.total_out
+    }
+
+    /// Sets an encoder parameter directly by its libbrotli identifier.
+    ///
+    /// This is a low-level escape hatch for parameters that `CompressParams`
+    /// does not cover yet. `param` is one of the `BROTLI_PARAM_*` values of
+    /// libbrotli's `BrotliEncoderParameter` (also available as constants in
+    /// `brotli-sys`), and `value` is passed through unchanged. No validation
+    /// is done beyond what libbrotli itself performs, and parameters set this
+    /// way are overwritten by a later call to `set_params`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if libbrotli rejects the parameter or its value, for
+    /// example because the parameter is unknown or compression has already
+    /// started.
+    pub fn set_parameter_raw(&mut self, param: u32, value: u32) -> Result<(), Error> {
+        self.set_param(param as brotli_sys::BrotliEncoderParameter, value)
+    }
+
+    fn set_param(
+        &mut self,
+        param: brotli_sys::BrotliEncoderParameter,
+        value: u32,
+    ) -> Result<(), Error> {
+        let r = unsafe { brotli_sys::BrotliEncoderSetParameter(self.state, param, value) };
+        if r == 0 {
+            return Err(Error::config(ErrorKind::Other, "configuring the encoder"));
+        }
+        Ok(())
+    }
+
+    /// Configure the parameters of this compression session.
+    ///
+    /// This must be called before compression starts.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `ErrorKind::InvalidParameter`, naming the
+    /// rejected parameter, if a parameter is out of range, of kind
+    /// `ErrorKind::ConflictingParameters` if `npostfix` and `ndirect` cannot
+    /// be used together, and of kind `ErrorKind::AlreadyStarted` if
+    /// compression has already started. No parameter is changed if `params`
+    /// is rejected.
+    ///
+    /// Combinations that libbrotli accepts but adjusts, such as an `lgblock`
+    /// below quality 4, are applied as given; `CompressParams::validate`
+    /// reports those as well.
+    ///
+    /// Use `Compress::with_params` or `CompressBuilder` to configure a
+    /// compressor as it is created, before compression can have started.
+    pub fn set_params(&mut self, params: &CompressParams) -> Result<(), Error> {
+        if let Err(e) = params.check_ranges() {
+            let kind = match e {
+                ParamError::OutOfRange(name) => ErrorKind::InvalidParameter(name),
+                ParamError::Conflict(a, b) => ErrorKind::ConflictingParameters(a, b),
+            };
+            return Err(Error::config(kind, "configuring the encoder"));
+        }
+        self.prepare()?;
+        self.apply_params(params)?;
+        self.params = Some(params.clone());
+        Ok(())
+    }
+
+    fn apply_params(&mut self, params: &CompressParams) -> Result<(), Error> {
+        let large_window = (params.lgwin > brotli_sys::BROTLI_MAX_WINDOW_BITS) as u32;
+        let values = [
+            (brotli_sys::BROTLI_PARAM_MODE, params.mode),
+            (brotli_sys::BROTLI_PARAM_QUALITY, params.quality),
+            (brotli_sys::BROTLI_PARAM_LARGE_WINDOW, large_window),
+            (brotli_sys::BROTLI_PARAM_LGWIN, params.lgwin),
+            (brotli_sys::BROTLI_PARAM_LGBLOCK, params.lgblock),
+            (brotli_sys::BROTLI_PARAM_SIZE_HINT, params.size_hint),
+            (
+                brotli_sys::BROTLI_PARAM_DISABLE_LITERAL_CONTEXT_MODELING,
+                params.disable_literal_context_modeling as u32,
+            ),
+            (brotli_sys::BROTLI_PARAM_NPOSTFIX, params.npostfix),
+            (brotli_sys::BROTLI_PARAM_NDIRECT, params.ndirect),
+            (brotli_sys::BROTLI_PARAM_STREAM_OFFSET, params.stream_offset),
+        ];
+        for &(param, value) in values.iter() {
+            // The values are in range, so libbrotli only rejects them once
+            // the stream has started.
+            self.set_param(param, value)
+                .map_err(|_| Error::config(ErrorKind::AlreadyStarted, "configuring the encoder"))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the parameters this compressor uses, after the adjustments
+    /// libbrotli makes to them.
+    ///
+    /// These are the parameters last applied with `set_params`, or the
+    /// defaults, with out-of-range values clamped and the block size, window
+    /// size and distance parameters as derived by the encoder for the
+    /// quality. libbrotli cannot be queried for these, so they are computed
+    /// the way libbrotli 1.1 derives them. Parameters set with
+    /// `set_parameter_raw` are not reflected.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use brotli2::CompressParams;
+    /// use brotli2::raw::Compress;
+    ///
+    /// let mut params = CompressParams::new();
+    /// params.quality(2).lgwin(12);
+    /// let compress = Compress::with_params(&params).unwrap();
+    /// assert_eq!(compress.params().get_lgblock(), 14);
+    /// ```
+    pub fn params(&self) -> CompressParams {
+        match self.params {
+            Some(ref params) => params.effective(),
+            None => CompressParams::new().effective(),
+        }
+    }
+
+    /// Changes the quality used for the rest of the stream.
+    ///
+    /// This is meant to be called at a flush boundary, after a
+    /// `CompressOp::Flush` has finished and all of its output has been taken,
+    /// so that for example a long-lived connection can start fast and switch
+    /// to denser compression later.
+    ///
+    /// libbrotli cannot change the parameters of a stream that has started,
+    /// so from then on this replaces the encoder with one that continues the
+    /// same stream at the new quality, using libbrotli's support for stitching
+    /// streams together at a flush boundary. The new encoder starts with an
+    /// empty window, so it can't refer back to data from before the switch.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `ErrorKind::InvalidParameter("quality")`,
+    /// leaving the compressor unchanged, if it is not at a flush boundary, if
+    /// the stream has been finished or adopted with `from_raw`, if
+    /// dictionaries are attached, or if the new quality would use a different
+    /// window than the stream was started with, as qualities 0 and 1 use a
+    /// window of at least 18 bits.
+    pub fn set_quality(&mut self, quality: Quality) -> Result<(), Error> {
+        let rejected = || {
+            Error::new(
+                ErrorKind::InvalidParameter("quality"),
+                "changing the quality",
+            )
+        };
+        let mut params = self.params.clone().unwrap_or_default();
+        params.quality(quality.get());
+        if self
+            .set_param(brotli_sys::BROTLI_PARAM_QUALITY, quality.get())
+            .is_ok()
+        {
+            self.params = Some(params);
+            return Ok(());
+        }
+        let data_in = match self.data_in {
+            Some(data_in) => data_in,
+            None => return Err(rejected()),
+        };
+        if self.unflushed
+            || self.has_more_output()
+            || unsafe { brotli_sys::BrotliEncoderIsFinished(self.state) != 0 }
+            // The decoder would look up the stitched stream's references in
+            // the dictionaries at a different position, and expects
+            // references to fit the window from the stream header.
+            || !self.dicts.is_empty()
+            || params.effective().lgwin != self.params().lgwin
+        {
+            return Err(rejected());
+        }
+        let mut stitched = params.clone();
+        stitched.stream_offset(u64::from(params.stream_offset) + data_in);
+        let state = Compress::create_state(&self.allocator, "changing the quality")?;
+        let previous = mem::replace(&mut self.state, state);
+        let result = self.apply_params(&stitched);
+        let stale = if result.is_ok() {
+            self.params = Some(params);
+            previous
+        } else {
+            mem::replace(&mut self.state, previous)
+        };
+        unsafe {
+            brotli_sys::BrotliEncoderDestroyInstance(stale);
+        }
+        result.map_err(|_| rejected())
+    }
+}
+
+impl Default for Compress {
+    fn default() -> Compress {
+        Compress::new()
+    }
+}
+
+impl CompressBuilder {
+    /// Creates a compressor to configure, with the default parameters.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the encoder state cannot be allocated. See `try_new` for a
+    /// fallible alternative.
+    pub fn new() -> CompressBuilder {
+        CompressBuilder {
+            inner: Compress::new(),
+        }
+    }
+
+    /// Creates a compressor to configure, with the default parameters.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `ErrorKind::Alloc` if the encoder state cannot
+    /// be allocated.
+    pub fn try_new() -> Result<CompressBuilder, Error> {
+        Ok(CompressBuilder {
+            inner: Compress::try_new()?,
+        })
+    }
+
+    /// Creates a compressor to configure, whose internal memory is allocated
+    /// with `allocator`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `ErrorKind::Alloc` if the encoder state cannot
+    /// be allocated.
+    pub fn with_allocator<A: Allocator + 'static>(allocator: A) -> Result<CompressBuilder, Error> {
+        Ok(CompressBuilder {
+            inner: Compress::with_allocator(allocator)?,
+        })
+    }
+
+    /// Sets the compression parameters.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `ErrorKind::InvalidParameter` or
+    /// `ErrorKind::ConflictingParameters` if `params` is rejected, as by
+    /// `Compress::set_params`, leaving the parameters unchanged.
+    pub fn params(&mut self, params: &CompressParams) -> Result<&mut CompressBuilder, Error> {
+        self.inner.set_params(params)?;
+        Ok(self)
+    }
+
+    /// Attaches a prepared dictionary, as by `Compress::attach_dictionary`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if too many dictionaries have been attached.
+    pub fn dictionary(&mut self, dict: &Dictionary) -> Result<&mut CompressBuilder, Error> {
+        self.inner.attach_dictionary(dict)?;
+        Ok(self)
+    }
+
+    /// Returns the parameters the compressor will use, as by
+    /// `Compress::params`.
+    pub fn get_params(&self) -> CompressParams {
+        self.inner.params()
+    }
+
+    /// Returns the configured compressor, ready to compress data.
+    pub fn start(self) -> Compress {
+        self.inner
+    }
+}
+
+impl Default for CompressBuilder {
+    fn default() -> CompressBuilder {
+        CompressBuilder::new()
+    }
+}
+
+impl fmt::Debug for Compress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Compress")
+            .field("params", &self.params)
+            .field("total_in", &self.total_in)
+            .field("total_out", &self.total_out)
+            .field("finished", &self.is_finished())
+            .finish()
+    }
+}
+
+impl Drop for Compress {
+    fn drop(&mut self) {
+        unsafe {
+            brotli_sys::BrotliEncoderDestroyInstance(self.state);
+        }
+    }
+}
+
+impl Dictionary {
+    /// Prepares a new dictionary from the raw bytes in `data`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if brotli fails to prepare the dictionary.
+    pub fn new(data: &[u8]) -> Result<Dictionary, Error> {
+        let data = data.to_vec().into_boxed_slice();
+        let recording = Recording::default();
+        let live = recording.live.clone();
+        let allocator: Box<Box<dyn Allocator>> = Box::new(Box::new(recording));
+        let allocator_ptr = &*allocator as *const Box<dyn Allocator> as *mut c_void;
+        let prepared = unsafe {
+            brotli_sys::BrotliEncoderPrepareDictionary(
+                brotli_sys::BROTLI_SHARED_DICTIONARY_RAW,
+                data.len(),
+                data.as_ptr(),
+                brotli_sys::BROTLI_MAX_QUALITY as c_int,
+                Some(alloc_func),
+                Some(free_func),
+                allocator_ptr,
+            )
+        };
+        if prepared.is_null() {
+            return Err(Error::new(ErrorKind::Other, "preparing a dictionary"));
+        }
+        // The hash tables are the one live allocation with the header of a
+        // prepared dictionary pointing to its contents, and end with that
+        // pointer.
+        let tables = unsafe {
+            let live = live.lock().unwrap_or_else(|e| e.into_inner());
+            live.iter()
+                .map(|&(ptr, size)| (ptr as *const u8, size))
+                .filter(|&(_, size)| size >= PREPARED_HEADER + mem::size_of::<*const u8>())
+                .map(|(ptr, size)| (ptr, size - mem::size_of::<*const u8>()))
+                .find(|&(ptr, len)| {
+                    let tables = slice::from_raw_parts(ptr, len);
+                    read_u32(tables, 0) == LEAN_PREPARED_MAGIC
+                        && read_u32(tables, 2) as usize == data.len()
+                        && prepared_tables_len(tables) == Some(len)
+                })
+        };
+        Ok(Dictionary {
+            inner: Arc::new(DictionaryInner {
+                prepared,
+                storage: DictionaryStorage::Prepared {
+                    data,
+                    tables,
+                    _allocator: allocator,
+                },
+            }),
+        })
+    }
+
+    /// Returns the raw bytes of this dictionary.
+    pub fn as_bytes(&self) -> &[u8] {
+        match self.inner.storage {
+            DictionaryStorage::Prepared { ref data, .. } => data,
+            DictionaryStorage::Loaded {
+                ref blob,
+                tables_len,
+                data_len,
+            } => &blob_bytes(blob)[tables_len..tables_len + data_len],
+        }
+    }
+
+    /// Returns the hash tables of the prepared dictionary, if known.
+    fn tables(&self) -> Option<&[u8]> {
+        match self.inner.storage {
+            DictionaryStorage::Prepared { tables, .. } => {
+                tables.map(|(ptr, len)| unsafe { slice::from_raw_parts(ptr, len) })
+            }
+            DictionaryStorage::Loaded {
+                ref blob,
+                tables_len,
+                ..
+            } => Some(&blob_bytes(blob)[..tables_len]),
+        }
+    }
+
+    /// Writes this dictionary to `w` in a form that can be loaded again with
+    /// `Dictionary::read_from`.
+    ///
+    /// What is written is the dictionary contents followed by the hash
+    /// tables libbrotli prepared for them, as they are laid out in memory.
+    /// That layout is private to libbrotli and depends on its version and
+    /// on the byte order of the machine, so serialized dictionaries are not
+    /// portable: the tables are tagged with both, and are only used when
+    /// loaded by the same libbrotli version on a machine of the same byte
+    /// order. Otherwise loading prepares the dictionary again from its
+    /// contents, which gives the same dictionary but takes as long as
+    /// `Dictionary::new`.
+    pub fn write_to<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let data = self.as_bytes();
+        w.write_all(DICTIONARY_MAGIC)?;
+        w.write_all(&(data.len() as u64).to_le_bytes())?;
+        w.write_all(data)?;
+        let version = unsafe { brotli_sys::BrotliEncoderVersion() };
+        w.write_all(&version.to_le_bytes())?;
+        w.write_all(&BYTE_ORDER_MARK.to_ne_bytes())?;
+        match self.tables() {
+            Some(tables) => {
+                w.write_all(&(tables.len() as u64).to_le_bytes())?;
+                w.write_all(&PREPARED_MAGIC.to_ne_bytes())?;
+                w.write_all(&tables[4..])
+            }
+            None => w.write_all(&0u64.to_le_bytes()),
+        }
+    }
+
+    /// Loads a dictionary previously written with `Dictionary::write_to`.
+    ///
+    /// When the dictionary was written by the libbrotli version in use, on a
+    /// machine of the same byte order, its prepared hash tables are used as
+    /// they are rather than computed again.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the data in `r` was not
+    /// written by `Dictionary::write_to`, or any I/O error from `r`.
+    pub fn read_from<R: Read>(mut r: R) -> io::Result<Dictionary> {
+        let mut header = [0; 16];
+        r.read_exact(&mut header)?;
+        if &header[..8] != DICTIONARY_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a serialized brotli dictionary",
+            ));
+        }
+        let data = read_counted(&mut r, &header[8..])?;
+        let mut trailer = [0; 16];
+        r.read_exact(&mut trailer)?;
+        let tables = read_counted(&mut r, &trailer[8..])?;
+        let mut version = [0; 4];
+        version.copy_from_slice(&trailer[..4]);
+        if u32::from_le_bytes(version) != unsafe { brotli_sys::BrotliEncoderVersion() }
+            || read_u32(&trailer, 1) != BYTE_ORDER_MARK
+            || tables.is_empty()
+        {
+            return Ok(Dictionary::new(&data)?);
+        }
+        if !check_prepared_tables(&tables, data.len()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "serialized brotli dictionary is corrupt",
+            ));
+        }
+        let len = tables.len() + data.len();
+        let mut blob = vec![0u32; (len + 3) / 4].into_boxed_slice();
+        {
+            let bytes = unsafe { slice::from_raw_parts_mut(blob.as_mut_ptr() as *mut u8, len) };
+            bytes[..tables.len()].copy_from_slice(&tables);
+            bytes[tables.len()..].copy_from_slice(&data);
+        }
+        Ok(Dictionary {
+            inner: Arc::new(DictionaryInner {
+                prepared: blob.as_mut_ptr() as *mut brotli_sys::BrotliEncoderPreparedDictionary,
+                storage: DictionaryStorage::Loaded {
+                    blob,
+                    tables_len: tables.len(),
+                    data_len: data.len(),
+                },
+            }),
+        })
+    }
+}
+
+fn blob_bytes(blob: &[u32]) -> &[u8] {
+    unsafe { slice::from_raw_parts(blob.as_ptr() as *const u8, blob.len() * 4) }
+}
+
+/// Reads as many bytes from `r` as the little-endian length in `len` says.
+fn read_counted<R: Read>(r: &mut R, len: &[u8]) -> io::Result<Vec<u8>> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(len);
+    let len = u64::from_le_bytes(bytes);
+    let mut data = Vec::new();
+    r.take(len).read_to_end(&mut data)?;
+    if data.len() as u64 != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "serialized brotli dictionary is truncated",
+        ));
+    }
+    Ok(data)
+}
+
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Dictionary")
+            .field("len", &self.as_bytes().len())
+            .finish()
+    }
+}
+
+impl Drop for DictionaryInner {
+    fn drop(&mut self) {
+        if let DictionaryStorage::Prepared { .. } = self.storage {
+            unsafe {
+                brotli_sys::BrotliEncoderDestroyPreparedDictionary(self.prepared);
+            }
+        }
+    }
+}
+
+/// Compresses the data in `input` into `output`.
+///
+/// The `output` buffer is updated to point to the exact slice which contains
+/// the output data.
+///
+/// If successful, the amount of compressed bytes are returned (the size of the
+/// `output` slice), or an error is returned. The output buffer being too small
+/// is considered to be an error, which an `output` buffer of
+/// `max_compressed_size(input.len())` bytes is guaranteed to avoid.
+pub fn compress_buf(
+    params: &CompressParams,
+    input: &[u8],
+    output: &mut &mut [u8],
+) -> Result<usize, Error> {
+    let mut size = output.len();
+    let r = unsafe {
+        brotli_sys::BrotliEncoderCompress(
+            params.quality as c_int,
+            params.lgwin as c_int,
+            params.mode as brotli_sys::BrotliEncoderMode,
+            input.len(),
+            input.as_ptr(),
+            &mut size,
+            output.as_mut_ptr(),
+        )
+    };
+    *output = &mut mem::take(output)[..size];
+    if r == 0 {
+        Err(Error::new(ErrorKind::Other, "compressing"))
+    } else {
+        Ok(size)
+    }
+}
+
+/// Compresses the data in `input` into a newly allocated vector.
+///
+/// Unlike `compress_buf`, this uses all of `params`, and does not require an
+/// output buffer to be sized in advance.
+pub fn compress_vec(params: &CompressParams, input: &[u8]) -> Result<Vec<u8>, Error> {
+    // The stream fits in `max_compressed_size` bytes except at the lowest
+    // qualities, where the vector still grows as needed.
+    let mut output = match max_compressed_size(input.len()) {
+        usize::MAX => Vec::new(),
+        bound => Vec::with_capacity(bound),
+    };
+    compress_cb(params, input, |buf| output.extend_from_slice(buf))?;
+    Ok(output)
+}
+
+// The largest output buffer `compress_cb` uses.
+const CB_BUF_SIZE: usize = 64 * 1024;
+
+/// Compresses the data in `input`, passing each chunk of output to `sink` as
+/// it is produced.
+///
+/// This uses all of `params`, like `compress_vec`, without collecting the
+/// output in memory.
+pub fn compress_cb<F>(params: &CompressParams, mut input: &[u8], mut sink: F) -> Result<(), Error>
+where
+    F: FnMut(&[u8]),
+{
+    let mut compress = Compress::try_new()?;
+    compress.set_params(params)?;
+    // Small inputs are compressed in one go, larger ones in chunks.
+    let mut buf = vec![0; cmp::min(max_compressed_size(input.len()), CB_BUF_SIZE)];
+    let len = buf.len();
+    loop {
+        let (status, remaining) = {
+            let mut out = &mut buf[..];
+            let status = compress.compress(CompressOp::Finish, &mut input, &mut out)?;
+            (status, out.len())
+        };
+        if remaining < len {
+            sink(&buf[..len - remaining]);
+        }
+        match status {
+            CoStatus::Finished => return Ok(()),
+            CoStatus::Unfinished => {}
+        }
+    }
+}
+
+/// Returns the largest number of bytes `compress_buf` can produce for an input
+/// of `input_len` bytes.
+///
+/// This bound also holds for `Compress` streams at quality 2 or higher that
+/// are finished without flushing, such as with `compress_with_dict_buf`.
+/// Returns `usize::MAX` if the bound is too large to be represented.
+pub fn max_compressed_size(input_len: usize) -> usize {
+    match unsafe { brotli_sys::BrotliEncoderMaxCompressedSize(input_len) } {
+        0 => usize::MAX,
+        n => n,
+    }
+}
+
+/// Compresses the data in `input` into `output`, using a custom dictionary.
+///
+/// This is the same as `compress_buf`, except that the output can only be
+/// decompressed with the same dictionary, for example with
+/// `decompress_with_dict_buf`.
+pub fn compress_with_dict_buf(
+    params: &CompressParams,
+    dict: &Dictionary,
+    mut input: &[u8],
+    output: &mut &mut [u8],
+) -> Result<usize, Error> {
+    let mut compress = Compress::new();
+    compress.set_params(params)?;
+    compress.attach_dictionary(dict)?;
+    let len = output.len();
+    let (status, remaining) = {
+        let mut out = &mut output[..];
+        let status = compress.compress(CompressOp::Finish, &mut input, &mut out)?;
+        (status, out.len())
+    };
+    let size = len - remaining;
+    *output = &mut mem::take(output)[..size];
+    match status {
+        CoStatus::Finished => Ok(size),
+        CoStatus::Unfinished => Err(Error::new(ErrorKind::Other, "compressing")),
+    }
+}
+
+impl Error {
+    pub(crate) fn new(kind: ErrorKind, op: &'static str) -> Error {
+        Error {
+            kind,
+            op,
+            source: None,
+            config: false,
+        }
+    }
+
+    // An error from configuring parameters or dictionaries.
+    fn config(kind: ErrorKind, op: &'static str) -> Error {
+        Error {
+            config: true,
+            ..Error::new(kind, op)
+        }
+    }
+
+    fn decode(code: brotli_sys::BrotliDecoderErrorCode, op: &'static str) -> Error {
+        let kind = match code {
+            brotli_sys::BROTLI_DECODER_ERROR_FORMAT_DISTANCE
+                ..=brotli_sys::BROTLI_DECODER_ERROR_FORMAT_EXUBERANT_NIBBLE => DecodeError::Format,
+            brotli_sys::BROTLI_DECODER_ERROR_COMPOUND_DICTIONARY
+            | brotli_sys::BROTLI_DECODER_ERROR_DICTIONARY_NOT_SET => DecodeError::Dictionary,
+            brotli_sys::BROTLI_DECODER_ERROR_ALLOC_BLOCK_TYPE_TREES
+                ..=brotli_sys::BROTLI_DECODER_ERROR_ALLOC_CONTEXT_MODES => DecodeError::Alloc,
+            _ => DecodeError::Other,
+        };
+        // libbrotli names errors after their constants, minus the
+        // `BROTLI_DECODER` prefix, e.g. "_ERROR_FORMAT_PADDING_1".
+        let message = unsafe {
+            let ptr = brotli_sys::BrotliDecoderErrorString(code);
+            CStr::from_ptr(ptr).to_str().unwrap_or("")
+        };
+        Error {
+            kind: ErrorKind::Decode(kind),
+            op,
+            source: Some(LibraryError {
+                code,
+                message: message.trim_start_matches('_'),
+            }),
+            config: false,
+        }
+    }
+
+    // Whether this error comes from configuring parameters or dictionaries,
+    // which resetting a stream does not undo.
+    pub(crate) fn is_config(&self) -> bool {
+        self.config
+    }
+
+    /// Returns the general category of this error.
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// Returns a description of the operation that failed, such as
+    /// "decompressing" or "configuring the encoder".
+    pub fn operation(&self) -> &'static str {
+        self.op
+    }
+
+    /// Returns the libbrotli error code behind this error, if any.
+    ///
+    /// This is one of the negative `BROTLI_DECODER_ERROR_*` values of
+    /// libbrotli's `BrotliDecoderErrorCode` for errors of kind
+    /// `ErrorKind::Decode`.
+    pub fn code(&self) -> Option<i32> {
+        self.source.map(|s| s.code)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ErrorKind::InvalidParameter(name) => write!(f, "invalid brotli parameter `{}`", name)?,
+            ErrorKind::ConflictingParameters(a, b) => {
+                write!(f, "conflicting brotli parameters `{}` and `{}`", a, b)?
+            }
+            ErrorKind::AlreadyStarted => f.write_str("brotli stream has already started")?,
+            ErrorKind::Decode(DecodeError::Format) => f.write_str("invalid brotli stream")?,
+            ErrorKind::Decode(DecodeError::Dictionary) => {
+                f.write_str("missing or unusable brotli dictionary")?
+            }
+            ErrorKind::Decode(DecodeError::Alloc) => f.write_str("brotli decoder out of memory")?,
+            ErrorKind::Decode(DecodeError::Other) => f.write_str("brotli decoder error")?,
+            ErrorKind::UnknownResult(n) => write!(f, "unknown brotli result code {}", n)?,
+            ErrorKind::Alloc => f.write_str("brotli out of memory")?,
+            ErrorKind::LimitExceeded => f.write_str("brotli output size limit exceeded")?,
+            ErrorKind::Metadata => f.write_str("brotli stream contains a metadata block")?,
+            ErrorKind::Other => f.write_str("brotli error")?,
+        }
+        write!(f, " while {}", self.op)
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        "brotli error"
+    }
+
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.source
+            .as_ref()
+            .map(|s| s as &(dyn error::Error + 'static))
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        let kind = match err.kind {
+            ErrorKind::InvalidParameter(_)
+            | ErrorKind::ConflictingParameters(..)
+            | ErrorKind::AlreadyStarted => io::ErrorKind::InvalidInput,
+            ErrorKind::Decode(DecodeError::Format)
+            | ErrorKind::Decode(DecodeError::Dictionary)
+            | ErrorKind::LimitExceeded
+            | ErrorKind::Metadata => io::ErrorKind::InvalidData,
+            ErrorKind::Decode(DecodeError::Alloc) | ErrorKind::Alloc => io::ErrorKind::OutOfMemory,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+impl LibraryError {
+    /// Returns the libbrotli error code.
+    pub fn code(&self) -> i32 {
+        self.code
+    }
+
+    /// Returns libbrotli's name for the error code, such as
+    /// "ERROR_FORMAT_PADDING_1".
+    pub fn message(&self) -> &'static str {
+        self.message
+    }
+}
+
+impl fmt::Display for LibraryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (code {})", self.message, self.code)
+    }
+}
+
+impl error::Error for LibraryError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use CompressMode;
+
+    #[test]
+    fn decompress_error() {
+        let mut d = Decompress::new();
+        let err = d
+            .decompress(&mut &[0; 1024][..], &mut &mut [0; 2048][..])
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Decode(DecodeError::Format));
+        assert_eq!(
+            err.code(),
+            Some(brotli_sys::BROTLI_DECODER_ERROR_FORMAT_CL_SPACE)
+        );
+        assert_eq!(err.to_string(), "invalid brotli stream while decompressing");
+        let source = error::Error::source(&err).unwrap();
+        assert_eq!(source.to_string(), "ERROR_FORMAT_CL_SPACE (code -6)");
+
+        let io_err = io::Error::from(err.clone());
+        assert_eq!(io_err.kind(), io::ErrorKind::InvalidData);
+        let inner = io_err.get_ref().unwrap().downcast_ref::<Error>();
+        assert_eq!(inner, Some(&err));
+    }
+
+    #[test]
+    fn unknown_result() {
+        let d = Decompress::new();
+        let err = d.rc(42).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnknownResult(42));
+    }
+
+    // Counts the allocations and frees made through it.
+    struct Counting(Arc<(AtomicUsize, AtomicUsize)>);
+
+    unsafe impl Allocator for Counting {
+        fn alloc(&self, size: usize) -> *mut u8 {
+            (self.0).0.fetch_add(1, Ordering::SeqCst);
+            unsafe { ::libc::malloc(size) as *mut u8 }
+        }
+
+        unsafe fn free(&self, ptr: *mut u8) {
+            if !ptr.is_null() {
+                (self.0).1.fetch_add(1, Ordering::SeqCst);
+            }
+            ::libc::free(ptr as *mut c_void)
+        }
+    }
+
+    #[test]
+    fn custom_allocator() {
+        let counts = Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+        let mut data = [0; 128];
+        let n = {
+            let mut c = Compress::with_allocator(Counting(counts.clone())).unwrap();
+            let mut out = &mut data[..];
+            c.compress(CompressOp::Finish, &mut &b"hello!"[..], &mut out)
+                .unwrap();
+            128 - out.len()
+        };
+        {
+            let mut d = Decompress::with_allocator(Counting(counts.clone())).unwrap();
+            let mut dst = [0; 6];
+            assert_eq!(
+                d.decompress(&mut &data[..n], &mut &mut dst[..]),
+                Ok(DeStatus::Finished)
+            );
+            assert_eq!(&dst, b"hello!");
+        }
+        let allocs = counts.0.load(Ordering::SeqCst);
+        assert!(allocs > 0);
+        assert_eq!(allocs, counts.1.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn memory_usage() {
+        let allocator = Tracking::new(Malloc);
+        let usage = allocator.usage();
+        let mut c = Compress::with_allocator(allocator).unwrap();
+        let initial = usage.current();
+        assert!(initial > 0);
+
+        let data = b"0123456789abcdef".repeat(4096);
+        let mut out = vec![0; 1024];
+        c.compress(CompressOp::Finish, &mut &data[..], &mut &mut out[..])
+            .unwrap();
+        assert!(usage.peak() > initial);
+        assert!(usage.peak() >= usage.current());
+        drop(c);
+        assert_eq!(usage.current(), 0);
+    }
+
+    #[test]
+    fn reset_keeps_memory() {
+        let counts = Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+        let dict = Dictionary::new(b"0123456789abcdef").unwrap();
+        let mut c = Compress::with_allocator(Counting(counts.clone())).unwrap();
+        c.set_params(CompressParams::new().quality(5)).unwrap();
+        c.attach_dictionary(&dict).unwrap();
+        let mut d = Decompress::with_allocator(Counting(counts.clone())).unwrap();
+        d.set_large_window(true).unwrap();
+        d.attach_dictionary(&dict).unwrap();
+
+        let data = b"0123456789abcdef".repeat(256);
+        let mut compressed = vec![0; max_compressed_size(data.len())];
+        let len = compressed.len();
+        let mut warm = 0;
+        for i in 0..4 {
+            let n = {
+                let mut out = &mut compressed[..];
+                let status = c.compress(CompressOp::Finish, &mut &data[..], &mut out);
+                assert_eq!(status, Ok(CoStatus::Finished));
+                len - out.len()
+            };
+            let mut dst = vec![0; data.len()];
+            let status = d.decompress(&mut &compressed[..n], &mut &mut dst[..]);
+            assert_eq!(status, Ok(DeStatus::Finished));
+            assert_eq!(dst, data);
+            c.reset().unwrap();
+            d.reset().unwrap();
+            assert_eq!(c.params().get_quality(), 5);
+            assert!(d.params.large_window);
+            if i == 1 {
+                warm = counts.0.load(Ordering::SeqCst);
+            }
+        }
+        assert_eq!(counts.0.load(Ordering::SeqCst), warm);
+    }
+
+    #[test]
+    fn msg_keeps_memory() {
+        let counts = Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+        let mut c = Compress::with_allocator(Counting(counts.clone())).unwrap();
+        c.set_params(CompressParams::new().quality(9)).unwrap();
+        let mut d = Decompress::with_allocator(Counting(counts.clone())).unwrap();
+
+        let mut warm = 0;
+        for i in 0..10 {
+            let msg = format!("message {} of a series of many small ones", i).repeat(20);
+            let compressed = c.compress_msg(msg.as_bytes()).unwrap();
+            assert_eq!(d.decompress_msg(&compressed).unwrap(), msg.as_bytes());
+            assert_eq!(c.params().get_quality(), 9);
+            if i == 1 {
+                warm = counts.0.load(Ordering::SeqCst);
+            }
+        }
+        assert_eq!(counts.0.load(Ordering::SeqCst), warm);
+        assert_eq!(counts.1.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn memory_limit() {
+        let allocator = Tracking::with_limit(Malloc, 0);
+        let err = Decompress::with_allocator(allocator).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::Alloc);
+
+        let mut params = CompressParams::new();
+        params.quality(1).lgwin(24);
+        let data: Vec<u8> = (0..1 << 22)
+            .map(|i: u32| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let compressed = compress_vec(&params, &data).unwrap();
+
+        let mut d =
+            Decompress::with_params(DecompressParams::new().max_memory(Some(1 << 20))).unwrap();
+        let mut out = vec![0; data.len()];
+        let err = d
+            .decompress(&mut &compressed[..], &mut &mut out[..])
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Decode(DecodeError::Alloc));
+
+        let mut d =
+            Decompress::with_params(DecompressParams::new().max_memory(Some(1 << 25))).unwrap();
+        let status = d
+            .decompress(&mut &compressed[..], &mut &mut out[..])
+            .unwrap();
+        assert_eq!(status, DeStatus::Finished);
+        assert_eq!(out, data);
+
+        let mut d = Decompress::new();
+        d.set_params(DecompressParams::new().max_memory(Some(1 << 20)))
+            .unwrap();
+        let err = d
+            .decompress(&mut &compressed[..], &mut &mut out[..])
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Decode(DecodeError::Alloc));
+
+        let mut d = Decompress::new();
+        let err = d
+            .set_params(DecompressParams::new().max_memory(Some(0)))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Alloc);
+        assert!(err.is_config());
+        d.decompress(&mut &compressed[..], &mut &mut out[..])
+            .unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn compress_buf_smoke() {
+        let mut data = [0; 128];
+        let mut data = &mut data[..];
+        compress_buf(&CompressParams::new(), b"hello!", &mut data).unwrap();
+
+        let mut dst = [0; 128];
+        {
+            let mut dst = &mut dst[..];
+            let n = decompress_buf(data, &mut dst).unwrap();
+            assert_eq!(n, dst.len());
+            assert_eq!(dst.len(), 6);
+        }
+        assert_eq!(&dst[..6], b"hello!");
+    }
+
+    #[test]
+    fn max_compressed_size_is_sufficient() {
+        let data = (0..10000)
+            .map(|i: u32| (i.wrapping_mul(2654435761) >> 24) as u8)
+            .collect::<Vec<_>>();
+        for &quality in [0, 1, 5, 11].iter() {
+            let mut params = CompressParams::new();
+            params.quality(quality);
+            let mut out = vec![0; max_compressed_size(data.len())];
+            compress_buf(&params, &data, &mut &mut out[..]).unwrap();
+
+            let compressed = compress_vec(&params, &data).unwrap();
+            assert_eq!(compressed.capacity(), max_compressed_size(data.len()));
+            let mut chunks = 0;
+            compress_cb(&params, &data, |_| chunks += 1).unwrap();
+            assert_eq!(chunks, 1);
+        }
+        assert_eq!(max_compressed_size(usize::MAX), usize::MAX);
+    }
+
+    #[test]
+    fn vec_roundtrip() {
+        let data = b"hello, vectors! ".repeat(1000);
+        let compressed = compress_vec(&CompressParams::new(), &data).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress_vec(&compressed).unwrap(), data);
+
+        decompress_vec(&compressed[..compressed.len() - 1]).unwrap_err();
+        let mut trailing = compressed.clone();
+        trailing.push(0);
+        decompress_vec(&trailing).unwrap_err();
+    }
+
+    #[test]
+    fn vec_limited() {
+        let data = vec![0; 1 << 20];
+        let compressed = compress_vec(&CompressParams::new(), &data).unwrap();
+        assert_eq!(decompress_vec_limited(&compressed, 1 << 20).unwrap(), data);
+        let err = decompress_vec_limited(&compressed, (1 << 20) - 1).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::LimitExceeded);
+        let err = decompress_vec_limited(&compressed, 0).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::LimitExceeded);
+    }
+
+    #[test]
+    fn reset() {
+        let mut c = Compress::new();
+        let mut d = Decompress::new();
+        let mut first = Vec::new();
+        for i in 0..3 {
+            let mut data = [0; 128];
+            let n = {
+                let mut out = &mut data[..];
+                c.compress(CompressOp::Finish, &mut &b"hello!"[..], &mut out)
+                    .unwrap();
+                128 - out.len()
+            };
+            if i == 0 {
+                first = data[..n].to_vec();
+            }
+            assert_eq!(&data[..n], &first[..]);
+            let mut dst = [0; 6];
+            assert_eq!(
+                d.decompress(&mut &data[..n], &mut &mut dst[..]),
+                Ok(DeStatus::Finished)
+            );
+            assert_eq!(&dst, b"hello!");
+            c.reset().unwrap();
+            d.reset().unwrap();
+        }
+    }
+
+    #[test]
+    fn totals() {
+        let input = b"hello, totals! ".repeat(100);
+        let mut c = Compress::new();
+        let mut data = [0; 128];
+        c.compress(CompressOp::Process, &mut &input[..], &mut &mut data[..])
+            .unwrap();
+        assert_eq!(c.total_in(), input.len() as u64);
+        let mut n = c.total_out() as usize;
+        let status = c
+            .compress(CompressOp::Finish, &mut &[][..], &mut &mut data[n..])
+            .unwrap();
+        assert_eq!(status, CoStatus::Finished);
+        n = c.total_out() as usize;
+
+        let mut d = Decompress::new();
+        assert_eq!(
+            d.decompress(&mut &data[..n], &mut &mut [][..]),
+            Ok(DeStatus::NeedOutput)
+        );
+        assert_eq!(d.total_in(), n as u64);
+        let mut out = Vec::new();
+        while let Some(buf) = d.take_output(None) {
+            out.extend_from_slice(buf);
+        }
+        assert_eq!(out, input);
+        assert_eq!(d.total_out(), input.len() as u64);
+        d.reset().unwrap();
+        assert_eq!((d.total_in(), d.total_out()), (0, 0));
+    }
+
+    #[test]
+    fn compress_detailed() {
+        let mut c = Compress::new();
+        let mut data = [0; 128];
+        let mut input = &b"hello!"[..];
+        let mut out = &mut data[..];
+        let result = c
+            .compress_detailed(CompressOp::Finish, &mut input, &mut out)
+            .unwrap();
+        assert_eq!(result.status(), CoStatus::Finished);
+        assert_eq!(result.consumed(), 6);
+        assert_eq!(result.produced(), 128 - out.len());
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn compress_state() {
+        let mut c = Compress::new();
+        assert!(!c.is_finished());
+        let input = b"hello, state! ".repeat(100);
+        let mut data = [0; 4];
+        let status = c
+            .compress(CompressOp::Finish, &mut &input[..], &mut &mut data[..])
+            .unwrap();
+        assert_eq!(status, CoStatus::Unfinished);
+        assert!(c.has_more_output());
+        assert!(!c.is_finished());
+        while c.take_output(None).is_some() {}
+        assert!(!c.has_more_output());
+        assert!(c.is_finished());
+    }
+
+    #[test]
+    fn decompress_state() {
+        let mut data = [0; 128];
+        let mut data = &mut data[..];
+        compress_buf(&CompressParams::new(), b"hello!", &mut data).unwrap();
+
+        let mut d = Decompress::new();
+        assert!(!d.is_used());
+        assert!(!d.is_finished());
+        let mut dst = [0; 3];
+        assert_eq!(
+            d.decompress(&mut &data[..], &mut &mut dst[..]),
+            Ok(DeStatus::NeedOutput)
+        );
+        assert!(d.is_used());
+        assert!(!d.is_finished());
+        while d.take_output(None).is_some() {}
+        assert!(d.is_finished());
+    }
+
+    #[test]
+    fn split() {
+        let input: Vec<u8> = (0..100_000u32).flat_map(|i| i.to_le_bytes()).collect();
+        let compressed = compress_vec(&CompressParams::new(), &input).unwrap();
+        let (mut feeder, mut drainer) = Decompress::new().split();
+        let data = compressed.clone();
+        let t = std::thread::spawn(move || {
+            for chunk in data.chunks(1000) {
+                feeder.feed(chunk);
+            }
+        });
+
+        let mut out = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            let mut output = &mut buf[..];
+            let status = drainer.drain(&mut output).unwrap();
+            let n = 4096 - output.len();
+            out.extend_from_slice(&buf[..n]);
+            match status {
+                DeStatus::Finished => break,
+                DeStatus::NeedInput => drainer.wait_for_input(),
+                DeStatus::NeedOutput => {}
+            }
+        }
+        t.join().unwrap();
+        assert!(drainer.is_finished());
+        assert_eq!(drainer.total_in(), compressed.len() as u64);
+        assert_eq!(out, input);
+
+        let (mut feeder, mut drainer) = Decompress::new().split();
+        feeder.feed(&compressed[..compressed.len() / 2]);
+        assert_eq!(feeder.buffered(), compressed.len() / 2);
+        let mut buf = vec![0; input.len()];
+        assert_eq!(
+            drainer.drain(&mut &mut buf[..]).unwrap(),
+            DeStatus::NeedInput
+        );
+        assert_eq!(feeder.buffered(), 0);
+        drop(feeder);
+        let err = drainer.drain(&mut &mut buf[..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Decode(DecodeError::Format));
+    }
 
     #[test]
-    fn decompress_error() {
+    fn split_bounded() {
+        let input: Vec<u8> = (0..100_000u32).flat_map(|i| i.to_le_bytes()).collect();
+        let compressed = compress_vec(CompressParams::new().quality(5), &input).unwrap();
+        let (mut feeder, mut drainer) = Decompress::new().split_bounded(2000);
+        let max_buffered = Arc::new(AtomicUsize::new(0));
+        let t = {
+            let data = compressed.clone();
+            let max_buffered = max_buffered.clone();
+            std::thread::spawn(move || {
+                for chunk in data.chunks(1000) {
+                    feeder.feed(chunk);
+                    max_buffered.fetch_max(feeder.buffered(), Ordering::Relaxed);
+                }
+            })
+        };
+
+        let mut out = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            let mut output = &mut buf[..];
+            let status = drainer.drain(&mut output).unwrap();
+            let n = 4096 - output.len();
+            out.extend_from_slice(&buf[..n]);
+            match status {
+                DeStatus::Finished => break,
+                DeStatus::NeedInput => drainer.wait_for_input(),
+                DeStatus::NeedOutput => {}
+            }
+        }
+        t.join().unwrap();
+        assert_eq!(out, input);
+        assert!(max_buffered.load(Ordering::Relaxed) < 3000);
+
+        // A feeder waiting for room is released when the drainer goes away.
+        let (mut feeder, drainer) = Decompress::new().split_bounded(10);
+        feeder.feed(&compressed[..10]);
+        let t = std::thread::spawn(move || feeder.feed(&compressed[10..20]));
+        drop(drainer);
+        t.join().unwrap();
+
+        // And a drainer waiting for input when the feeder goes away.
+        let (feeder, drainer) = Decompress::new().split();
+        let t = std::thread::spawn(move || drainer.wait_for_input());
+        drop(feeder);
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn fill_output() {
+        let input: Vec<u8> = (0..1 << 20)
+            .map(|i: u32| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let compressed = compress_vec(CompressParams::new().quality(1), &input).unwrap();
+
         let mut d = Decompress::new();
-        d.decompress(&mut &[0; 1024][..], &mut &mut [0; 2048][..])
-            .unwrap_err();
+        let mut compressed = &compressed[..];
+        let mut out = Vec::new();
+        while !d.is_finished() {
+            d.decompress(&mut compressed, &mut &mut [][..]).unwrap();
+            // Use half of each chunk, leaving the rest to be copied aside by
+            // the next call into the decoder.
+            let chunk = d.fill_output().to_vec();
+            let half = chunk.len().div_ceil(2);
+            out.extend_from_slice(&chunk[..half]);
+            d.consume_output(half);
+            assert_eq!(d.total_out(), out.len() as u64);
+        }
+        assert_eq!(out, input);
     }
 
     #[test]
-    fn compress_buf_smoke() {
+    fn peek_output() {
+        let input = b"hello, peeking! ".repeat(100);
+        let mut c = Compress::new();
+        let status = c
+            .compress(CompressOp::Finish, &mut &input[..], &mut &mut [][..])
+            .unwrap();
+        assert_eq!(status, CoStatus::Unfinished);
+        let len = c.output_len();
+        assert!(len > 2);
+        let compressed = c.peek_output().to_vec();
+        assert_eq!(compressed.len(), len);
+        assert!(!c.is_finished());
+
+        let mut data = vec![0; len];
+        let (head, tail) = data.split_at_mut(2);
+        assert_eq!(c.take_output(Some(2)), Some(&compressed[..2]));
+        head.copy_from_slice(&compressed[..2]);
+        let mut out = &mut tail[..];
+        let status = c
+            .compress(CompressOp::Finish, &mut &[][..], &mut out)
+            .unwrap();
+        assert_eq!(status, CoStatus::Finished);
+        assert!(out.is_empty());
+        assert!(c.is_finished());
+        assert_eq!(data, compressed);
+        assert_eq!(c.total_out(), len as u64);
+
+        let mut d = Decompress::new();
+        assert_eq!(
+            d.decompress(&mut &data[..], &mut &mut [][..]),
+            Ok(DeStatus::NeedOutput)
+        );
+        assert_eq!(d.peek_output(), &input[..]);
+        let mut dst = vec![0; input.len()];
+        assert_eq!(
+            d.decompress(&mut &[][..], &mut &mut dst[..]),
+            Ok(DeStatus::Finished)
+        );
+        assert_eq!(dst, input);
+        assert!(d.is_finished());
+    }
+
+    #[test]
+    fn output_chunks() {
+        let input = b"hello, chunks! ".repeat(1000);
+        let mut c = Compress::new();
+        c.compress(CompressOp::Finish, &mut &input[..], &mut &mut [][..])
+            .unwrap();
+        let mut compressed = Vec::new();
+        let mut chunks = c.output_chunks();
+        while let Some(chunk) = chunks.take_chunk() {
+            compressed.extend_from_slice(chunk);
+        }
+        assert!(c.is_finished());
+        assert_eq!(c.output_chunks().take_chunk(), None);
+        assert_eq!(c.output_chunks().next(), None);
+
+        let mut d = Decompress::new();
+        d.decompress(&mut &compressed[..], &mut &mut [][..])
+            .unwrap();
+        // Appending reuses the buffer, keeping what is already in it.
+        let mut output = b"prefix".to_vec();
+        let len = d.output_chunks().append_to(&mut output);
+        assert_eq!(len, input.len());
+        assert_eq!(&output[..6], b"prefix");
+        assert_eq!(&output[6..], &input[..]);
+
+        let mut d = Decompress::new();
+        d.decompress(&mut &compressed[..], &mut &mut [][..])
+            .unwrap();
+        let output = d.output_chunks().collect::<Vec<_>>().concat();
+        assert_eq!(output, input);
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn take_output_bytes() {
+        let input = b"hello, bytes! ".repeat(1000);
+        let compressed = compress_vec(&CompressParams::new(), &input).unwrap();
+
+        let mut d = Decompress::new();
+        d.decompress(&mut &compressed[..], &mut &mut [][..])
+            .unwrap();
+        let mut output = Vec::new();
+        while let Some(bytes) = d.take_output_bytes(Some(1000)) {
+            assert!(bytes.len() <= 1000);
+            output.extend_from_slice(&bytes);
+        }
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn uninit_buffers() {
+        let input = b"hello, uninit! ".repeat(100);
+        let mut buf = [MaybeUninit::uninit(); 256];
+        let mut c = Compress::new();
+        let (status, compressed) = c
+            .compress_uninit(CompressOp::Finish, &mut &input[..], &mut buf)
+            .unwrap();
+        assert_eq!(status, CoStatus::Finished);
+        let compressed = compressed.to_vec();
+
+        let mut buf = vec![MaybeUninit::uninit(); 2048];
+        let mut d = Decompress::new();
+        let (status, output) = d.decompress_uninit(&mut &compressed[..], &mut buf).unwrap();
+        assert_eq!(status, DeStatus::Finished);
+        assert_eq!(output, &input[..]);
+    }
+
+    #[test]
+    fn compress_gather() {
+        let parts: [&[u8]; 4] = [b"hello, ", b"", b"gathered ", b"world!"];
+        let slices = parts.iter().map(|p| IoSlice::new(p)).collect::<Vec<_>>();
         let mut data = [0; 128];
-        let mut data = &mut data[..];
-        compress_buf(&CompressParams::new(), b"hello!", &mut data).unwrap();
+        let (result, n) = {
+            let mut c = Compress::new();
+            let mut out = &mut data[..];
+            let result = c
+                .compress_gather(CompressOp::Finish, &slices, &mut out)
+                .unwrap();
+            (result, 128 - out.len())
+        };
+        assert_eq!(result.status(), CoStatus::Finished);
+        assert_eq!(result.consumed(), 22);
+        assert_eq!(result.produced(), n);
 
-        let mut dst = [0; 128];
-        {
-            let mut dst = &mut dst[..];
-            let n = decompress_buf(data, &mut dst).unwrap();
-            assert_eq!(n, dst.len());
-            assert_eq!(dst.len(), 6);
+        let mut dst = [0; 22];
+        decompress_buf(&data[..n], &mut &mut dst[..]).unwrap();
+        assert_eq!(&dst, b"hello, gathered world!");
+    }
+
+    #[test]
+    fn callbacks() {
+        let input = b"hello, callbacks! ".repeat(1000);
+        let mut compressed = Vec::new();
+        compress_cb(&CompressParams::new(), &input, |buf| {
+            compressed.extend_from_slice(buf)
+        })
+        .unwrap();
+
+        let mut chunks = 0;
+        let mut output = Vec::new();
+        decompress_cb(&compressed, |buf| {
+            chunks += 1;
+            output.extend_from_slice(buf)
+        })
+        .unwrap();
+        assert!(chunks > 0);
+        assert_eq!(output, input);
+
+        decompress_cb(&compressed[..10], |_| {}).unwrap_err();
+    }
+
+    #[test]
+    fn messages() {
+        let dict = Dictionary::new(b"message number ").unwrap();
+        let mut params = CompressParams::new();
+        params.quality(5);
+        let mut c = Compress::new();
+        c.set_params(&params).unwrap();
+        c.attach_dictionary(&dict).unwrap();
+        let mut d = Decompress::new();
+        d.attach_dictionary(&dict).unwrap();
+
+        for i in 0..10 {
+            let msg = format!("message number {}", i);
+            let compressed = c.compress_msg(msg.as_bytes()).unwrap();
+            assert_eq!(d.decompress_msg(&compressed).unwrap(), msg.as_bytes());
+            d.decompress_msg(&compressed[..compressed.len() - 1])
+                .unwrap_err();
         }
-        assert_eq!(&dst[..6], b"hello!");
     }
 
     #[test]
@@ -482,6 +4140,648 @@ mod tests {
         assert_eq!(&dst[..6], b"hello!");
     }
 
+    #[test]
+    fn dictionary_roundtrip() {
+        let dict = b"{\"name\": \"brotli\", \"kind\": \"compression\"}";
+        let input = b"{\"name\": \"brotli\", \"kind\": \"decompression\"}";
+
+        let mut c = Compress::new();
+        c.set_dictionary(dict).unwrap();
+        let mut data = [0; 128];
+        let n = {
+            let mut out = &mut data[..];
+            let status = c.compress(CompressOp::Finish, &mut &input[..], &mut out);
+            assert_eq!(status, Ok(CoStatus::Finished));
+            128 - out.len()
+        };
+
+        let mut d = Decompress::new();
+        d.set_dictionary(dict).unwrap();
+        let mut dst = [0; 128];
+        {
+            let mut dst = &mut dst[..];
+            assert_eq!(
+                d.decompress(&mut &data[..n], &mut dst),
+                Ok(DeStatus::Finished)
+            );
+        }
+        assert_eq!(&dst[..input.len()], &input[..]);
+
+        let mut d = Decompress::new();
+        d.decompress(&mut &data[..n], &mut &mut [0; 128][..])
+            .unwrap_err();
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn legacy_custom_dictionary() {
+        let dict = b"{\"name\": \"brotli\", \"kind\": \"compression\"}".to_vec();
+        let input = b"{\"name\": \"brotli\", \"kind\": \"decompression\"}";
+
+        // The encoder keeps its own copy of the dictionary.
+        let copy = dict.clone();
+        let mut c = Compress::new();
+        unsafe {
+            brotli_sys::BrotliEncoderSetCustomDictionary(c.as_raw(), copy.len(), copy.as_ptr());
+        }
+        drop(copy);
+        let data = c.compress_msg(input).unwrap();
+
+        let mut d = Decompress::new();
+        unsafe {
+            brotli_sys::BrotliDecoderSetCustomDictionary(d.as_raw(), dict.len(), dict.as_ptr());
+        }
+        let mut dst = [0; 128];
+        {
+            let mut dst = &mut dst[..];
+            assert_eq!(
+                d.decompress(&mut &data[..], &mut dst),
+                Ok(DeStatus::Finished)
+            );
+        }
+        assert_eq!(&dst[..input.len()], &input[..]);
+    }
+
+    #[test]
+    fn shared_dictionary() {
+        let dict = Dictionary::new(&[7; 1024]).unwrap();
+        let input = [7; 2048];
+
+        let mut compressed = Vec::new();
+        for _ in 0..2 {
+            let mut c = Compress::new();
+            c.attach_dictionary(&dict).unwrap();
+            let mut data = [0; 128];
+            let mut out = &mut data[..];
+            c.compress(CompressOp::Finish, &mut &input[..], &mut out)
+                .unwrap();
+            let n = 128 - out.len();
+            compressed.push(data[..n].to_vec());
+        }
+        assert_eq!(compressed[0], compressed[1]);
+
+        let mut d = Decompress::new();
+        d.attach_dictionary(&dict).unwrap();
+        let mut dst = [0; 2048];
+        assert_eq!(
+            d.decompress(&mut &compressed[0][..], &mut &mut dst[..]),
+            Ok(DeStatus::Finished)
+        );
+        assert_eq!(&dst[..], &input[..]);
+    }
+
+    #[test]
+    fn dictionary_serialize() {
+        let dict = Dictionary::new(b"a shared dictionary").unwrap();
+        let mut buf = Vec::new();
+        dict.write_to(&mut buf).unwrap();
+        let loaded = Dictionary::read_from(&buf[..]).unwrap();
+        assert_eq!(loaded.as_bytes(), dict.as_bytes());
+
+        Dictionary::read_from(&buf[..buf.len() - 1]).unwrap_err();
+        Dictionary::read_from(&b"a shared dictionary"[..]).unwrap_err();
+    }
+
+    #[test]
+    fn dictionary_serialize_prepared() {
+        let contents = (0..20_000u32)
+            .map(|i| format!("entry {} of the shared dictionary\n", i * 7919 % 1000))
+            .collect::<String>();
+        let dict = Dictionary::new(contents.as_bytes()).unwrap();
+        assert!(dict.tables().is_some());
+        let mut buf = Vec::new();
+        dict.write_to(&mut buf).unwrap();
+
+        let loaded = Dictionary::read_from(&buf[..]).unwrap();
+        match loaded.inner.storage {
+            DictionaryStorage::Loaded { .. } => {}
+            DictionaryStorage::Prepared { .. } => panic!("dictionary was prepared again"),
+        }
+        assert_eq!(loaded.as_bytes(), contents.as_bytes());
+        // Only the magic number differs, as the loaded tables are followed
+        // by the dictionary contents rather than pointing to them.
+        let (tables, loaded_tables) = (dict.tables().unwrap(), loaded.tables().unwrap());
+        assert_eq!(read_u32(tables, 0), LEAN_PREPARED_MAGIC);
+        assert_eq!(read_u32(loaded_tables, 0), PREPARED_MAGIC);
+        assert_eq!(tables[4..], loaded_tables[4..]);
+
+        let input = &contents.as_bytes()[5000..60_000];
+        let mut outputs = Vec::new();
+        for dict in &[&dict, &loaded] {
+            let mut c = Compress::with_params(CompressParams::new().quality(9)).unwrap();
+            c.attach_dictionary(dict).unwrap();
+            outputs.push(c.compress_msg(input).unwrap());
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        let mut d = Decompress::new();
+        d.attach_dictionary(&loaded).unwrap();
+        assert_eq!(d.decompress_msg(&outputs[1]).unwrap(), input);
+
+        // Tables written by another libbrotli version are prepared again.
+        let tables_at = 16 + contents.len();
+        let mut other = buf.clone();
+        other[tables_at] ^= 1;
+        let again = Dictionary::read_from(&other[..]).unwrap();
+        match again.inner.storage {
+            DictionaryStorage::Prepared { .. } => {}
+            DictionaryStorage::Loaded { .. } => panic!("tables of another version were used"),
+        }
+        assert_eq!(again.as_bytes(), contents.as_bytes());
+
+        // So are tables written on a machine of the other byte order.
+        let mut other = buf.clone();
+        other[tables_at + 4..tables_at + 8].reverse();
+        let again = Dictionary::read_from(&other[..]).unwrap();
+        match again.inner.storage {
+            DictionaryStorage::Prepared { .. } => {}
+            DictionaryStorage::Loaded { .. } => panic!("tables of another byte order were used"),
+        }
+        assert_eq!(again.as_bytes(), contents.as_bytes());
+
+        // Tables that refer past the dictionary contents are rejected.
+        let mut corrupt = buf.clone();
+        let items_end = buf.len() - 4;
+        corrupt[items_end..].copy_from_slice(&0x8fff_ffffu32.to_ne_bytes());
+        let err = Dictionary::read_from(&corrupt[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn dict_buf_smoke() {
+        let dict = Dictionary::new(b"hello, world! hello, brotli!").unwrap();
+        let mut data = [0; 128];
+        let mut data = &mut data[..];
+        compress_with_dict_buf(&CompressParams::new(), &dict, b"hello, brotli!", &mut data)
+            .unwrap();
+
+        let mut dst = [0; 128];
+        let mut dst = &mut dst[..];
+        let n = decompress_with_dict_buf(&dict, data, &mut dst).unwrap();
+        assert_eq!(n, 14);
+        assert_eq!(dst, b"hello, brotli!");
+
+        let mut small = [0; 4];
+        decompress_with_dict_buf(&dict, data, &mut &mut small[..]).unwrap_err();
+        compress_with_dict_buf(
+            &CompressParams::new(),
+            &dict,
+            b"hello, brotli!",
+            &mut &mut small[..2],
+        )
+        .unwrap_err();
+    }
+
+    #[test]
+    fn distance_params() {
+        let mut params = CompressParams::new();
+        params.npostfix(2).ndirect(8);
+        let mut c = Compress::new();
+        c.set_params(&params).unwrap();
+        let data = b"0123456789abcdef".repeat(64);
+        let mut out = [0; 256];
+        let mut out = &mut out[..];
+        assert_eq!(
+            c.compress(CompressOp::Finish, &mut &data[..], &mut out),
+            Ok(CoStatus::Finished)
+        );
+
+        for &(npostfix, ndirect) in [(4, 0), (1, 3), (0, 16), (3, 128)].iter() {
+            let mut params = CompressParams::new();
+            params.npostfix(npostfix).ndirect(ndirect);
+            Compress::new().set_params(&params).unwrap_err();
+        }
+        let mut params = CompressParams::new();
+        params.npostfix(1).ndirect(3);
+        let err = Compress::new().set_params(&params).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            ErrorKind::ConflictingParameters("ndirect", "npostfix")
+        );
+    }
+
+    #[test]
+    fn invalid_params() {
+        let mut params = CompressParams::new();
+        params.quality(12);
+        let err = Compress::new().set_params(&params).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidParameter("quality"));
+
+        let mut params = CompressParams::new();
+        params.lgwin(31);
+        let err = Compress::new().set_params(&params).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidParameter("lgwin"));
+
+        let mut c = Compress::new();
+        let mut data = [0; 128];
+        c.compress(CompressOp::Finish, &mut &b"hello!"[..], &mut &mut data[..])
+            .unwrap();
+        let err = c.set_params(&CompressParams::new()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyStarted);
+        assert!(err.is_config());
+
+        let data = compress_vec(&CompressParams::new(), b"hello!").unwrap();
+        let mut d = Decompress::new();
+        d.decompress(&mut &data[..1], &mut &mut [][..]).unwrap();
+        let err = d.set_large_window(true).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyStarted);
+
+        let err = decompress_vec(b"not brotli").unwrap_err();
+        assert!(!err.is_config());
+    }
+
+    #[test]
+    fn with_params() {
+        let mut params = CompressParams::new();
+        params.quality(12);
+        let err = Compress::with_params(&params).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidParameter("quality"));
+
+        let mut c = Compress::with_params(&CompressParams::text()).unwrap();
+        let data = c.compress_msg(b"hello, hello!").unwrap();
+        assert_eq!(decompress_vec(&data).unwrap(), b"hello, hello!");
+    }
+
+    #[test]
+    fn builder() {
+        let dict = Dictionary::new(b"hello, hello, world!").unwrap();
+        let mut builder = CompressBuilder::new();
+        let mut params = CompressParams::new();
+        params.quality(12);
+        let err = builder.params(&params).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidParameter("quality"));
+        builder
+            .params(CompressParams::new().quality(5))
+            .unwrap()
+            .dictionary(&dict)
+            .unwrap();
+        assert_eq!(builder.get_params().get_quality(), 5);
+
+        let mut c = builder.start();
+        let data = c.compress_msg(b"hello, world!").unwrap();
+        let mut d = Decompress::new();
+        d.attach_dictionary(&dict).unwrap();
+        assert_eq!(d.decompress_msg(&data).unwrap(), b"hello, world!");
+    }
+
+    #[test]
+    fn set_quality() {
+        let text = (0..2000)
+            .map(|i| format!("line {} of a long-lived connection\n", i % 300))
+            .collect::<String>();
+        let text = text.as_bytes();
+        let mut c = Compress::new();
+        c.set_quality(Quality::MIN).unwrap();
+        assert_eq!(c.params().get_quality(), 0);
+
+        let mut out = Vec::new();
+        let mut compress = |c: &mut Compress, op, mut input: &[u8]| loop {
+            let status = c.compress(op, &mut input, &mut &mut [][..]).unwrap();
+            while let Some(buf) = c.take_output(None) {
+                out.extend_from_slice(buf);
+            }
+            if status == CoStatus::Finished && input.is_empty() {
+                break;
+            }
+        };
+        compress(&mut c, CompressOp::Flush, &text[..30_000]);
+        c.set_quality(Quality::new(5).unwrap()).unwrap();
+        compress(&mut c, CompressOp::Process, &text[30_000..40_000]);
+        let err = c.set_quality(Quality::MAX).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidParameter("quality"));
+        assert_eq!(c.params().get_quality(), 5);
+        compress(&mut c, CompressOp::Flush, &[]);
+        c.set_quality(Quality::MAX).unwrap();
+        assert_eq!(c.params().get_quality(), 11);
+        compress(&mut c, CompressOp::Finish, &text[40_000..]);
+        assert_eq!(c.total_in(), text.len() as u64);
+        c.set_quality(Quality::MIN).unwrap_err();
+        assert_eq!(decompress_vec(&out).unwrap(), text);
+    }
+
+    // Text to compress across a quality switch, of `len` bytes.
+    fn switch_text(len: usize) -> Vec<u8> {
+        let mut text = Vec::with_capacity(len);
+        let mut i = 0;
+        while text.len() < len {
+            text.extend_from_slice(format!("line {} of a switched stream\n", i % 7919).as_bytes());
+            i += 1;
+        }
+        text.truncate(len);
+        text
+    }
+
+    // Compresses `before`, flushes, tries switching to `quality`, then
+    // compresses and finishes with `after`, returning whether the switch
+    // was accepted along with the stream.
+    fn compress_switched(
+        c: &mut Compress,
+        quality: u32,
+        before: &[u8],
+        after: &[u8],
+    ) -> (bool, Vec<u8>) {
+        let mut out = Vec::new();
+        let mut compress = |c: &mut Compress, op, mut input: &[u8]| loop {
+            let status = c.compress(op, &mut input, &mut &mut [][..]).unwrap();
+            while let Some(buf) = c.take_output(None) {
+                out.extend_from_slice(buf);
+            }
+            if status == CoStatus::Finished && input.is_empty() {
+                break;
+            }
+        };
+        compress(c, CompressOp::Flush, before);
+        let switched = c.set_quality(Quality::new(quality).unwrap()).is_ok();
+        compress(c, CompressOp::Finish, after);
+        (switched, out)
+    }
+
+    #[test]
+    fn set_quality_window() {
+        let text = switch_text(400_000);
+        let (before, after) = text.split_at(100_000);
+
+        // Qualities 0 and 1 use an 18-bit window where the others would use
+        // the 16 bits asked for.
+        for &(from, to, accepted) in &[(1, 5, false), (0, 9, false), (5, 1, false), (5, 9, true)] {
+            let mut params = CompressParams::new();
+            params.quality(from).lgwin(16);
+            let mut c = Compress::with_params(&params).unwrap();
+            let (switched, data) = compress_switched(&mut c, to, before, after);
+            assert_eq!(switched, accepted, "{} to {}", from, to);
+            assert_eq!(decompress_vec(&data).unwrap(), text, "{} to {}", from, to);
+        }
+
+        let mut params = CompressParams::new();
+        params.quality(5).lgwin(18);
+        let mut c = Compress::with_params(&params).unwrap();
+        let (switched, data) = compress_switched(&mut c, 1, before, after);
+        assert!(switched);
+        assert_eq!(c.params().get_quality(), 1);
+        assert_eq!(decompress_vec(&data).unwrap(), text);
+    }
+
+    #[test]
+    fn set_quality_dictionary() {
+        let text = switch_text(400_000);
+        let (before, after) = text.split_at(100_000);
+        let dict = Dictionary::new(&text[..4096]).unwrap();
+        for &(from, to) in &[(5, 9), (9, 5), (5, 11)] {
+            let mut c = Compress::new();
+            c.set_params(CompressParams::new().quality(from)).unwrap();
+            c.attach_dictionary(&dict).unwrap();
+            let (switched, data) = compress_switched(&mut c, to, before, after);
+            assert!(!switched, "{} to {}", from, to);
+            assert_eq!(c.params().get_quality(), from);
+            let mut d = Decompress::new();
+            d.attach_dictionary(&dict).unwrap();
+            assert_eq!(d.decompress_msg(&data).unwrap(), text, "{} to {}", from, to);
+        }
+    }
+
+    #[test]
+    fn effective_params() {
+        let c = Compress::new();
+        assert_eq!(c.params().get_lgblock(), 18);
+        assert_eq!(c.params().get_lgwin(), 22);
+
+        let mut params = CompressParams::new();
+        params.quality(0).lgwin(10);
+        let c = Compress::with_params(&params).unwrap();
+        assert_eq!(c.params().get_lgwin(), 18);
+        assert_eq!(c.params().get_lgblock(), 10);
+
+        let mut params = CompressParams::new();
+        params.mode(CompressMode::Font);
+        let c = Compress::with_params(&params).unwrap();
+        assert_eq!(c.params().npostfix, 1);
+        assert_eq!(c.params().ndirect, 12);
+    }
+
+    // Reads the window size from the header of a brotli stream.
+    fn stream_lgwin(data: &[u8]) -> u32 {
+        let bits = u32::from(data[0]) | u32::from(data[1]) << 8;
+        if bits & 1 == 0 {
+            16
+        } else if (bits >> 1) & 7 != 0 {
+            17 + ((bits >> 1) & 7)
+        } else if (bits >> 4) & 7 == 1 {
+            (bits >> 8) & 63
+        } else if (bits >> 4) & 7 != 0 {
+            8 + ((bits >> 4) & 7)
+        } else {
+            17
+        }
+    }
+
+    #[test]
+    fn effective_lgwin_matches_library() {
+        for quality in 0..=11 {
+            for lgwin in 10..=30 {
+                let mut params = CompressParams::new();
+                params.quality(quality).lgwin(lgwin);
+                let mut c = Compress::with_params(&params).unwrap();
+                let data = c.compress_msg(b"a").unwrap();
+                assert_eq!(
+                    c.params().get_lgwin(),
+                    stream_lgwin(&data),
+                    "quality {} lgwin {}",
+                    quality,
+                    lgwin
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn debug() {
+        let mut c = Compress::new();
+        c.compress(CompressOp::Finish, &mut &b"hi"[..], &mut &mut [0; 64][..])
+            .unwrap();
+        let s = format!("{:?}", c);
+        assert!(s.contains("total_in: 2"), "{}", s);
+        assert!(s.contains("finished: true"), "{}", s);
+        assert!(format!("{:?}", Decompress::new()).starts_with("Decompress {"));
+    }
+
+    #[test]
+    fn raw_handles() {
+        let state = unsafe { brotli_sys::BrotliEncoderCreateInstance(None, None, ptr::null_mut()) };
+        let mut c = unsafe { Compress::from_raw(state) };
+        assert_eq!(c.as_raw(), state);
+        let data = c.compress_msg(b"raw").unwrap();
+
+        let state = unsafe { brotli_sys::BrotliDecoderCreateInstance(None, None, ptr::null_mut()) };
+        let mut d = unsafe { Decompress::from_raw(state) };
+        assert_eq!(d.as_raw(), state);
+        assert_eq!(d.decompress_msg(&data).unwrap(), b"raw");
+    }
+
+    #[test]
+    fn emit_metadata() {
+        let mut c = Compress::new();
+        let mut data = Vec::new();
+        c.emit_metadata(b"side channel", &mut data).unwrap();
+        data.extend(c.compress_msg(b"payload").unwrap());
+        assert_eq!(decompress_vec(&data).unwrap(), b"payload");
+
+        let too_long = vec![0; MAX_METADATA_LEN + 1];
+        let err = c.emit_metadata(&too_long, &mut data).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidParameter("metadata"));
+    }
+
+    #[test]
+    fn metadata_callback() {
+        let mut c = Compress::new();
+        let mut data = Vec::new();
+        c.emit_metadata(b"first", &mut data).unwrap();
+        c.emit_metadata(b"", &mut data).unwrap();
+        data.extend(c.compress_msg(b"payload").unwrap());
+
+        let blocks = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = blocks.clone();
+        let mut d = Decompress::new();
+        d.set_metadata_callback(move |block| sink.lock().unwrap().push(block.to_vec()));
+        assert_eq!(d.decompress_msg(&data).unwrap(), b"payload");
+        assert_eq!(d.decompress_msg(&data).unwrap(), b"payload");
+        assert_eq!(blocks.lock().unwrap().len(), 4);
+        assert_eq!(blocks.lock().unwrap()[0], b"first");
+        assert!(blocks.lock().unwrap()[1].is_empty());
+
+        d.set_metadata_callback(|_| panic!("metadata"));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| d.decompress_msg(&data)));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn reject_metadata_msg() {
+        let mut c = Compress::new();
+        let mut data = Vec::new();
+        c.emit_metadata(b"hidden", &mut data).unwrap();
+        data.extend(c.compress_msg(b"payload").unwrap());
+        let plain = c.compress_msg(b"payload").unwrap();
+
+        let mut d = Decompress::new();
+        d.set_metadata_policy(MetadataPolicy::Reject);
+        let err = d.decompress_msg(&data).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Metadata);
+        assert_eq!(d.decompress_msg(&plain).unwrap(), b"payload");
+        let err = d.decompress_msg(&data).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Metadata);
+    }
+
+    #[test]
+    fn sync_flush() {
+        let mut c = Compress::new();
+        let mut d = Decompress::new();
+        let mut decoded = Vec::new();
+        for part in [&b"first "[..], b"second"].iter() {
+            c.compress(CompressOp::Process, &mut &part[..], &mut &mut [][..])
+                .unwrap();
+            let data = c.sync_flush().unwrap();
+            assert!(!c.has_more_output());
+            d.decompress(&mut &data[..], &mut &mut [][..]).unwrap();
+            while let Some(buf) = d.take_output(None) {
+                decoded.extend_from_slice(buf);
+            }
+        }
+        assert_eq!(decoded, b"first second");
+    }
+
+    #[test]
+    fn compress_bounded() {
+        let mut c = Compress::new();
+        let input = (0..10_000u32)
+            .map(|i| (i * 7919 % 251) as u8)
+            .collect::<Vec<_>>();
+        let mut output = vec![0; max_compressed_size(input.len())];
+        let len = c.compress_bounded(&input, &mut output).unwrap();
+        assert_eq!(decompress_vec(&output[..len]).unwrap(), input);
+
+        let err = c.compress_bounded(&input, &mut output[..10]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::LimitExceeded);
+        let len = c.compress_bounded(&input, &mut output).unwrap();
+        assert_eq!(decompress_vec(&output[..len]).unwrap(), input);
+    }
+
+    #[test]
+    fn stream_offset() {
+        let mut data = [0; 256];
+        let mut out = &mut data[..];
+
+        let mut c = Compress::new();
+        assert_eq!(
+            c.compress(CompressOp::Flush, &mut &b"hello "[..], &mut out),
+            Ok(CoStatus::Finished)
+        );
+
+        let mut params = CompressParams::new();
+        params.stream_offset(6);
+        let mut c = Compress::new();
+        c.set_params(&params).unwrap();
+        assert_eq!(
+            c.compress(CompressOp::Finish, &mut &b"world"[..], &mut out),
+            Ok(CoStatus::Finished)
+        );
+        let n = 256 - out.len();
+
+        let mut dst = [0; 11];
+        decompress_buf(&data[..n], &mut &mut dst[..]).unwrap();
+        assert_eq!(&dst, b"hello world");
+    }
+
+    #[test]
+    fn large_window() {
+        let mut params = CompressParams::new();
+        params.lgwin(30);
+        let mut data = [0; 128];
+        let n = {
+            let mut out = &mut data[..];
+            let mut c = Compress::new();
+            c.set_params(&params).unwrap();
+            assert_eq!(
+                c.compress(CompressOp::Finish, &mut &b"hello!"[..], &mut out),
+                Ok(CoStatus::Finished)
+            );
+            128 - out.len()
+        };
+
+        // Large-window streams are rejected by standard decoders
+        let mut d = Decompress::new();
+        d.decompress(&mut &data[..n], &mut &mut [0; 128][..])
+            .unwrap_err();
+
+        let mut d = Decompress::new();
+        d.set_large_window(true).unwrap();
+        let mut dst = [0; 6];
+        assert_eq!(
+            d.decompress(&mut &data[..n], &mut &mut dst[..]),
+            Ok(DeStatus::Finished)
+        );
+        assert_eq!(&dst, b"hello!");
+        d.set_large_window(false).unwrap_err();
+    }
+
+    #[test]
+    fn set_parameter_raw() {
+        // 1 is BROTLI_PARAM_QUALITY
+        let mut c = Compress::new();
+        c.set_parameter_raw(1, 5).unwrap();
+        c.set_parameter_raw(1000, 0).unwrap_err();
+
+        let mut data = [0; 128];
+        c.compress(CompressOp::Finish, &mut &b"hello!"[..], &mut &mut data[..])
+            .unwrap();
+        c.set_parameter_raw(1, 6).unwrap_err();
+    }
+
+    #[test]
+    fn decompress_set_parameter_raw() {
+        // 1 is BROTLI_DECODER_PARAM_LARGE_WINDOW
+        let mut d = Decompress::new();
+        d.set_parameter_raw(1, 1).unwrap();
+        d.set_parameter_raw(1000, 0).unwrap_err();
+    }
+
     #[test]
     fn compress_smoke() {
         let mut data = [0; 128];
@@ -1,11 +1,19 @@
 //! Reader-based compression/decompression streams
 
+use std::cmp;
+use std::fmt;
 use std::io::prelude::*;
-use std::io::{self, BufReader};
+#[cfg(feature = "nightly")]
+use std::io::BorrowedCursor;
+use std::io::{self, BufReader, IoSliceMut};
+use std::mem;
+
+use brotli_sys;
 
 use bufread;
+use raw::{Dictionary, MetadataPolicy};
 
-use super::CompressParams;
+use super::{CompressParams, DecompressParams, Digest, Stats, Summary};
 
 /// A compression stream which wraps an uncompressed stream of data. Compressed
 /// data will be read from the stream.
@@ -15,6 +23,9 @@ pub struct BrotliEncoder<R: Read> {
 
 /// A decompression stream which wraps a compressed stream of data. Decompressed
 /// data will be read from the stream.
+///
+/// It also implements `BufRead`, handing out decompressed data straight from
+/// the decoder without another layer of buffering.
 pub struct BrotliDecoder<R: Read> {
     inner: bufread::BrotliDecoder<BufReader<R>>,
 }
@@ -30,16 +41,103 @@ impl<R: Read> BrotliEncoder<R> {
         }
     }
 
+    /// Creates a new encoder like `new`, which reads up to `capacity` bytes
+    /// of uncompressed data at a time from `r`.
+    pub fn with_capacity(capacity: usize, r: R, level: u32) -> BrotliEncoder<R> {
+        BrotliEncoder {
+            inner: bufread::BrotliEncoder::new(BufReader::with_capacity(capacity, r), level),
+        }
+    }
+
     /// Configure the compression parameters of this encoder.
     pub fn from_params(r: R, params: &CompressParams) -> BrotliEncoder<R> {
         BrotliEncoder {
             inner: bufread::BrotliEncoder::from_params(
-                BufReader::with_capacity(params.get_lgwin_readable(), r),
+                BufReader::with_capacity(buf_capacity(params), r),
+                params,
+            ),
+        }
+    }
+
+    /// Configure the compression parameters of this encoder, compressing
+    /// against the prefix dictionary `dict`.
+    ///
+    /// The resulting stream can only be decompressed with the same dictionary.
+    pub fn with_dictionary(r: R, params: &CompressParams, dict: &Dictionary) -> BrotliEncoder<R> {
+        BrotliEncoder {
+            inner: bufread::BrotliEncoder::with_dictionary(
+                BufReader::with_capacity(buf_capacity(params), r),
+                params,
+                dict,
+            ),
+        }
+    }
+
+    pub(crate) fn build(
+        capacity: Option<usize>,
+        r: R,
+        params: &CompressParams,
+        dict: Option<&Dictionary>,
+    ) -> BrotliEncoder<R> {
+        let capacity = capacity.unwrap_or_else(|| buf_capacity(params));
+        BrotliEncoder {
+            inner: bufread::BrotliEncoder::build(
+                BufReader::with_capacity(capacity, r),
                 params,
+                dict,
             ),
         }
     }
 
+    /// Sets whether to flush the compressed stream when the underlying
+    /// stream has no data available.
+    ///
+    /// By default, an `io::ErrorKind::WouldBlock` error from the underlying
+    /// stream is returned as is, and data read before it may stay buffered in
+    /// the compressor until more input arrives. With this enabled, reading
+    /// first flushes the compressed stream so that all input read so far can
+    /// be decompressed, and only returns the error once nothing is left to
+    /// flush. This suits live or non-blocking sources.
+    pub fn set_flush_on_would_block(&mut self, flush: bool) {
+        self.inner.set_flush_on_would_block(flush);
+    }
+
+    /// Replaces the compression parameters of this encoder.
+    ///
+    /// This allows tuning an encoder created elsewhere, for example by generic
+    /// code, but only before compression starts. Once data has been read from
+    /// the encoder, this fails with an error of kind
+    /// `io::ErrorKind::InvalidInput` wrapping a `raw::Error` of kind
+    /// `ErrorKind::AlreadyStarted`, and the parameters are left unchanged.
+    pub fn set_params(&mut self, params: &CompressParams) -> io::Result<()> {
+        self.inner.set_params(params)
+    }
+
+    /// Starts collecting `Stats` for this encoder, as returned by `stats`.
+    pub fn enable_stats(&mut self) {
+        self.inner.enable_stats();
+    }
+
+    /// Returns the statistics collected since `enable_stats` was called, or
+    /// `None` if it was not.
+    ///
+    /// The byte counts cover the whole stream, and `reset` starts the
+    /// statistics over.
+    pub fn stats(&self) -> Option<Stats> {
+        self.inner.stats()
+    }
+
+    /// Returns the number of uncompressed bytes read from the underlying
+    /// stream and compressed so far.
+    pub fn total_in(&self) -> u64 {
+        self.inner.total_in()
+    }
+
+    /// Returns the number of compressed bytes produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.inner.total_out()
+    }
+
     /// Acquires a reference to the underlying stream
     pub fn get_ref(&self) -> &R {
         self.inner.get_ref().get_ref()
@@ -53,16 +151,61 @@ impl<R: Read> BrotliEncoder<R> {
         self.inner.get_mut().get_mut()
     }
 
+    /// Resets this encoder to process a new stream read from `r`, returning
+    /// the previous underlying stream.
+    ///
+    /// The parameters and dictionary it was created with are kept, and any
+    /// data buffered from the previous stream is discarded.
+    pub fn reset(&mut self, r: R) -> R {
+        let reader = self.inner.get_mut();
+        let buffered = reader.buffer().len();
+        reader.consume(buffered);
+        let old = mem::replace(reader.get_mut(), r);
+        self.inner.restart();
+        old
+    }
+
     /// Unwrap the underlying writer, finishing the compression stream.
     pub fn into_inner(self) -> R {
         self.inner.into_inner().into_inner()
     }
+
+    /// Consumes this encoder like `into_inner`, also returning how much data
+    /// it compressed.
+    ///
+    /// The summary only covers the whole stream once this encoder has been
+    /// read to the end.
+    pub fn into_inner_with_summary(self) -> (R, Summary) {
+        let summary = Summary::new(self.total_in(), self.total_out());
+        (self.into_inner(), summary)
+    }
+}
+
+/// Size of the input buffer for an encoder with the given parameters, which
+/// is the size of the window capped to that of the largest standard window.
+fn buf_capacity(params: &CompressParams) -> usize {
+    cmp::min(
+        params.get_lgwin_readable(),
+        1 << brotli_sys::BROTLI_MAX_WINDOW_BITS,
+    )
+}
+
+impl<R: Read + fmt::Debug> fmt::Debug for BrotliEncoder<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BrotliEncoder")
+            .field("inner", &self.inner)
+            .finish()
+    }
 }
 
 impl<R: Read> Read for BrotliEncoder<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.inner.read(buf)
     }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        self.inner.read_vectored(bufs)
+    }
 }
 
 impl<R: Read> BrotliDecoder<R> {
@@ -74,6 +217,114 @@ impl<R: Read> BrotliDecoder<R> {
         }
     }
 
+    /// Creates a new decoder like `new`, which reads up to `capacity` bytes
+    /// of compressed data at a time from `r`.
+    pub fn with_capacity(capacity: usize, r: R) -> BrotliDecoder<R> {
+        BrotliDecoder {
+            inner: bufread::BrotliDecoder::new(BufReader::with_capacity(capacity, r)),
+        }
+    }
+
+    /// Configure the decompression parameters of this decoder.
+    pub fn from_params(r: R, params: &DecompressParams) -> BrotliDecoder<R> {
+        BrotliDecoder {
+            inner: bufread::BrotliDecoder::from_params(BufReader::new(r), params),
+        }
+    }
+
+    /// Create a new decompression stream, which will read compressed data
+    /// from the given input stream that was compressed against the prefix
+    /// dictionary `dict`.
+    pub fn with_dictionary(r: R, dict: &Dictionary) -> BrotliDecoder<R> {
+        BrotliDecoder {
+            inner: bufread::BrotliDecoder::with_dictionary(BufReader::new(r), dict),
+        }
+    }
+
+    pub(crate) fn build(
+        capacity: Option<usize>,
+        r: R,
+        params: &DecompressParams,
+        dict: Option<&Dictionary>,
+    ) -> BrotliDecoder<R> {
+        let r = match capacity {
+            Some(capacity) => BufReader::with_capacity(capacity, r),
+            None => BufReader::new(r),
+        };
+        BrotliDecoder {
+            inner: bufread::BrotliDecoder::build(r, params, dict),
+        }
+    }
+
+    /// Returns whether the end of the brotli stream has been reached and all
+    /// of its data has been read.
+    ///
+    /// `total_in` is then the length of the compressed stream, and the input
+    /// following it starts with `unconsumed`.
+    pub fn is_finished(&self) -> bool {
+        self.inner.is_finished()
+    }
+
+    /// Decodes the rest of the stream into `w`, returning the number of bytes
+    /// written.
+    ///
+    /// This writes straight from the decoder's output buffer, which avoids the
+    /// extra copy `io::copy` makes. `w` is not flushed.
+    pub fn copy_to<W: Write + ?Sized>(&mut self, w: &mut W) -> io::Result<u64> {
+        self.inner.copy_to(w)
+    }
+
+    pub(crate) fn copy_to_with<W, F>(&mut self, w: &mut W, chunk: F) -> io::Result<u64>
+    where
+        W: Write + ?Sized,
+        F: FnMut(u64, u64) -> io::Result<()>,
+    {
+        self.inner.copy_to_with(w, chunk)
+    }
+
+    /// Returns the bytes read from the underlying stream into the internal
+    /// buffer but not consumed by the decoder yet.
+    ///
+    /// Once the brotli stream has ended, these are the bytes that follow it,
+    /// up to the end of the last read from the underlying stream, which is
+    /// how protocol parsers can find where a compressed payload ends inside a
+    /// larger message. `into_parts` returns them along with the stream.
+    pub fn unconsumed(&self) -> &[u8] {
+        self.inner.get_ref().buffer()
+    }
+
+    /// Sets how metadata blocks in the compressed stream are handled.
+    ///
+    /// By default they are skipped. See `MetadataPolicy` for the choices.
+    pub fn set_metadata_policy(&mut self, policy: MetadataPolicy) {
+        self.inner.set_metadata_policy(policy);
+    }
+
+    /// Starts collecting `Stats` for this decoder, as returned by `stats`.
+    pub fn enable_stats(&mut self) {
+        self.inner.enable_stats();
+    }
+
+    /// Returns the statistics collected since `enable_stats` was called, or
+    /// `None` if it was not.
+    ///
+    /// The byte counts cover the whole stream, and `reset` starts the
+    /// statistics over.
+    pub fn stats(&self) -> Option<Stats> {
+        self.inner.stats()
+    }
+
+    /// Returns the number of compressed bytes read from the underlying
+    /// stream and decompressed so far.
+    pub fn total_in(&self) -> u64 {
+        self.inner.total_in()
+    }
+
+    /// Returns the number of decompressed bytes produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.inner.total_out()
+    }
+
     /// Acquires a reference to the underlying stream
     pub fn get_ref(&self) -> &R {
         self.inner.get_ref().get_ref()
@@ -87,22 +338,246 @@ impl<R: Read> BrotliDecoder<R> {
         self.inner.get_mut().get_mut()
     }
 
+    /// Sets the maximum number of bytes this decoder may decompress.
+    ///
+    /// Once a stream would decompress to more than `limit` bytes, reading fails
+    /// with an error of kind `io::ErrorKind::InvalidData` wrapping a
+    /// `raw::Error` of kind `ErrorKind::LimitExceeded`, and the data past the
+    /// limit is never returned. This protects against small inputs that expand
+    /// to huge amounts of data. `None`, the default, removes the limit.
+    pub fn set_max_output(&mut self, limit: Option<u64>) {
+        self.inner.set_max_output(limit);
+    }
+
+    /// Sets whether data following the end of the brotli stream is an
+    /// error.
+    ///
+    /// By default such data is ignored. In strict mode, reading fails with an
+    /// error of kind `io::ErrorKind::InvalidData` once the stream has ended if
+    /// more data follows it.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.inner.set_strict(strict);
+    }
+
+    /// Sets whether a stream that ends early is decoded as far as possible.
+    ///
+    /// By default, reading fails with an error if the underlying stream ends
+    /// in the middle of the brotli stream. In tolerant mode, the data decoded
+    /// up to that point is returned instead, reading then reports the end of
+    /// the data, and `is_truncated` returns `true`.
+    pub fn set_tolerant(&mut self, tolerant: bool) {
+        self.inner.set_tolerant(tolerant);
+    }
+
+    /// Returns whether the brotli stream was found to be truncated, which
+    /// is only reported in tolerant mode.
+    pub fn is_truncated(&self) -> bool {
+        self.inner.is_truncated()
+    }
+
+    /// Consumes this decoder, returning the underlying stream and the bytes
+    /// read from it into the internal buffer but not consumed by the decoder.
+    ///
+    /// Once the brotli stream has ended, these are the bytes that follow it,
+    /// up to the end of the last read from the underlying stream. Discarding
+    /// them, as `into_inner` does, loses data that follows the compressed
+    /// payload.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::io::prelude::*;
+    /// use brotli2::raw::compress_vec;
+    /// use brotli2::read::BrotliDecoder;
+    /// use brotli2::CompressParams;
+    ///
+    /// let mut data = compress_vec(&CompressParams::new(), b"payload").unwrap();
+    /// data.extend_from_slice(b"trailer");
+    ///
+    /// let mut decoder = BrotliDecoder::new(&data[..]);
+    /// let mut payload = Vec::new();
+    /// decoder.read_to_end(&mut payload).unwrap();
+    /// let (rest, buffered) = decoder.into_parts();
+    /// assert_eq!(payload, b"payload");
+    /// assert_eq!([&buffered[..], rest].concat(), b"trailer");
+    /// ```
+    pub fn into_parts(self) -> (R, Vec<u8>) {
+        let reader = self.inner.into_inner();
+        let buffered = reader.buffer().to_vec();
+        (reader.into_inner(), buffered)
+    }
+
+    /// Resets this decoder to process a new stream read from `r`, returning
+    /// the previous underlying stream.
+    ///
+    /// The parameters and dictionary it was created with are kept, and any
+    /// data buffered from the previous stream is discarded.
+    pub fn reset(&mut self, r: R) -> R {
+        let reader = self.inner.get_mut();
+        let buffered = reader.buffer().len();
+        reader.consume(buffered);
+        let old = mem::replace(reader.get_mut(), r);
+        self.inner.restart();
+        old
+    }
+
     /// Unwrap the underlying writer, finishing the compression stream.
     pub fn into_inner(self) -> R {
         self.inner.into_inner().into_inner()
     }
 }
 
+impl<R: Read + fmt::Debug> fmt::Debug for BrotliDecoder<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BrotliDecoder")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
 impl<R: Read> Read for BrotliDecoder<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.inner.read(buf)
     }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        self.inner.read_vectored(bufs)
+    }
+
+    #[cfg(feature = "nightly")]
+    fn read_buf(&mut self, cursor: BorrowedCursor) -> io::Result<()> {
+        self.inner.read_buf(cursor)
+    }
+}
+
+impl<R: Read> BufRead for BrotliDecoder<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
+
+/// A decompression stream which decodes any number of brotli streams that
+/// follow each other in the wrapped stream, like
+/// `bufread::MultiBrotliDecoder`.
+#[derive(Debug)]
+pub struct MultiBrotliDecoder<R: Read> {
+    inner: bufread::MultiBrotliDecoder<BufReader<R>>,
+}
+
+impl<R: Read> MultiBrotliDecoder<R> {
+    /// Create a new decompression stream, which will read compressed
+    /// data from the given input stream and decompress it.
+    pub fn new(r: R) -> MultiBrotliDecoder<R> {
+        MultiBrotliDecoder {
+            inner: bufread::MultiBrotliDecoder::new(BufReader::new(r)),
+        }
+    }
+
+    /// Configure the decompression parameters of this decoder.
+    pub fn from_params(r: R, params: &DecompressParams) -> MultiBrotliDecoder<R> {
+        MultiBrotliDecoder {
+            inner: bufread::MultiBrotliDecoder::from_params(BufReader::new(r), params),
+        }
+    }
+
+    /// Create a new decompression stream, which will read compressed data
+    /// from the given input stream where every stream was compressed against
+    /// the prefix dictionary `dict`.
+    pub fn with_dictionary(r: R, dict: &Dictionary) -> MultiBrotliDecoder<R> {
+        MultiBrotliDecoder {
+            inner: bufread::MultiBrotliDecoder::with_dictionary(BufReader::new(r), dict),
+        }
+    }
+
+    /// Acquires a reference to the underlying stream
+    pub fn get_ref(&self) -> &R {
+        self.inner.get_ref().get_ref()
+    }
+
+    /// Acquires a mutable reference to the underlying stream
+    ///
+    /// Note that mutation of the stream may result in surprising results if
+    /// this decoder is continued to be used.
+    pub fn get_mut(&mut self) -> &mut R {
+        self.inner.get_mut().get_mut()
+    }
+
+    /// Consumes this decoder, returning the underlying stream.
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner().into_inner()
+    }
+}
+
+impl<R: Read> Read for MultiBrotliDecoder<R> {
+    fn read(&mut self, into: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(into)
+    }
+}
+
+/// A reader which computes a `Digest` over the data read through it.
+///
+/// Wrapping a `BrotliDecoder` in it digests the decompressed data, while
+/// giving it to a decoder as its input stream digests the compressed data.
+/// Encoders work the same way. See `write::DigestWriter` for an example.
+#[derive(Debug)]
+pub struct DigestReader<R: Read, D: Digest> {
+    obj: R,
+    digest: D,
+}
+
+impl<R: Read, D: Digest> DigestReader<R, D> {
+    /// Creates a reader which reads from `obj`, adding all data read to
+    /// `digest`.
+    pub fn new(obj: R, digest: D) -> DigestReader<R, D> {
+        DigestReader { obj, digest }
+    }
+
+    /// Acquires a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.obj
+    }
+
+    /// Acquires a mutable reference to the underlying reader.
+    ///
+    /// Data read directly from it is not digested.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.obj
+    }
+
+    /// Returns the digest of the data read so far, before finalizing.
+    pub fn digest(&self) -> &D {
+        &self.digest
+    }
+
+    /// Consumes this reader, returning the underlying reader and the
+    /// finalized digest of all data read through it.
+    pub fn finish(self) -> (R, D::Output) {
+        (self.obj, self.digest.finalize())
+    }
+}
+
+impl<R: Read, D: Digest> Read for DigestReader<R, D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.obj.read(buf)?;
+        self.digest.update(&buf[..n]);
+        Ok(n)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use read::{BrotliDecoder, BrotliEncoder};
+    use raw::{self, Compress, Decompress, Dictionary, MetadataPolicy};
+    use read::{BrotliDecoder, BrotliEncoder, DigestReader, MultiBrotliDecoder};
+    use std::cmp;
     use std::io::prelude::*;
+    use std::io::{self, IoSliceMut};
+    use std::thread;
+    use std::time::Duration;
+    use {CompressParams, DecompressParams, Digest};
 
     use rand::distributions::Standard;
     use rand::{thread_rng, Rng};
@@ -119,6 +594,477 @@ mod tests {
         assert_eq!(data2, m);
     }
 
+    #[test]
+    fn metadata_policy() {
+        let mut c = Compress::new();
+        let mut data = Vec::new();
+        c.emit_metadata(b"hidden", &mut data).unwrap();
+        data.extend(c.compress_msg(b"visible").unwrap());
+
+        let mut out = Vec::new();
+        BrotliDecoder::new(&data[..]).read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"visible");
+
+        let mut d = BrotliDecoder::new(&data[..]);
+        d.set_metadata_policy(MetadataPolicy::Reject);
+        let err = d.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = err.into_inner().unwrap().downcast::<raw::Error>().unwrap();
+        assert_eq!(err.kind(), raw::ErrorKind::Metadata);
+    }
+
+    #[test]
+    fn buf_read() {
+        let input = (0..1000)
+            .map(|i| format!("line {}\n", i))
+            .collect::<String>();
+        let mut data = Vec::new();
+        BrotliEncoder::new(input.as_bytes(), 6)
+            .read_to_end(&mut data)
+            .unwrap();
+
+        let d = BrotliDecoder::new(&data[..]);
+        let lines = d.lines().collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(lines.len(), 1000);
+        assert_eq!(lines[999], "line 999");
+
+        let mut d = BrotliDecoder::new(&data[..]);
+        d.set_max_output(Some(10));
+        let mut line = String::new();
+        d.read_line(&mut line).unwrap();
+        assert_eq!(line, "line 0\n");
+        assert!(d.read_line(&mut line).is_err());
+    }
+
+    #[test]
+    fn vectored() {
+        fn read_all<R: Read>(mut r: R) -> Vec<u8> {
+            let mut out = Vec::new();
+            let (mut a, mut b, mut c) = ([0; 5], [0; 100], [0; 4000]);
+            loop {
+                let mut bufs = [
+                    IoSliceMut::new(&mut a),
+                    IoSliceMut::new(&mut []),
+                    IoSliceMut::new(&mut b),
+                    IoSliceMut::new(&mut c),
+                ];
+                let mut n = r.read_vectored(&mut bufs).unwrap();
+                if n == 0 {
+                    return out;
+                }
+                for buf in [&a[..], &b[..], &c[..]] {
+                    let amt = cmp::min(n, buf.len());
+                    out.extend_from_slice(&buf[..amt]);
+                    n -= amt;
+                }
+            }
+        }
+
+        let input = thread_rng()
+            .sample_iter(&Standard)
+            .take(100_000)
+            .collect::<Vec<u8>>();
+        let data = read_all(BrotliEncoder::new(&input[..], 1));
+        let mut expected = Vec::new();
+        BrotliEncoder::new(&input[..], 1)
+            .read_to_end(&mut expected)
+            .unwrap();
+        assert_eq!(data, expected);
+        assert_eq!(read_all(BrotliDecoder::new(&data[..])), input);
+    }
+
+    #[test]
+    #[cfg(feature = "nightly")]
+    fn read_buf() {
+        use std::io::BorrowedBuf;
+        use std::mem::MaybeUninit;
+
+        let input = b"hello world! ".repeat(1000);
+        let mut data = Vec::new();
+        BrotliEncoder::new(&input[..], 6)
+            .read_to_end(&mut data)
+            .unwrap();
+
+        let mut d = BrotliDecoder::new(&data[..]);
+        let mut out = Vec::new();
+        let mut storage = [MaybeUninit::uninit(); 100];
+        loop {
+            let mut buf = BorrowedBuf::from(&mut storage[..]);
+            d.read_buf(buf.unfilled()).unwrap();
+            if buf.len() == 0 {
+                break;
+            }
+            out.extend_from_slice(buf.filled());
+        }
+        assert_eq!(out, input);
+    }
+
+    #[test]
+    fn into_inner_with_summary() {
+        let mut c = BrotliEncoder::new(&[0; 1000][..], 6);
+        let mut data = Vec::new();
+        c.read_to_end(&mut data).unwrap();
+        let (rest, summary) = c.into_inner_with_summary();
+        assert!(rest.is_empty());
+        assert_eq!(summary.total_in(), 1000);
+        assert_eq!(summary.total_out(), data.len() as u64);
+        assert!(summary.ratio() > 1.0);
+    }
+
+    #[test]
+    fn set_params() {
+        let input = vec![b'a'; 10_000];
+        let mut params = CompressParams::new();
+        params.quality(11).lgwin(10);
+        let mut expected = Vec::new();
+        BrotliEncoder::from_params(&input[..], &params)
+            .read_to_end(&mut expected)
+            .unwrap();
+
+        let mut c = BrotliEncoder::new(&input[..], 1);
+        c.set_params(&params).unwrap();
+        let mut data = vec![0; 1];
+        c.read_exact(&mut data).unwrap();
+        let err = c.set_params(CompressParams::new().quality(1)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        c.read_to_end(&mut data).unwrap();
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn by_mut_ref() {
+        fn read_some<R: Read>(r: R, n: u64) -> Vec<u8> {
+            let mut out = Vec::new();
+            r.take(n).read_to_end(&mut out).unwrap();
+            out
+        }
+
+        let mut data = Vec::new();
+        BrotliEncoder::new(&b"hello world"[..], 6)
+            .read_to_end(&mut data)
+            .unwrap();
+        let mut d = BrotliDecoder::new(&data[..]);
+        assert_eq!(read_some(&mut d, 6), b"hello ");
+        assert_eq!(read_some(&mut d, 100), b"world");
+    }
+
+    #[test]
+    fn flush_on_would_block() {
+        // Yields each chunk in one read, with `None` as `WouldBlock`. The
+        // first one is answered with a flush, the second with the error.
+        struct Live(Vec<Option<&'static [u8]>>);
+
+        impl Read for Live {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0.is_empty() {
+                    return Ok(0);
+                }
+                match self.0.remove(0) {
+                    Some(data) => {
+                        buf[..data.len()].copy_from_slice(data);
+                        Ok(data.len())
+                    }
+                    None => Err(io::ErrorKind::WouldBlock.into()),
+                }
+            }
+        }
+
+        let live = Live(vec![Some(b"hello "), None, None, Some(b"world")]);
+        let mut c = BrotliEncoder::new(live, 6);
+        c.set_flush_on_would_block(true);
+        let mut data = Vec::new();
+        let mut buf = [0; 1024];
+        let err = loop {
+            match c.read(&mut buf) {
+                Ok(n) => data.extend_from_slice(&buf[..n]),
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        let mut d = Decompress::new();
+        let mut out = [0; 64];
+        let mut output = &mut out[..];
+        d.decompress(&mut &data[..], &mut output).unwrap();
+        let n = 64 - output.len();
+        assert_eq!(&out[..n], b"hello ");
+
+        c.read_to_end(&mut data).unwrap();
+        let mut out = Vec::new();
+        BrotliDecoder::new(&data[..]).read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"hello world");
+    }
+
+    #[test]
+    fn small_reads() {
+        let input = thread_rng()
+            .sample_iter(&Standard)
+            .take(100_000)
+            .collect::<Vec<u8>>();
+        let mut data = Vec::new();
+        BrotliEncoder::new(&input[..], 1)
+            .read_to_end(&mut data)
+            .unwrap();
+
+        let mut d = BrotliDecoder::new(&data[..]);
+        let mut out = Vec::new();
+        let mut buf = [0; 7];
+        loop {
+            match d.read(&mut buf).unwrap() {
+                0 => break,
+                n => out.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert_eq!(out, input);
+    }
+
+    #[test]
+    fn with_capacity() {
+        let input = b"hello world! ".repeat(1000);
+        let mut data = Vec::new();
+        BrotliEncoder::with_capacity(16, &input[..], 6)
+            .read_to_end(&mut data)
+            .unwrap();
+        let mut out = Vec::new();
+        BrotliDecoder::with_capacity(16, &data[..])
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, input);
+    }
+
+    #[test]
+    fn max_output() {
+        let data = raw::compress_vec(&CompressParams::new(), &[0; 100_000]).unwrap();
+
+        let mut d = BrotliDecoder::new(&data[..]);
+        d.set_max_output(Some(100_000));
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert_eq!(out.len(), 100_000);
+
+        let mut d = BrotliDecoder::new(&data[..]);
+        d.set_max_output(Some(99_999));
+        let mut out = Vec::new();
+        let err = d.read_to_end(&mut out).unwrap_err();
+        assert_eq!(out.len(), 99_999);
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = err.into_inner().unwrap().downcast::<raw::Error>().unwrap();
+        assert_eq!(err.kind(), raw::ErrorKind::LimitExceeded);
+    }
+
+    #[test]
+    fn reset() {
+        let mut c = BrotliEncoder::new(&b"first"[..], 6);
+        let mut first = Vec::new();
+        c.read_to_end(&mut first).unwrap();
+        c.reset(&b"second"[..]);
+        let mut second = Vec::new();
+        c.read_to_end(&mut second).unwrap();
+
+        let mut d = BrotliDecoder::new(&first[..]);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"first");
+        d.reset(&second[..]);
+        out.clear();
+        d.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"second");
+
+        let mut c = BrotliEncoder::new(&b""[..], 12);
+        assert!(c.read_to_end(&mut Vec::new()).is_err());
+        c.reset(&b"x"[..]);
+        assert!(c.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn multi_decoder() {
+        let mut data = Vec::new();
+        for part in [&b"first "[..], b"", b"second"].iter() {
+            let mut c = BrotliEncoder::new(*part, 6);
+            c.read_to_end(&mut data).unwrap();
+        }
+        let mut out = Vec::new();
+        MultiBrotliDecoder::new(&data[..])
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, b"first second");
+
+        let dict = Dictionary::new(b"a dictionary for every stream").unwrap();
+        let mut dict_data = Vec::new();
+        for part in [&b"a first stream "[..], b"a second stream"].iter() {
+            let mut c = BrotliEncoder::with_dictionary(*part, &CompressParams::new(), &dict);
+            c.read_to_end(&mut dict_data).unwrap();
+        }
+        let mut out = Vec::new();
+        MultiBrotliDecoder::with_dictionary(&dict_data[..], &dict)
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, b"a first stream a second stream");
+
+        data.push(0xff);
+        assert!(MultiBrotliDecoder::new(&data[..])
+            .read_to_end(&mut Vec::new())
+            .is_err());
+    }
+
+    #[test]
+    fn strict() {
+        let mut data = Vec::new();
+        BrotliEncoder::new(&b"hello"[..], 6)
+            .read_to_end(&mut data)
+            .unwrap();
+        let mut d = BrotliDecoder::new(&data[..]);
+        d.set_strict(true);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"hello");
+
+        data.push(0);
+        let mut d = BrotliDecoder::new(&data[..]);
+        d.set_strict(true);
+        let err = d.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(BrotliDecoder::new(&data[..])
+            .read_to_end(&mut Vec::new())
+            .is_ok());
+    }
+
+    #[test]
+    fn digest_reader() {
+        // Adler-32, as a stand-in for a real checksum.
+        struct Adler(u32, u32);
+
+        impl Digest for Adler {
+            type Output = u32;
+
+            fn update(&mut self, data: &[u8]) {
+                for &b in data {
+                    self.0 = (self.0 + b as u32) % 65521;
+                    self.1 = (self.1 + self.0) % 65521;
+                }
+            }
+
+            fn finalize(self) -> u32 {
+                self.1 << 16 | self.0
+            }
+        }
+
+        let input = b"hello world! ".repeat(1000);
+        let mut data = Vec::new();
+        BrotliEncoder::new(&input[..], 6)
+            .read_to_end(&mut data)
+            .unwrap();
+
+        let mut r = DigestReader::new(BrotliDecoder::new(&data[..]), Adler(1, 0));
+        let mut out = Vec::new();
+        r.read_to_end(&mut out).unwrap();
+        assert_eq!(out, input);
+        let (_, digest) = r.finish();
+        let mut expected = Adler(1, 0);
+        expected.update(&input);
+        assert_eq!(digest, expected.finalize());
+    }
+
+    #[test]
+    fn stats() {
+        let input = b"hello world! ".repeat(1000);
+        let mut c = BrotliEncoder::new(&input[..], 6);
+        c.enable_stats();
+        let mut data = Vec::new();
+        c.read_to_end(&mut data).unwrap();
+        let stats = c.stats().unwrap();
+        assert_eq!(stats.total_in(), input.len() as u64);
+        assert_eq!(stats.total_out(), data.len() as u64);
+        assert_eq!(stats.flushes(), 0);
+
+        // Waiting on the underlying reader counts towards the elapsed time.
+        struct Slow<'a>(&'a [u8]);
+
+        impl<'a> Read for Slow<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                thread::sleep(Duration::from_millis(10));
+                self.0.read(buf)
+            }
+        }
+
+        let mut d = BrotliDecoder::new(Slow(&data));
+        assert_eq!(d.stats(), None);
+        d.enable_stats();
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        let stats = d.stats().unwrap();
+        assert_eq!(stats.total_in(), data.len() as u64);
+        assert_eq!(stats.total_out(), input.len() as u64);
+        assert!(stats.elapsed() >= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn unconsumed() {
+        let mut data = Vec::new();
+        BrotliEncoder::new(&b"hello"[..], 6)
+            .read_to_end(&mut data)
+            .unwrap();
+        let len = data.len();
+        data.extend_from_slice(b"trailer");
+
+        let mut d = BrotliDecoder::new(&data[..]);
+        assert!(!d.is_finished());
+        let mut out = [0; 5];
+        d.read_exact(&mut out).unwrap();
+        assert_eq!(d.read(&mut [0; 16]).unwrap(), 0);
+        assert!(d.is_finished());
+        assert_eq!(d.total_in(), len as u64);
+        assert_eq!(d.unconsumed(), b"trailer");
+    }
+
+    #[test]
+    fn copy_to() {
+        let input = thread_rng()
+            .sample_iter(&Standard)
+            .take(300_000)
+            .collect::<Vec<u8>>();
+        let mut data = Vec::new();
+        BrotliEncoder::new(&input[..], 1)
+            .read_to_end(&mut data)
+            .unwrap();
+
+        let mut d = BrotliDecoder::new(&data[..]);
+        let mut first = [0; 1000];
+        d.read_exact(&mut first).unwrap();
+        let mut out = first.to_vec();
+        assert_eq!(d.copy_to(&mut out).unwrap(), 299_000);
+        assert!(d.is_finished());
+        assert!(out == input);
+        assert_eq!(d.copy_to(&mut out).unwrap(), 0);
+
+        let mut d = BrotliDecoder::new(&data[..data.len() - 1]);
+        assert!(d.copy_to(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn tolerant() {
+        let input = thread_rng()
+            .sample_iter(&Standard)
+            .take(100_000)
+            .collect::<Vec<u8>>();
+        let mut data = Vec::new();
+        BrotliEncoder::new(&input[..], 1)
+            .read_to_end(&mut data)
+            .unwrap();
+        let truncated = &data[..data.len() / 2];
+
+        assert!(BrotliDecoder::new(truncated)
+            .read_to_end(&mut Vec::new())
+            .is_err());
+        let mut d = BrotliDecoder::new(truncated);
+        d.set_tolerant(true);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert!(d.is_truncated());
+        assert!(!out.is_empty());
+        assert_eq!(out, &input[..out.len()]);
+    }
+
     #[test]
     fn smoke2() {
         let m: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
@@ -136,7 +1082,7 @@ mod tests {
         let mut d = BrotliDecoder::new(c);
         let mut data = vec![];
         d.read_to_end(&mut data).unwrap();
-        assert!(data == &m[..]);
+        assert!(data == m[..]);
     }
 
     #[test]
@@ -150,18 +1096,15 @@ mod tests {
         let v = thread_rng()
             .sample_iter(&Standard)
             .take(1024)
-            .collect::<Vec<_>>();
+            .collect::<Vec<u8>>();
         for _ in 0..200 {
-            result.extend(v.iter().map(|x: &u8| *x));
+            result.extend(v.iter().copied());
         }
 
         let mut d = BrotliDecoder::new(&result[..]);
-        let mut data = Vec::with_capacity(m.len());
-        unsafe {
-            data.set_len(m.len());
-        }
+        let mut data = vec![0; m.len()];
         assert!(d.read(&mut data).unwrap() == m.len());
-        assert!(data == &m[..]);
+        assert!(data == m[..]);
     }
 
     #[test]
@@ -190,6 +1133,27 @@ mod tests {
         assert!(d.read(&mut data).unwrap() == 0);
     }
 
+    #[test]
+    fn dictionary() {
+        let dict = Dictionary::new(b"a dictionary shared by both ends").unwrap();
+        let m: &[u8] = b"a message compressed against a dictionary";
+        let c = BrotliEncoder::with_dictionary(m, &CompressParams::new(), &dict);
+        let mut d = BrotliDecoder::with_dictionary(c, &dict);
+        let mut data = vec![];
+        d.read_to_end(&mut data).unwrap();
+        assert_eq!(data, m);
+    }
+
+    #[test]
+    fn large_window() {
+        let m = vec![3u8; 128 * 1024 + 1];
+        let c = BrotliEncoder::from_params(&m[..], CompressParams::new().lgwin(30));
+        let mut d = BrotliDecoder::from_params(c, DecompressParams::new().large_window(true));
+        let mut data = vec![];
+        d.read_to_end(&mut data).unwrap();
+        assert!(data == m);
+    }
+
     #[test]
     fn qc() {
         ::quickcheck::quickcheck(test as fn(_) -> _);
@@ -1,62 +1,180 @@
 //! Writer-based compression/decompression streams
 
+use std::cmp;
+use std::fmt;
 use std::io;
 use std::io::prelude::*;
+use std::io::IoSlice;
+use std::mem;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use raw::{self, CoStatus, Compress, CompressOp, DeStatus, Decompress};
+use bufread;
+use raw::{
+    self, CoStatus, Compress, CompressOp, DeStatus, Decompress, Dictionary, ErrorKind,
+    MetadataPolicy,
+};
 
-use super::CompressParams;
+use super::{CompressParams, DecompressParams, Digest, Stats, Summary};
 
 const BUF_SIZE: usize = 32 * 1024;
 
 /// A compression stream which will have uncompressed data written to it and
 /// will write compressed data to an output stream.
+///
+/// If the output stream fails with an error such as
+/// `io::ErrorKind::WouldBlock`, compressed data it did not accept is kept and
+/// written first by the next call, so the failed call can simply be retried.
+/// This makes the encoder usable with non-blocking writers, as long as the
+/// stream is completed with `try_finish`, which can be retried, rather than
+/// `finish`.
 pub struct BrotliEncoder<W: Write> {
     data: Compress,
     obj: Option<W>,
     buf: Vec<u8>,
     cur: usize,
+    buf_size: usize,
     err: Option<raw::Error>,
+    stats: Option<Stats>,
 }
 
 /// A compression stream which will have compressed data written to it and
 /// will write uncompressed data to an output stream.
+///
+/// As with `BrotliEncoder`, decompressed data the output stream did not accept
+/// because of an error such as `io::ErrorKind::WouldBlock` is kept, and any
+/// failed call, including `finish`, can be retried.
 pub struct BrotliDecoder<W: Write> {
     data: Decompress,
     obj: Option<W>,
     buf: Vec<u8>,
     cur: usize,
+    buf_size: usize,
     err: Option<raw::Error>,
+    strict: bool,
+    tolerant: bool,
+    truncated: bool,
+    max_output: Option<u64>,
+    stats: Option<Stats>,
 }
 
 impl<W: Write> BrotliEncoder<W> {
     /// Create a new compression stream which will compress at the given level
     /// to write compress output to the give output stream.
     pub fn new(obj: W, level: u32) -> BrotliEncoder<W> {
-        let mut data = Compress::new();
-        data.set_params(CompressParams::new().quality(level));
-        BrotliEncoder {
-            data: data,
-            obj: Some(obj),
-            buf: Vec::with_capacity(BUF_SIZE),
-            cur: 0,
-            err: None,
-        }
+        BrotliEncoder::with_capacity(BUF_SIZE, obj, level)
+    }
+
+    /// Creates a new encoder like `new`, which buffers up to `capacity` bytes
+    /// of compressed data at a time before writing them to `obj`.
+    pub fn with_capacity(capacity: usize, obj: W, level: u32) -> BrotliEncoder<W> {
+        BrotliEncoder::with_buffer(Vec::with_capacity(capacity), obj, level)
+    }
+
+    /// Creates a new encoder like `new`, which uses `buf` to hold compressed
+    /// data not yet written to `obj`.
+    ///
+    /// `buf` is cleared first, and its capacity determines how much data is
+    /// buffered at a time, falling back to the default if it is zero. Use
+    /// `into_parts` to get the buffer back, for example to return it to a
+    /// pool.
+    pub fn with_buffer(buf: Vec<u8>, obj: W, level: u32) -> BrotliEncoder<W> {
+        BrotliEncoder::build(buf, obj, CompressParams::new().quality(level), None)
     }
 
     /// Creates a new encoder with a custom `CompressParams`.
     pub fn from_params(obj: W, params: &CompressParams) -> BrotliEncoder<W> {
+        BrotliEncoder::build(Vec::with_capacity(BUF_SIZE), obj, params, None)
+    }
+
+    /// Creates a new encoder with a custom `CompressParams` which compresses
+    /// against the prefix dictionary `dict`.
+    ///
+    /// The resulting stream can only be decompressed with the same dictionary.
+    pub fn with_dictionary(obj: W, params: &CompressParams, dict: &Dictionary) -> BrotliEncoder<W> {
+        BrotliEncoder::build(Vec::with_capacity(BUF_SIZE), obj, params, Some(dict))
+    }
+
+    pub(crate) fn build(
+        mut buf: Vec<u8>,
+        obj: W,
+        params: &CompressParams,
+        dict: Option<&Dictionary>,
+    ) -> BrotliEncoder<W> {
         let mut data = Compress::new();
-        data.set_params(params);
+        let err = data
+            .set_params(params)
+            .and_then(|()| dict.map_or(Ok(()), |dict| data.attach_dictionary(dict)))
+            .err();
+        buf.clear();
         BrotliEncoder {
-            data: data,
+            data,
             obj: Some(obj),
-            buf: Vec::with_capacity(BUF_SIZE),
+            buf_size: buffer_size(&buf),
+            buf,
             cur: 0,
-            err: None,
+            err,
+            stats: None,
         }
     }
 
+    /// Replaces the compression parameters of this encoder.
+    ///
+    /// This allows tuning an encoder created elsewhere, for example by generic
+    /// code, but only before compression starts. Once data has been written to
+    /// or flushed through the encoder, this fails with an error of kind
+    /// `io::ErrorKind::InvalidInput` wrapping a `raw::Error` of kind
+    /// `ErrorKind::AlreadyStarted`, and the parameters are left unchanged.
+    pub fn set_params(&mut self, params: &CompressParams) -> io::Result<()> {
+        self.data.set_params(params)?;
+        Ok(())
+    }
+
+    /// Starts collecting `Stats` for this encoder, as returned by `stats`.
+    pub fn enable_stats(&mut self) {
+        self.stats.get_or_insert_with(Stats::default);
+    }
+
+    /// Returns the statistics collected since `enable_stats` was called, or
+    /// `None` if it was not.
+    ///
+    /// The byte counts cover the whole stream, and `reset` starts the
+    /// statistics over.
+    pub fn stats(&self) -> Option<Stats> {
+        Stats::with_totals(self.stats, self.data.total_in(), self.data.total_out())
+    }
+
+    /// Returns the number of uncompressed bytes written to this encoder so far.
+    pub fn total_in(&self) -> u64 {
+        self.data.total_in()
+    }
+
+    /// Returns the number of compressed bytes produced so far, including
+    /// any not yet written to the underlying writer.
+    pub fn total_out(&self) -> u64 {
+        self.data.total_out()
+    }
+
+    /// Returns the number of compressed bytes written to the underlying
+    /// writer so far.
+    ///
+    /// Right after a successful `flush` this is the offset in the compressed
+    /// stream at which decompression can catch up with everything written
+    /// before it.
+    pub fn compressed_position(&self) -> u64 {
+        self.data.total_out() - (self.buf.len() - self.cur) as u64
+    }
+
+    /// Returns the number of compressed bytes held by this encoder that have
+    /// not been written to the underlying writer yet.
+    ///
+    /// This counts both output waiting in the internal buffer and output
+    /// the compressor has ready but not handed over, and is what the next
+    /// flush would have to write at least.
+    pub fn pending(&mut self) -> usize {
+        self.buf.len() - self.cur + self.data.output_len()
+    }
+
     /// Acquires a reference to the underlying writer.
     pub fn get_ref(&self) -> &W {
         self.obj.as_ref().unwrap()
@@ -72,32 +190,38 @@ impl<W: Write> BrotliEncoder<W> {
 
     fn dump(&mut self) -> io::Result<()> {
         loop {
-            while !self.buf.is_empty() {
-                let amt = self.obj.as_mut().unwrap().write(&self.buf[self.cur..])?;
-                self.cur += amt;
-                if self.cur == self.buf.len() {
-                    self.buf.clear();
-                    self.cur = 0
-                }
-            }
-            // TODO: if we could peek, the buffer wouldn't be necessary
-            if let Some(data) = self.data.take_output(Some(BUF_SIZE)) {
-                match self.obj.as_mut().unwrap().write(data) {
-                    Ok(n) => self.buf.extend_from_slice(&data[n..]),
-                    Err(e) => {
-                        self.buf.extend_from_slice(data);
-                        return Err(e);
-                    }
-                }
+            let pending = self.buf.len() - self.cur;
+            let data = if pending < self.buf_size {
+                self.data.take_output(Some(self.buf_size))
             } else {
-                break;
-            }
+                None
+            };
+            let data = match data {
+                Some(data) => data,
+                None if pending > 0 => &[],
+                None => break,
+            };
+            let obj = self.obj.as_mut().unwrap();
+            write_pending(obj, &mut self.buf, &mut self.cur, data)?;
         }
         Ok(())
     }
 
     // Flush or finish stream, also flushing underlying stream
     fn do_flush_or_finish(&mut self, finish: bool) -> io::Result<()> {
+        let start = Stats::start(&self.stats);
+        let result = self.flush_or_finish(finish);
+        Stats::record(&mut self.stats, start);
+        if result.is_ok() && !finish {
+            Stats::count_flush(&mut self.stats);
+        }
+        result
+    }
+
+    fn flush_or_finish(&mut self, finish: bool) -> io::Result<()> {
+        if let Some(ref err) = self.err {
+            return Err(err.clone().into());
+        }
         self.dump()?;
         let op = if finish {
             CompressOp::Finish
@@ -112,10 +236,8 @@ impl<W: Write> BrotliEncoder<W> {
                     return Err(err.into());
                 }
             };
+            self.dump()?;
             let obj = self.obj.as_mut().unwrap();
-            while let Some(data) = self.data.take_output(None) {
-                obj.write_all(data)?;
-            }
             match status {
                 CoStatus::Finished => {
                     obj.flush()?;
@@ -126,6 +248,17 @@ impl<W: Write> BrotliEncoder<W> {
         }
     }
 
+    /// Flushes the compressed stream so that everything written so far can
+    /// be decoded from the data written to the output stream, then flushes
+    /// the output stream.
+    ///
+    /// The compressed data written so far then ends on a byte boundary, so
+    /// network protocols can use this to frame messages. This is what
+    /// `Write::flush` does as well.
+    pub fn sync_flush(&mut self) -> io::Result<()> {
+        self.do_flush_or_finish(false)
+    }
+
     /// Consumes this encoder, flushing the output stream.
     ///
     /// This will flush the underlying data stream and then return the contained
@@ -134,10 +267,217 @@ impl<W: Write> BrotliEncoder<W> {
         self.do_flush_or_finish(true)?;
         Ok(self.obj.take().unwrap())
     }
+
+    /// Writes `metadata` to the stream as a metadata block, between the data
+    /// written before and after it.
+    ///
+    /// Metadata blocks carry opaque out-of-band data that decoders skip
+    /// unless configured otherwise, so the decompressed output is unchanged.
+    /// Data written earlier is flushed first.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `io::ErrorKind::InvalidInput` if `metadata`
+    /// is longer than `raw::MAX_METADATA_LEN`, or an error if the block
+    /// cannot be compressed or written out.
+    pub fn write_metadata(&mut self, metadata: &[u8]) -> io::Result<()> {
+        if let Some(ref err) = self.err {
+            return Err(err.clone().into());
+        }
+        self.dump()?;
+        if metadata.len() > raw::MAX_METADATA_LEN {
+            let msg = "metadata block is too long";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        if let Err(err) = self.data.emit_metadata(metadata, &mut self.buf) {
+            self.err = Some(err.clone());
+            return Err(err.into());
+        }
+        self.dump()
+    }
+
+    /// Consumes this encoder like `finish`, also returning how much data it
+    /// compressed.
+    pub fn finish_with_summary(mut self) -> io::Result<(W, Summary)> {
+        self.do_flush_or_finish(true)?;
+        let summary = Summary::new(self.data.total_in(), self.data.total_out());
+        Ok((self.obj.take().unwrap(), summary))
+    }
+
+    /// Finishes the compressed stream and flushes the output stream, without
+    /// consuming this encoder.
+    ///
+    /// Unlike `finish`, the writer can still be recovered with `into_inner`
+    /// if this fails.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        self.do_flush_or_finish(true)
+    }
+
+    /// Consumes this encoder, returning the underlying writer without
+    /// finishing the stream.
+    ///
+    /// This works even after an error. Unless `try_finish` succeeded first,
+    /// the data written to the writer is not a complete brotli stream, and
+    /// compressed data not yet written to it is discarded.
+    pub fn into_inner(mut self) -> W {
+        self.obj.take().unwrap()
+    }
+
+    /// Consumes this encoder like `into_inner`, also returning its internal
+    /// buffer, emptied.
+    pub fn into_parts(mut self) -> (W, Vec<u8>) {
+        let mut buf = mem::take(&mut self.buf);
+        buf.clear();
+        (self.obj.take().unwrap(), buf)
+    }
+
+    /// Finishes the current stream and resets this encoder to write a new
+    /// one to `w`, returning the previous writer.
+    ///
+    /// The parameters and dictionary it was created with are kept.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, leaving this encoder unchanged, if the current
+    /// stream cannot be finished.
+    pub fn reset(&mut self, w: W) -> io::Result<W> {
+        self.try_finish()?;
+        self.err = self.data.reset().err();
+        if self.stats.is_some() {
+            self.stats = Some(Stats::default());
+        }
+        Ok(mem::replace(self.obj.as_mut().unwrap(), w))
+    }
+
+    /// Wraps this encoder in an `AutoFinishEncoder`, which finishes the
+    /// stream when dropped and passes the result, including the writer on
+    /// success, to `f`.
+    pub fn on_finish<F: FnMut(io::Result<W>)>(self, f: F) -> AutoFinishEncoder<W, F> {
+        AutoFinishEncoder {
+            encoder: Some(self),
+            on_finish: f,
+        }
+    }
 }
 
-impl<W: Write> Write for BrotliEncoder<W> {
-    fn write(&mut self, mut data: &[u8]) -> io::Result<usize> {
+/// A `BrotliEncoder` which finishes its stream when dropped, created with
+/// `BrotliEncoder::on_finish`.
+///
+/// A plain `BrotliEncoder` also finishes its stream when dropped, but any
+/// failure to do so goes unnoticed and leaves a truncated stream behind.
+/// This reports the outcome to a callback instead.
+///
+/// # Examples
+///
+/// ```
+/// use std::io::prelude::*;
+/// use brotli2::write::BrotliEncoder;
+///
+/// let mut encoder = BrotliEncoder::new(Vec::new(), 6).on_finish(|result| {
+///     if let Err(e) = result {
+///         eprintln!("failed to finish the brotli stream: {}", e);
+///     }
+/// });
+/// encoder.write_all(b"hello").unwrap();
+/// ```
+pub struct AutoFinishEncoder<
+    W: Write,
+    F: FnMut(io::Result<W>) = Box<dyn FnMut(io::Result<W>) + Send>,
+> {
+    encoder: Option<BrotliEncoder<W>>,
+    on_finish: F,
+}
+
+impl<W: Write, F: FnMut(io::Result<W>)> AutoFinishEncoder<W, F> {
+    /// Acquires a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        self.encoder.as_ref().unwrap().get_ref()
+    }
+
+    /// Acquires a mutable reference to the underlying writer.
+    ///
+    /// Note that mutating the output/input state of the stream may corrupt
+    /// this object, so care must be taken when using this method.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.encoder.as_mut().unwrap().get_mut()
+    }
+}
+
+impl<W: Write, F: FnMut(io::Result<W>)> Write for AutoFinishEncoder<W, F> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.encoder.as_mut().unwrap().write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write, F: FnMut(io::Result<W>)> Drop for AutoFinishEncoder<W, F> {
+    fn drop(&mut self) {
+        let result = self.encoder.take().unwrap().finish();
+        (self.on_finish)(result);
+    }
+}
+
+// Writes the data buffered from `cur` onwards, followed by `data`, with a
+// single vectored write, and buffers whatever part of `data` is left.
+//
+// Nothing is ever dropped: whatever the writer does not accept, including on
+// errors, stays buffered to be written by a later call.
+fn write_pending<W: Write>(
+    obj: &mut W,
+    buf: &mut Vec<u8>,
+    cur: &mut usize,
+    data: &[u8],
+) -> io::Result<()> {
+    let pending = buf.len() - *cur;
+    let bufs = [IoSlice::new(&buf[*cur..]), IoSlice::new(data)];
+    match obj.write_vectored(&bufs) {
+        Ok(0) => {
+            buf.extend_from_slice(data);
+            let msg = "failed to write brotli stream data";
+            return Err(io::Error::new(io::ErrorKind::WriteZero, msg));
+        }
+        // Retried by the caller's loop, like `write_all` does.
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => buf.extend_from_slice(data),
+        Ok(n) if n < pending => {
+            *cur += n;
+            buf.extend_from_slice(data);
+        }
+        Ok(n) => {
+            buf.clear();
+            *cur = 0;
+            buf.extend_from_slice(&data[n - pending..]);
+        }
+        Err(e) => {
+            buf.extend_from_slice(data);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+fn buffer_size(buf: &Vec<u8>) -> usize {
+    match buf.capacity() {
+        0 => BUF_SIZE,
+        n => n,
+    }
+}
+
+impl<W: Write + fmt::Debug> fmt::Debug for BrotliEncoder<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BrotliEncoder")
+            .field("obj", &self.obj)
+            .field("data", &self.data)
+            .field("buffered", &(self.buf.len() - self.cur))
+            .field("err", &self.err)
+            .finish()
+    }
+}
+
+impl<W: Write> BrotliEncoder<W> {
+    fn write_input(&mut self, mut data: &[u8]) -> io::Result<usize> {
         if data.is_empty() {
             return Ok(0);
         }
@@ -161,6 +501,49 @@ impl<W: Write> Write for BrotliEncoder<W> {
         Ok(avail_in - data.len())
     }
 
+    fn write_input_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        if let Some(ref err) = self.err {
+            return Err(err.clone().into());
+        }
+        self.dump()?;
+        let mut written = 0;
+        for buf in bufs {
+            let mut data = &buf[..];
+            if let Err(err) = self
+                .data
+                .compress(CompressOp::Process, &mut data, &mut &mut [][..])
+            {
+                self.err = Some(err.clone());
+                if written == 0 {
+                    return Err(err.into());
+                }
+                break;
+            }
+            written += buf.len() - data.len();
+            // The encoder only stops taking input once it has output ready.
+            if !data.is_empty() {
+                break;
+            }
+        }
+        Ok(written)
+    }
+}
+
+impl<W: Write> Write for BrotliEncoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let start = Stats::start(&self.stats);
+        let result = self.write_input(data);
+        Stats::record(&mut self.stats, start);
+        result
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        let start = Stats::start(&self.stats);
+        let result = self.write_input_vectored(bufs);
+        Stats::record(&mut self.stats, start);
+        result
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         self.do_flush_or_finish(false)
     }
@@ -178,15 +561,142 @@ impl<W: Write> BrotliDecoder<W> {
     /// Creates a new decoding stream which will decode all input written to it
     /// into `obj`.
     pub fn new(obj: W) -> BrotliDecoder<W> {
+        BrotliDecoder::with_capacity(BUF_SIZE, obj)
+    }
+
+    /// Creates a new decoder like `new`, which buffers up to `capacity` bytes
+    /// of decompressed data at a time before writing them to `obj`.
+    pub fn with_capacity(capacity: usize, obj: W) -> BrotliDecoder<W> {
+        BrotliDecoder::with_buffer(Vec::with_capacity(capacity), obj)
+    }
+
+    /// Creates a new decoder like `new`, which uses `buf` to hold decompressed
+    /// data not yet written to `obj`.
+    ///
+    /// `buf` is cleared first, and its capacity determines how much data is
+    /// buffered at a time, falling back to the default if it is zero. Use
+    /// `finish_into_parts` to get the buffer back, for example to return it
+    /// to a pool.
+    pub fn with_buffer(buf: Vec<u8>, obj: W) -> BrotliDecoder<W> {
+        BrotliDecoder::build(buf, obj, &DecompressParams::new(), None)
+    }
+
+    /// Creates a new decoder with a custom `DecompressParams`.
+    pub fn from_params(obj: W, params: &DecompressParams) -> BrotliDecoder<W> {
+        BrotliDecoder::build(Vec::with_capacity(BUF_SIZE), obj, params, None)
+    }
+
+    /// Creates a new decoding stream which will decode all input written to
+    /// it into `obj`, where the input was compressed against the prefix
+    /// dictionary `dict`.
+    pub fn with_dictionary(obj: W, dict: &Dictionary) -> BrotliDecoder<W> {
+        let params = DecompressParams::new();
+        BrotliDecoder::build(Vec::with_capacity(BUF_SIZE), obj, &params, Some(dict))
+    }
+
+    pub(crate) fn build(
+        mut buf: Vec<u8>,
+        obj: W,
+        params: &DecompressParams,
+        dict: Option<&Dictionary>,
+    ) -> BrotliDecoder<W> {
+        let (data, err) = bufread::new_decompress(params, dict);
+        buf.clear();
         BrotliDecoder {
-            data: Decompress::new(),
+            data,
             obj: Some(obj),
-            buf: Vec::with_capacity(BUF_SIZE),
+            buf_size: buffer_size(&buf),
+            buf,
             cur: 0,
-            err: None,
+            err,
+            strict: false,
+            tolerant: false,
+            truncated: false,
+            max_output: None,
+            stats: None,
         }
     }
 
+    /// Sets the maximum number of bytes this decoder may decompress.
+    ///
+    /// Once a stream would decompress to more than `limit` bytes, writing or
+    /// finishing fails with an error of kind `io::ErrorKind::InvalidData`
+    /// wrapping a `raw::Error` of kind `ErrorKind::LimitExceeded`, and the data
+    /// past the limit is never written out. This protects against small inputs
+    /// that expand to huge amounts of data. `None`, the default, removes the
+    /// limit.
+    pub fn set_max_output(&mut self, limit: Option<u64>) {
+        self.max_output = limit;
+    }
+
+    /// Sets whether data following the end of the brotli stream is an
+    /// error.
+    ///
+    /// By default such data is not accepted by `write`, which returns
+    /// `Ok(0)`. In strict mode, writing it fails with an error of kind
+    /// `io::ErrorKind::InvalidData` instead.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Sets whether a stream that ends early is decoded as far as possible.
+    ///
+    /// By default, `finish` fails with an error if the data written so far
+    /// ends in the middle of the brotli stream. In tolerant mode, it instead
+    /// writes out the data decoded up to that point and succeeds, and
+    /// `is_truncated` returns `true`.
+    pub fn set_tolerant(&mut self, tolerant: bool) {
+        self.tolerant = tolerant;
+    }
+
+    /// Returns whether the brotli stream was found to be truncated, which
+    /// is only reported in tolerant mode.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Returns whether the end of the brotli stream has been reached.
+    ///
+    /// The decoder never consumes input past the end of the stream: `write`
+    /// then reports how much of its input belonged to the stream, and
+    /// `total_in` is the length of the compressed stream. Decompressed data
+    /// may still be buffered until the next `write`, `flush` or `finish`.
+    pub fn is_finished(&self) -> bool {
+        self.data.is_finished()
+    }
+
+    /// Sets how metadata blocks in the compressed stream are handled.
+    ///
+    /// By default they are skipped. See `MetadataPolicy` for the choices.
+    pub fn set_metadata_policy(&mut self, policy: MetadataPolicy) {
+        self.data.set_metadata_policy(policy);
+    }
+
+    /// Starts collecting `Stats` for this decoder, as returned by `stats`.
+    pub fn enable_stats(&mut self) {
+        self.stats.get_or_insert_with(Stats::default);
+    }
+
+    /// Returns the statistics collected since `enable_stats` was called, or
+    /// `None` if it was not.
+    ///
+    /// The byte counts cover the whole stream, and `reset` starts the
+    /// statistics over.
+    pub fn stats(&self) -> Option<Stats> {
+        Stats::with_totals(self.stats, self.data.total_in(), self.data.total_out())
+    }
+
+    /// Returns the number of compressed bytes written to this decoder so far.
+    pub fn total_in(&self) -> u64 {
+        self.data.total_in()
+    }
+
+    /// Returns the number of decompressed bytes produced so far, including
+    /// any not yet written to the underlying writer.
+    pub fn total_out(&self) -> u64 {
+        self.data.total_out()
+    }
+
     /// Acquires a reference to the underlying writer.
     pub fn get_ref(&self) -> &W {
         self.obj.as_ref().unwrap()
@@ -201,26 +711,52 @@ impl<W: Write> BrotliDecoder<W> {
     }
 
     fn dump(&mut self) -> io::Result<()> {
+        // Output past a limit that was hit has already been taken from the
+        // decompressor, so it must not be asked for more.
+        if let Some(ref err) = self.err {
+            return Err(err.clone().into());
+        }
         loop {
-            while !self.buf.is_empty() {
-                let amt = self.obj.as_mut().unwrap().write(&self.buf[self.cur..])?;
-                self.cur += amt;
-                if self.cur == self.buf.len() {
-                    self.buf.clear();
-                    self.cur = 0
-                }
-            }
-            // TODO: if we could peek, the buffer wouldn't be necessary
-            if let Some(data) = self.data.take_output(Some(BUF_SIZE)) {
-                self.buf.extend_from_slice(data)
+            let pending = self.buf.len() - self.cur;
+            let remaining = self
+                .max_output
+                .map(|max| max.saturating_sub(self.data.total_out()));
+            let size = match remaining {
+                Some(remaining) => cmp::min(self.buf_size as u64, remaining + 1) as usize,
+                None => self.buf_size,
+            };
+            let data = if pending < self.buf_size {
+                self.data.take_output(Some(size))
             } else {
-                break;
+                None
+            };
+            let data = match data {
+                Some(data) => data,
+                None if pending > 0 => &[],
+                None => break,
+            };
+            if remaining.map_or(false, |remaining| data.len() as u64 > remaining) {
+                let err = raw::Error::new(ErrorKind::LimitExceeded, "decompressing");
+                self.err = Some(err.clone());
+                return Err(err.into());
             }
+            let obj = self.obj.as_mut().unwrap();
+            write_pending(obj, &mut self.buf, &mut self.cur, data)?;
         }
         Ok(())
     }
 
     fn do_finish(&mut self) -> io::Result<()> {
+        let start = Stats::start(&self.stats);
+        let result = self.finish_stream();
+        Stats::record(&mut self.stats, start);
+        result
+    }
+
+    fn finish_stream(&mut self) -> io::Result<()> {
+        if let Some(ref err) = self.err {
+            return Err(err.clone().into());
+        }
         self.dump()?;
         loop {
             let status = match self.data.decompress(&mut &[][..], &mut &mut [][..]) {
@@ -230,10 +766,8 @@ impl<W: Write> BrotliDecoder<W> {
                     return Err(err.into());
                 }
             };
+            self.dump()?;
             let obj = self.obj.as_mut().unwrap();
-            while let Some(data) = self.data.take_output(None) {
-                obj.write_all(data)?;
-            }
             match status {
                 DeStatus::Finished => {
                     obj.flush()?;
@@ -242,6 +776,11 @@ impl<W: Write> BrotliDecoder<W> {
                 // When decoding a truncated file, brotli returns DeStatus::NeedInput.
                 // Since we're finishing, we cannot provide more data so this is an
                 // error.
+                DeStatus::NeedInput if self.tolerant => {
+                    self.truncated = true;
+                    obj.flush()?;
+                    return Ok(());
+                }
                 DeStatus::NeedInput => {
                     let msg = "brotli compressed stream is truncated or otherwise corrupt";
                     return Err(io::Error::new(io::ErrorKind::UnexpectedEof, msg));
@@ -251,15 +790,62 @@ impl<W: Write> BrotliDecoder<W> {
         }
     }
 
+    /// Finishes the current stream and resets this decoder to write the
+    /// data decoded from a new one to `w`, returning the previous writer.
+    ///
+    /// The parameters and dictionary it was created with are kept.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, leaving this decoder unchanged, if the current
+    /// stream is incomplete or its decoded data cannot be written out.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `finish` has been called.
+    pub fn reset(&mut self, w: W) -> io::Result<W> {
+        self.do_finish()?;
+        self.err = self.data.reset().err();
+        self.truncated = false;
+        if self.stats.is_some() {
+            self.stats = Some(Stats::default());
+        }
+        Ok(mem::replace(self.obj.as_mut().unwrap(), w))
+    }
+
     /// Unwrap the underlying writer, finishing the compression stream.
     pub fn finish(&mut self) -> io::Result<W> {
         self.do_finish()?;
         Ok(self.obj.take().unwrap())
     }
+
+    /// Finishes the stream like `finish`, returning the underlying writer
+    /// along with the internal buffer, emptied.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `finish` has been called.
+    pub fn finish_into_parts(mut self) -> io::Result<(W, Vec<u8>)> {
+        let obj = self.finish()?;
+        let mut buf = mem::take(&mut self.buf);
+        buf.clear();
+        Ok((obj, buf))
+    }
 }
 
-impl<W: Write> Write for BrotliDecoder<W> {
-    fn write(&mut self, mut data: &[u8]) -> io::Result<usize> {
+impl<W: Write + fmt::Debug> fmt::Debug for BrotliDecoder<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BrotliDecoder")
+            .field("obj", &self.obj)
+            .field("data", &self.data)
+            .field("buffered", &(self.buf.len() - self.cur))
+            .field("err", &self.err)
+            .finish()
+    }
+}
+
+impl<W: Write> BrotliDecoder<W> {
+    fn write_input(&mut self, mut data: &[u8]) -> io::Result<usize> {
         if data.is_empty() {
             return Ok(0);
         }
@@ -272,20 +858,93 @@ impl<W: Write> Write for BrotliDecoder<W> {
         self.dump()?;
         // Zero-length output buf to keep it all inside the decompressor buffer
         let avail_in = data.len();
-        let status = match self.data.decompress(&mut data, &mut &mut [][..]) {
-            Ok(s) => s,
-            Err(err) => {
-                self.err = Some(err.clone());
-                return Err(err.into());
+        loop {
+            let status = match self.data.decompress(&mut data, &mut &mut [][..]) {
+                Ok(s) => s,
+                Err(err) => {
+                    self.err = Some(err.clone());
+                    return Err(err.into());
+                }
+            };
+            // Highly compressible input can fill the decompressor's buffer
+            // before any of it is consumed.
+            if avail_in != data.len() || status != DeStatus::NeedOutput {
+                break;
             }
-        };
-        assert!(avail_in != data.len() || status == DeStatus::Finished);
+            self.dump()?;
+        }
+        if avail_in == data.len() && self.strict {
+            return Err(bufread::trailing_data());
+        }
         Ok(avail_in - data.len())
     }
 
-    fn flush(&mut self) -> io::Result<()> {
+    fn write_input_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        if let Some(ref err) = self.err {
+            return Err(err.clone().into());
+        }
         self.dump()?;
-        self.obj.as_mut().unwrap().flush()
+        let mut written = 0;
+        for buf in bufs {
+            let mut data = &buf[..];
+            let status = loop {
+                let status = match self.data.decompress(&mut data, &mut &mut [][..]) {
+                    Ok(s) => s,
+                    Err(err) => {
+                        self.err = Some(err.clone());
+                        if written == 0 {
+                            return Err(err.into());
+                        }
+                        return Ok(written);
+                    }
+                };
+                // As in `write`, make room when nothing at all was consumed.
+                if written != 0 || data.len() != buf.len() || status != DeStatus::NeedOutput {
+                    break status;
+                }
+                self.dump()?;
+            };
+            written += buf.len() - data.len();
+            if status == DeStatus::Finished && !data.is_empty() {
+                if written == 0 && self.strict {
+                    return Err(bufread::trailing_data());
+                }
+                break;
+            }
+            // The decoder only stops taking input once it has output ready.
+            if !data.is_empty() {
+                break;
+            }
+        }
+        Ok(written)
+    }
+}
+
+impl<W: Write> Write for BrotliDecoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let start = Stats::start(&self.stats);
+        let result = self.write_input(data);
+        Stats::record(&mut self.stats, start);
+        result
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        let start = Stats::start(&self.stats);
+        let result = self.write_input_vectored(bufs);
+        Stats::record(&mut self.stats, start);
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let start = Stats::start(&self.stats);
+        let result = self
+            .dump()
+            .and_then(|()| self.obj.as_mut().unwrap().flush());
+        Stats::record(&mut self.stats, start);
+        if result.is_ok() {
+            Stats::count_flush(&mut self.stats);
+        }
+        result
     }
 }
 
@@ -297,18 +956,1371 @@ impl<W: Write> Drop for BrotliDecoder<W> {
     }
 }
 
+/// A decompression stream which decodes any number of brotli streams written
+/// to it one after the other, writing all their decompressed data to an
+/// output stream.
+///
+/// See `bufread::MultiBrotliDecoder` for when this is appropriate.
+#[derive(Debug)]
+pub struct MultiBrotliDecoder<W: Write> {
+    inner: BrotliDecoder<W>,
+}
+
+impl<W: Write> MultiBrotliDecoder<W> {
+    /// Creates a new decoding stream which will decode all input written to
+    /// it into `obj`.
+    pub fn new(obj: W) -> MultiBrotliDecoder<W> {
+        MultiBrotliDecoder {
+            inner: BrotliDecoder::new(obj),
+        }
+    }
+
+    /// Creates a new decoding stream with a custom `DecompressParams`.
+    pub fn from_params(obj: W, params: &DecompressParams) -> MultiBrotliDecoder<W> {
+        MultiBrotliDecoder {
+            inner: BrotliDecoder::from_params(obj, params),
+        }
+    }
+
+    /// Creates a new decoding stream which will decode all input written to
+    /// it into `obj`, where every stream was compressed against the prefix
+    /// dictionary `dict`.
+    pub fn with_dictionary(obj: W, dict: &Dictionary) -> MultiBrotliDecoder<W> {
+        MultiBrotliDecoder {
+            inner: BrotliDecoder::with_dictionary(obj, dict),
+        }
+    }
+
+    /// Acquires a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        self.inner.get_ref()
+    }
+
+    /// Acquires a mutable reference to the underlying writer.
+    ///
+    /// Note that mutating the output/input state of the stream may corrupt this
+    /// object, so care must be taken when using this method.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.get_mut()
+    }
+
+    /// Unwrap the underlying writer, finishing the last compression stream.
+    pub fn finish(&mut self) -> io::Result<W> {
+        self.inner.finish()
+    }
+}
+
+impl<W: Write> Write for MultiBrotliDecoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        loop {
+            let n = self.inner.write(data)?;
+            if n > 0 || data.is_empty() {
+                return Ok(n);
+            }
+            // The current stream has ended, so write out the rest of its
+            // data and start decoding the next one.
+            self.inner.dump()?;
+            self.inner.err = self.inner.data.reset().err();
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A writer which limits the rate at which data is written to an output
+/// stream, blocking as needed.
+///
+/// Placed between a `BrotliEncoder` and its output stream, this bounds the
+/// compressed data sent per second, for example to avoid saturating a network
+/// link. Up to one second's worth of data can be written in a burst after the
+/// stream has been idle.
+///
+/// # Examples
+///
+/// ```
+/// use std::io::prelude::*;
+/// use brotli2::write::{BrotliEncoder, RateLimited};
+///
+/// let mut encoder = BrotliEncoder::new(RateLimited::new(Vec::new(), 1 << 20), 6);
+/// encoder.write_all(b"Hello, World!").unwrap();
+/// let compressed = encoder.finish().unwrap().into_inner();
+/// assert!(!compressed.is_empty());
+/// ```
+#[derive(Debug)]
+pub struct RateLimited<W: Write> {
+    obj: W,
+    rate: u64,
+    budget: f64,
+    last: Instant,
+}
+
+impl<W: Write> RateLimited<W> {
+    /// Creates a writer which writes at most `bytes_per_sec` bytes per second
+    /// to `obj`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes_per_sec` is zero.
+    pub fn new(obj: W, bytes_per_sec: u64) -> RateLimited<W> {
+        assert!(bytes_per_sec > 0, "rate must be positive");
+        RateLimited {
+            obj,
+            rate: bytes_per_sec,
+            budget: bytes_per_sec as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Changes the rate limit to `bytes_per_sec` bytes per second.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes_per_sec` is zero.
+    pub fn set_rate(&mut self, bytes_per_sec: u64) {
+        assert!(bytes_per_sec > 0, "rate must be positive");
+        self.refill();
+        self.rate = bytes_per_sec;
+        self.budget = self.budget.min(bytes_per_sec as f64);
+    }
+
+    /// Returns the current rate limit in bytes per second.
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Acquires a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.obj
+    }
+
+    /// Acquires a mutable reference to the underlying writer.
+    ///
+    /// Data written directly to it does not count towards the limit.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.obj
+    }
+
+    /// Consumes this writer, returning the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.obj
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.budget = (self.budget + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last = now;
+    }
+}
+
+impl<W: Write> Write for RateLimited<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        self.refill();
+        if self.budget < 1.0 {
+            // Wait until a reasonable chunk can be written, rather than a
+            // single byte at a time.
+            let wanted = cmp::min(data.len() as u64, cmp::max(self.rate / 100, 1)) as f64;
+            thread::sleep(Duration::from_secs_f64(
+                (wanted - self.budget) / self.rate as f64,
+            ));
+            self.refill();
+        }
+        let len = cmp::min(data.len(), cmp::max(self.budget as usize, 1));
+        let n = self.obj.write(&data[..len])?;
+        self.budget -= n as f64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.obj.flush()
+    }
+}
+
+/// A writer which computes a `Digest` over the data written through it.
+///
+/// Wrapping a `BrotliEncoder` in it digests the uncompressed data, while
+/// giving it to an encoder as its output stream digests the compressed data,
+/// as is usually needed for archive formats. Decoders work the same way.
+///
+/// # Examples
+///
+/// ```
+/// use std::io::prelude::*;
+/// use brotli2::write::{BrotliEncoder, DigestWriter};
+/// use brotli2::Digest;
+///
+/// // A simple checksum, for illustration.
+/// struct Sum(u32);
+///
+/// impl Digest for Sum {
+///     type Output = u32;
+///
+///     fn update(&mut self, data: &[u8]) {
+///         for &b in data {
+///             self.0 = self.0.wrapping_add(b as u32);
+///         }
+///     }
+///
+///     fn finalize(self) -> u32 {
+///         self.0
+///     }
+/// }
+///
+/// // Digest the compressed data.
+/// let mut encoder = BrotliEncoder::new(DigestWriter::new(Vec::new(), Sum(0)), 6);
+/// encoder.write_all(b"Hello, World!").unwrap();
+/// let (compressed, sum) = encoder.finish().unwrap().finish();
+/// assert_eq!(sum, compressed.iter().map(|&b| b as u32).sum::<u32>());
+///
+/// // Digest the uncompressed data.
+/// let mut writer = DigestWriter::new(BrotliEncoder::new(Vec::new(), 6), Sum(0));
+/// writer.write_all(b"Hello, World!").unwrap();
+/// let (encoder, sum) = writer.finish();
+/// assert_eq!(sum, 1129);
+/// assert!(!encoder.finish().unwrap().is_empty());
+/// ```
+#[derive(Debug)]
+pub struct DigestWriter<W: Write, D: Digest> {
+    obj: W,
+    digest: D,
+}
+
+impl<W: Write, D: Digest> DigestWriter<W, D> {
+    /// Creates a writer which writes to `obj`, adding all data it accepts to
+    /// `digest`.
+    pub fn new(obj: W, digest: D) -> DigestWriter<W, D> {
+        DigestWriter { obj, digest }
+    }
+
+    /// Acquires a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.obj
+    }
+
+    /// Acquires a mutable reference to the underlying writer.
+    ///
+    /// Data written directly to it is not digested.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.obj
+    }
+
+    /// Returns the digest of the data written so far, before finalizing.
+    pub fn digest(&self) -> &D {
+        &self.digest
+    }
+
+    /// Consumes this writer, returning the underlying writer and the finalized
+    /// digest of all data written through it.
+    pub fn finish(self) -> (W, D::Output) {
+        (self.obj, self.digest.finalize())
+    }
+}
+
+impl<W: Write, D: Digest> Write for DigestWriter<W, D> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = self.obj.write(data)?;
+        self.digest.update(&data[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.obj.flush()
+    }
+}
+
+/// A decompression stream like `BrotliDecoder` which checks that the
+/// decompressed data has an expected length and digest.
+///
+/// The data is verified as it is decompressed, in a single pass, and `finish`
+/// fails if it does not match. Decompressed data is still written to the
+/// output stream before then, so it should not be trusted until `finish`
+/// succeeds.
+pub struct VerifyingDecoder<W: Write, D: Digest> {
+    inner: BrotliDecoder<DigestWriter<W, D>>,
+    expected: D::Output,
+    len: u64,
+}
+
+impl<W: Write, D: Digest> VerifyingDecoder<W, D>
+where
+    D::Output: PartialEq,
+{
+    /// Creates a new decoding stream which will decode all input written to
+    /// it into `obj`, expecting `len` bytes of data whose digest, computed
+    /// with `digest`, is `expected`.
+    pub fn new(obj: W, digest: D, expected: D::Output, len: u64) -> VerifyingDecoder<W, D> {
+        let mut inner = BrotliDecoder::new(DigestWriter::new(obj, digest));
+        inner.set_max_output(Some(len));
+        VerifyingDecoder {
+            inner,
+            expected,
+            len,
+        }
+    }
+
+    /// Acquires a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        self.inner.get_ref().get_ref()
+    }
+
+    /// Acquires a mutable reference to the underlying writer.
+    ///
+    /// Note that mutating the output/input state of the stream may corrupt this
+    /// object, so care must be taken when using this method.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.get_mut().get_mut()
+    }
+
+    /// Finishes the stream and verifies the decompressed data, returning the
+    /// underlying writer if it matches.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `io::ErrorKind::InvalidData` if the length or
+    /// digest of the decompressed data differ from the expected ones, or any
+    /// error from finishing the stream.
+    pub fn finish(mut self) -> io::Result<W> {
+        let obj = self.inner.finish()?;
+        if self.inner.total_out() != self.len {
+            let msg = "decompressed data is shorter than expected";
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        let (obj, digest) = obj.finish();
+        if digest != self.expected {
+            let msg = "decompressed data does not match the expected digest";
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        Ok(obj)
+    }
+}
+
+impl<W: Write + fmt::Debug, D: Digest + fmt::Debug> fmt::Debug for VerifyingDecoder<W, D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VerifyingDecoder")
+            .field("inner", &self.inner)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl<W: Write, D: Digest> Write for VerifyingDecoder<W, D>
+where
+    D::Output: PartialEq,
+{
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.inner.write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A compression stream which splits its output into separate brotli streams,
+/// each written to a new writer, keeping the compressed size of every stream
+/// within a limit.
+///
+/// Writers are created on demand by calling `factory` with the index of the
+/// segment, starting at 0, and every segment is a complete brotli stream which
+/// can be decompressed on its own. The current segment is only finished when
+/// more data is written after it filled up, so no empty segment is ever
+/// created. Finished writers are dropped.
+///
+/// To keep segments bounded, each `write` accepts at most as much input as
+/// the segment has room left for, and the stream is flushed whenever the input
+/// written since the last flush could fill it. A segment is finished once it
+/// is within a sixteenth of the limit. Segments can still exceed the limit by
+/// the few bytes of framing brotli adds to a block and to the end of a stream.
+///
+/// # Examples
+///
+/// ```
+/// use std::io::prelude::*;
+/// use brotli2::write::RollingEncoder;
+/// use brotli2::CompressParams;
+///
+/// let dir = std::env::temp_dir();
+/// let path = |index| dir.join(format!("rolling-{}.{}.br", std::process::id(), index));
+/// let factory = |index| std::fs::File::create(path(index)).unwrap();
+/// let mut encoder = RollingEncoder::new(factory, 1 << 20, &CompressParams::new());
+/// encoder.write_all(b"Hello, World!").unwrap();
+/// assert_eq!(encoder.finish().unwrap(), 1);
+/// # std::fs::remove_file(path(0)).unwrap();
+/// ```
+pub struct RollingEncoder<W: Write, F: FnMut(u64) -> W> {
+    inner: Option<BrotliEncoder<W>>,
+    factory: F,
+    params: CompressParams,
+    limit: u64,
+    segments: u64,
+    // Input written to the current segment since it was last flushed.
+    unflushed: u64,
+}
+
+impl<W: Write, F: FnMut(u64) -> W> RollingEncoder<W, F> {
+    /// Creates a new encoder which compresses with `params` and starts a new
+    /// segment after one has reached `limit` compressed bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is zero.
+    pub fn new(factory: F, limit: u64, params: &CompressParams) -> RollingEncoder<W, F> {
+        assert!(limit > 0, "segment limit must be positive");
+        RollingEncoder {
+            inner: None,
+            factory,
+            params: params.clone(),
+            limit,
+            segments: 0,
+            unflushed: 0,
+        }
+    }
+
+    /// Returns the number of segments started so far.
+    pub fn segments(&self) -> u64 {
+        self.segments
+    }
+
+    /// Acquires a reference to the writer of the current segment, if one has
+    /// been started.
+    pub fn get_ref(&self) -> Option<&W> {
+        self.inner.as_ref().map(|inner| inner.get_ref())
+    }
+
+    /// Finishes the current segment, returning the number of segments
+    /// written.
+    pub fn finish(mut self) -> io::Result<u64> {
+        if let Some(mut inner) = self.inner.take() {
+            inner.try_finish()?;
+        }
+        Ok(self.segments)
+    }
+
+    /// Returns the encoder for the current segment, finishing it first and
+    /// starting a new one if it is full, along with how much input it can
+    /// take without possibly going over the limit.
+    fn current(&mut self) -> io::Result<(&mut BrotliEncoder<W>, u64)> {
+        let full = match self.inner {
+            Some(ref mut inner) => {
+                // Compressed data never grows by more than the framing, so
+                // the input since the last flush bounds its output.
+                if self.unflushed >= self.limit.saturating_sub(inner.total_out()) {
+                    inner.flush()?;
+                    self.unflushed = 0;
+                }
+                let room = self.limit.saturating_sub(inner.total_out());
+                if self.unflushed == 0 && room <= self.limit / 16 {
+                    inner.try_finish()?;
+                    true
+                } else {
+                    false
+                }
+            }
+            None => false,
+        };
+        if full {
+            self.inner = None;
+        }
+        if self.inner.is_none() {
+            let obj = (self.factory)(self.segments);
+            self.segments += 1;
+            self.inner = Some(BrotliEncoder::from_params(obj, &self.params));
+        }
+        let inner = self.inner.as_mut().unwrap();
+        let room = self.limit.saturating_sub(inner.total_out()) - self.unflushed;
+        Ok((inner, room))
+    }
+}
+
+impl<W: Write + fmt::Debug, F: FnMut(u64) -> W> fmt::Debug for RollingEncoder<W, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RollingEncoder")
+            .field("inner", &self.inner)
+            .field("limit", &self.limit)
+            .field("segments", &self.segments)
+            .finish()
+    }
+}
+
+impl<W: Write, F: FnMut(u64) -> W> Write for RollingEncoder<W, F> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let (inner, room) = self.current()?;
+        let len = cmp::min(data.len() as u64, room) as usize;
+        let n = inner.write(&data[..len])?;
+        self.unflushed += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.inner {
+            Some(ref mut inner) => {
+                inner.flush()?;
+                self.unflushed = 0;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{BrotliDecoder, BrotliEncoder};
+    use super::{
+        BrotliDecoder, BrotliEncoder, DigestWriter, MultiBrotliDecoder, RateLimited,
+        RollingEncoder, VerifyingDecoder,
+    };
+    use raw::{self, Dictionary, MetadataPolicy};
+    use std::cmp;
     use std::io::prelude::*;
-    use std::iter::repeat;
+    use std::io::{self, IoSlice};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use {CompressParams, DecompressParams, Digest};
+
+    #[test]
+    fn totals() {
+        let mut c = BrotliEncoder::new(Vec::new(), 6);
+        c.write_all(&[7; 1000]).unwrap();
+        c.flush().unwrap();
+        assert_eq!(c.total_in(), 1000);
+        let compressed = c.get_ref().len() as u64;
+        assert_eq!(c.total_out(), compressed);
+
+        let data = c.finish().unwrap();
+        let mut d = BrotliDecoder::new(Vec::new());
+        d.write_all(&data).unwrap();
+        d.flush().unwrap();
+        assert_eq!(d.total_in(), data.len() as u64);
+        assert_eq!(d.total_out(), 1000);
+    }
+
+    #[test]
+    fn pending() {
+        struct Gate(bool, Vec<u8>);
+
+        impl Write for Gate {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if !self.0 {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                self.1.write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut c = BrotliEncoder::new(Gate(false, Vec::new()), 6);
+        assert_eq!(c.pending(), 0);
+        c.write_all(&b"hello world! ".repeat(1000)).unwrap();
+        assert!(c.flush().is_err());
+        let pending = c.pending();
+        assert!(pending > 0);
+
+        c.get_mut().0 = true;
+        c.flush().unwrap();
+        assert_eq!(c.pending(), 0);
+        assert_eq!(c.get_ref().1.len(), pending);
+    }
+
+    #[test]
+    fn compressed_position() {
+        let mut c = BrotliEncoder::new(Vec::new(), 6);
+        let mut offsets = Vec::new();
+        for chunk in ["first ", "second ", "third"].iter() {
+            c.write_all(chunk.as_bytes()).unwrap();
+            c.flush().unwrap();
+            offsets.push(c.compressed_position());
+            assert_eq!(c.compressed_position(), c.get_ref().len() as u64);
+        }
+        let data = c.finish().unwrap();
+
+        let mut d = BrotliDecoder::new(Vec::new());
+        d.write_all(&data[..offsets[1] as usize]).unwrap();
+        d.flush().unwrap();
+        assert_eq!(d.get_ref(), b"first second ");
+    }
+
+    #[test]
+    fn finish_with_summary() {
+        let mut c = BrotliEncoder::new(Vec::new(), 6);
+        c.write_all(&[0; 1000]).unwrap();
+        let (data, summary) = c.finish_with_summary().unwrap();
+        assert_eq!(summary.total_in(), 1000);
+        assert_eq!(summary.total_out(), data.len() as u64);
+        assert!(summary.ratio() > 1.0);
+    }
+
+    #[test]
+    fn write_metadata() {
+        let mut c = BrotliEncoder::new(Vec::new(), 6);
+        c.write_all(b"hello ").unwrap();
+        c.write_metadata(b"checksum").unwrap();
+        c.write_all(b"world").unwrap();
+        let too_long = vec![0; raw::MAX_METADATA_LEN + 1];
+        let err = c.write_metadata(&too_long).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let data = c.finish().unwrap();
+
+        let blocks = Arc::new(Mutex::new(Vec::new()));
+        let seen = blocks.clone();
+        let mut d = BrotliDecoder::new(Vec::new());
+        d.set_metadata_policy(MetadataPolicy::Callback(Box::new(move |block: &[u8]| {
+            seen.lock().unwrap().push(block.to_vec())
+        })));
+        d.write_all(&data).unwrap();
+        assert_eq!(d.finish().unwrap(), b"hello world");
+        assert_eq!(*blocks.lock().unwrap(), [b"checksum".to_vec()]);
+    }
+
+    #[test]
+    fn would_block() {
+        // Accepts at most 7 bytes per call, and every other call would block.
+        struct NonBlocking(bool, Vec<u8>);
+
+        impl Write for NonBlocking {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0 = !self.0;
+                if self.0 {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                let n = cmp::min(buf.len(), 7);
+                self.1.extend_from_slice(&buf[..n]);
+                Ok(n)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                self.0 = !self.0;
+                if self.0 {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                Ok(())
+            }
+        }
+
+        fn retry<T, F: FnMut() -> io::Result<T>>(mut f: F) -> T {
+            loop {
+                match f() {
+                    Ok(t) => return t,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => panic!("{}", e),
+                }
+            }
+        }
+
+        let input = (0..1000)
+            .map(|i| format!("line {}\n", i))
+            .collect::<String>();
+        let mut c = BrotliEncoder::new(NonBlocking(false, Vec::new()), 6);
+        for chunk in input.as_bytes().chunks(100) {
+            let mut chunk = chunk;
+            while !chunk.is_empty() {
+                let n = retry(|| c.write(chunk));
+                chunk = &chunk[n..];
+            }
+            retry(|| c.flush());
+        }
+        retry(|| c.try_finish());
+        let data = c.into_inner().1;
+
+        let mut d = BrotliDecoder::new(NonBlocking(false, Vec::new()));
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let n = retry(|| d.write(rest));
+            rest = &rest[n..];
+        }
+        let out = retry(|| d.finish()).1;
+        assert_eq!(out, input.as_bytes());
+    }
+
+    #[test]
+    fn flaky_writer() {
+        // Accepts a varying part of each write and fails now and then, with
+        // errors that are expected to be retried.
+        struct Flaky(u32, Vec<u8>);
+
+        impl Flaky {
+            fn next(&mut self) -> u32 {
+                self.0 = self.0.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (self.0 >> 16) % 8
+            }
+        }
+
+        impl Write for Flaky {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                match self.next() {
+                    0 => Err(io::ErrorKind::Interrupted.into()),
+                    1 => Err(io::Error::new(io::ErrorKind::Other, "transient")),
+                    k => {
+                        let n = cmp::min(buf.len(), k as usize * 3);
+                        self.1.extend_from_slice(&buf[..n]);
+                        Ok(n)
+                    }
+                }
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        fn retry<T, F: FnMut() -> io::Result<T>>(mut f: F) -> T {
+            loop {
+                if let Ok(t) = f() {
+                    return t;
+                }
+            }
+        }
+
+        let input = (0..1000)
+            .map(|i| format!("line {}\n", i))
+            .collect::<String>();
+        let mut c = BrotliEncoder::new(Flaky(1, Vec::new()), 6);
+        for chunk in input.as_bytes().chunks(100) {
+            let mut chunk = chunk;
+            while !chunk.is_empty() {
+                let n = retry(|| c.write(chunk));
+                chunk = &chunk[n..];
+            }
+            retry(|| c.flush());
+        }
+        retry(|| c.try_finish());
+        let data = c.into_inner().1;
+
+        let mut d = BrotliDecoder::new(Flaky(2, Vec::new()));
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let n = retry(|| d.write(rest));
+            rest = &rest[n..];
+        }
+        let out = retry(|| d.finish()).1;
+        assert_eq!(out, input.as_bytes());
+    }
+
+    #[test]
+    fn write_zero() {
+        struct Full;
+
+        impl Write for Full {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Ok(0)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut c = BrotliEncoder::new(Full, 6);
+        c.write_all(b"hello").unwrap();
+        let err = c.try_finish().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+        assert!(c.pending() > 0);
+        c.into_inner();
+    }
+
+    #[test]
+    fn set_params() {
+        let input = vec![b'a'; 10_000];
+        let mut params = CompressParams::new();
+        params.quality(11).lgwin(10);
+        let mut expected = BrotliEncoder::from_params(Vec::new(), &params);
+        expected.write_all(&input).unwrap();
+        let expected = expected.finish().unwrap();
+
+        let mut c = BrotliEncoder::new(Vec::new(), 1);
+        c.set_params(&params).unwrap();
+        c.write_all(&input).unwrap();
+        let err = c.set_params(CompressParams::new().quality(1)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(c.finish().unwrap(), expected);
+    }
+
+    #[test]
+    fn by_mut_ref() {
+        fn write_some<W: Write>(mut w: W, data: &[u8]) {
+            w.write_all(data).unwrap();
+        }
+
+        let mut c = BrotliEncoder::new(Vec::new(), 6);
+        write_some(&mut c, b"hello ");
+        write_some(&mut c, b"world");
+        let data = c.finish().unwrap();
+        let mut d = BrotliDecoder::new(Vec::new());
+        write_some(&mut d, &data);
+        assert_eq!(d.finish().unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn vectored() {
+        // Takes at most 100 bytes per call, from all slices at once.
+        struct Trickle(Vec<u8>);
+
+        impl Write for Trickle {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.write_vectored(&[IoSlice::new(buf)])
+            }
+
+            fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+                let mut n = 0;
+                for buf in bufs {
+                    let amt = cmp::min(buf.len(), 100 - n);
+                    self.0.extend_from_slice(&buf[..amt]);
+                    n += amt;
+                }
+                Ok(n)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let input = (0..1000)
+            .map(|i| format!("line {}\n", i))
+            .collect::<String>();
+        let mut bufs = input
+            .as_bytes()
+            .chunks(1000)
+            .map(IoSlice::new)
+            .collect::<Vec<_>>();
+        let mut c = BrotliEncoder::new(Trickle(Vec::new()), 6);
+        let mut slices = &mut bufs[..];
+        while !slices.is_empty() {
+            let n = c.write_vectored(slices).unwrap();
+            IoSlice::advance_slices(&mut slices, n);
+        }
+        let data = c.finish().unwrap().0;
+
+        let mut bufs = data.chunks(10).map(IoSlice::new).collect::<Vec<_>>();
+        let mut d = BrotliDecoder::new(Trickle(Vec::new()));
+        let mut slices = &mut bufs[..];
+        while !slices.is_empty() {
+            let n = d.write_vectored(slices).unwrap();
+            IoSlice::advance_slices(&mut slices, n);
+        }
+        assert_eq!(d.finish().unwrap().0, input.as_bytes());
+    }
+
+    #[test]
+    fn try_finish() {
+        struct Failing(usize, Vec<u8>);
+
+        impl Write for Failing {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.0 == 0 {
+                    return Err(io::Error::new(io::ErrorKind::Other, "disconnected"));
+                }
+                self.0 -= 1;
+                self.1.write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut c = BrotliEncoder::new(Failing(0, Vec::new()), 6);
+        c.write_all(b"hello").unwrap();
+        assert!(c.try_finish().is_err());
+        let mut w = c.into_inner();
+        assert!(w.1.is_empty());
+
+        w.0 = usize::MAX;
+        let mut c = BrotliEncoder::new(w, 6);
+        c.write_all(b"hello").unwrap();
+        c.try_finish().unwrap();
+        let data = c.into_inner().1;
+        let mut d = BrotliDecoder::new(Vec::new());
+        d.write_all(&data).unwrap();
+        assert_eq!(d.finish().unwrap(), b"hello");
+    }
+
+    #[test]
+    fn auto_finish() {
+        let mut out = None;
+        {
+            let mut c = BrotliEncoder::new(Vec::new(), 6).on_finish(|r| out = Some(r));
+            c.write_all(b"hello").unwrap();
+        }
+        let data = out.unwrap().unwrap();
+        let mut d = BrotliDecoder::new(Vec::new());
+        d.write_all(&data).unwrap();
+        assert_eq!(d.finish().unwrap(), b"hello");
+
+        struct Full;
+
+        impl Write for Full {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Ok(0)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut failed = None;
+        {
+            let mut c = BrotliEncoder::new(Full, 6).on_finish(|r| failed = r.err());
+            c.write_all(b"hello").unwrap();
+        }
+        assert_eq!(failed.unwrap().kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn reset() {
+        let mut c = BrotliEncoder::new(Vec::new(), 6);
+        c.write_all(b"first").unwrap();
+        let first = c.reset(Vec::new()).unwrap();
+        c.write_all(b"second").unwrap();
+        let second = c.finish().unwrap();
+
+        let mut d = BrotliDecoder::new(Vec::new());
+        d.write_all(&first).unwrap();
+        assert_eq!(d.reset(Vec::new()).unwrap(), b"first");
+        d.write_all(&second).unwrap();
+        assert_eq!(d.finish().unwrap(), b"second");
+    }
+
+    #[test]
+    fn multi_decoder() {
+        let mut data = Vec::new();
+        for part in [&b"first "[..], b"", b"second"].iter() {
+            let mut c = BrotliEncoder::new(data, 6);
+            c.write_all(part).unwrap();
+            data = c.finish().unwrap();
+        }
+        let mut d = MultiBrotliDecoder::new(Vec::new());
+        d.write_all(&data).unwrap();
+        assert_eq!(d.finish().unwrap(), b"first second");
+
+        // The dictionary is kept for every stream after the first.
+        let dict = Dictionary::new(b"a dictionary for every stream").unwrap();
+        let mut data = Vec::new();
+        for part in [&b"a first stream "[..], b"a second stream"].iter() {
+            let mut c = BrotliEncoder::with_dictionary(data, &CompressParams::new(), &dict);
+            c.write_all(part).unwrap();
+            data = c.finish().unwrap();
+        }
+        let mut d = MultiBrotliDecoder::with_dictionary(Vec::new(), &dict);
+        d.write_all(&data).unwrap();
+        assert_eq!(d.finish().unwrap(), b"a first stream a second stream");
+    }
+
+    #[test]
+    fn strict() {
+        let mut c = BrotliEncoder::new(Vec::new(), 6);
+        c.write_all(b"hello").unwrap();
+        let mut data = c.finish().unwrap();
+        data.push(0);
+
+        let mut d = BrotliDecoder::new(Vec::new());
+        d.set_strict(true);
+        let err = d.write_all(&data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(d.finish().unwrap(), b"hello");
+    }
+
+    #[test]
+    fn stats() {
+        // Waiting on the underlying writer counts towards the elapsed time.
+        struct Slow(Vec<u8>);
+
+        impl Write for Slow {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                thread::sleep(Duration::from_millis(10));
+                self.0.write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut c = BrotliEncoder::new(Slow(Vec::new()), 6);
+        assert_eq!(c.stats(), None);
+        c.enable_stats();
+        c.write_all(&b"hello world! ".repeat(1000)).unwrap();
+        c.flush().unwrap();
+        c.write_all(b"bye").unwrap();
+        c.flush().unwrap();
+        c.try_finish().unwrap();
+        let stats = c.stats().unwrap();
+        assert_eq!(stats.total_in(), 13003);
+        assert_eq!(stats.total_out(), c.get_ref().0.len() as u64);
+        assert_eq!(stats.flushes(), 2);
+        assert!(stats.elapsed() >= Duration::from_millis(10));
+
+        let data = c.reset(Slow(Vec::new())).unwrap().0;
+        assert_eq!(c.stats().unwrap().flushes(), 0);
+
+        let mut d = BrotliDecoder::new(Vec::new());
+        d.enable_stats();
+        d.write_all(&data).unwrap();
+        d.flush().unwrap();
+        let stats = d.stats().unwrap();
+        assert_eq!(stats.total_in(), data.len() as u64);
+        assert_eq!(stats.total_out(), 13003);
+        assert_eq!(stats.flushes(), 1);
+    }
+
+    // FNV-1a, as a stand-in for a real hash.
+    struct Fnv(u64);
+
+    impl Digest for Fnv {
+        type Output = u64;
+
+        fn update(&mut self, data: &[u8]) {
+            for &b in data {
+                self.0 = (self.0 ^ b as u64).wrapping_mul(0x100_0000_01b3);
+            }
+        }
+
+        fn finalize(self) -> u64 {
+            self.0
+        }
+    }
+
+    fn fnv(data: &[u8]) -> u64 {
+        let mut digest = Fnv(0xcbf2_9ce4_8422_2325);
+        digest.update(data);
+        digest.finalize()
+    }
+
+    #[test]
+    fn digest_writer() {
+        let input = b"hello world! ".repeat(1000);
+        let w = DigestWriter::new(Vec::new(), Fnv(0xcbf2_9ce4_8422_2325));
+        let mut c = BrotliEncoder::with_capacity(100, w, 6);
+        c.write_all(&input).unwrap();
+        let (data, compressed_digest) = c.finish().unwrap().finish();
+        assert_eq!(compressed_digest, fnv(&data));
+
+        let d = BrotliDecoder::new(Vec::new());
+        let mut w = DigestWriter::new(d, Fnv(0xcbf2_9ce4_8422_2325));
+        w.write_all(&data).unwrap();
+        let (mut d, digest) = w.finish();
+        assert_eq!(digest, compressed_digest);
+        assert_eq!(d.finish().unwrap(), input);
+    }
+
+    #[test]
+    fn verifying_decoder() {
+        let input = b"hello world! ".repeat(1000);
+        let mut c = BrotliEncoder::new(Vec::new(), 6);
+        c.write_all(&input).unwrap();
+        let data = c.finish().unwrap();
+        let len = input.len() as u64;
+        let new = |digest, len| {
+            VerifyingDecoder::new(Vec::new(), Fnv(0xcbf2_9ce4_8422_2325), digest, len)
+        };
+
+        let mut d = new(fnv(&input), len);
+        d.write_all(&data).unwrap();
+        assert_eq!(d.finish().unwrap(), input);
+
+        let mut d = new(fnv(b"something else"), len);
+        d.write_all(&data).unwrap();
+        let err = d.finish().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut d = new(fnv(&input), len + 1);
+        d.write_all(&data).unwrap();
+        let err = d.finish().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut d = new(fnv(&input), len - 1);
+        let err = d.write_all(&data).and_then(|()| d.flush()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = d.flush().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!((d.get_ref().len() as u64) < len);
+        assert!(d.finish().is_err());
+    }
+
+    #[test]
+    fn verifying_decoder_compressible() {
+        let input = vec![0; 16 << 20];
+        let mut c = BrotliEncoder::new(Vec::new(), 5);
+        c.write_all(&input).unwrap();
+        let data = c.finish().unwrap();
+        assert!(data.len() < 4096);
+        let len = input.len() as u64;
+        let new = |len| {
+            let digest = fnv(&input);
+            VerifyingDecoder::new(Vec::new(), Fnv(0xcbf2_9ce4_8422_2325), digest, len)
+        };
+
+        let mut d = new(len);
+        d.write_all(&data).unwrap();
+        assert_eq!(d.finish().unwrap(), input);
+
+        // The output stops at the expected length rather than expanding the
+        // whole stream.
+        let mut d = new(1 << 20);
+        let err = d.write_all(&data).and_then(|()| d.flush()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(d.get_ref().len() <= 1 << 20);
+        assert!(d.finish().is_err());
+    }
+
+    #[test]
+    fn rate_limited() {
+        let mut w = RateLimited::new(Vec::new(), 1_000_000);
+        let start = Instant::now();
+        // The first second's worth is written at once, the rest at the rate.
+        w.write_all(&vec![0; 1_300_000]).unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(250), "{:?}", elapsed);
+        assert_eq!(w.get_ref().len(), 1_300_000);
+
+        let mut c = BrotliEncoder::new(RateLimited::new(Vec::new(), 100), 6);
+        c.write_all(&b"hello world! ".repeat(1000)).unwrap();
+        let data = c.finish().unwrap().into_inner();
+        let mut d = BrotliDecoder::new(Vec::new());
+        d.write_all(&data).unwrap();
+        assert_eq!(d.finish().unwrap(), b"hello world! ".repeat(1000));
+    }
+
+    #[test]
+    fn rolling_encoder() {
+        // Appends to its own entry in a shared list of segments.
+        struct Segment(Arc<Mutex<Vec<Vec<u8>>>>, usize);
+
+        impl Write for Segment {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap()[self.1].extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        fn roll(input: &[u8], limit: u64) {
+            let segments = Arc::new(Mutex::new(Vec::new()));
+            let factory = |index| {
+                let mut list = segments.lock().unwrap();
+                assert_eq!(list.len() as u64, index);
+                list.push(Vec::new());
+                Segment(segments.clone(), index as usize)
+            };
+            let mut params = CompressParams::new();
+            params.quality(1);
+            let mut c = RollingEncoder::new(factory, limit, &params);
+            c.write_all(input).unwrap();
+            let n = c.finish().unwrap();
+
+            let segments = segments.lock().unwrap();
+            assert_eq!(segments.len() as u64, n);
+            let mut out = Vec::new();
+            for (i, segment) in segments.iter().enumerate() {
+                let len = segment.len() as u64;
+                assert!(len <= limit + 16, "{}", len);
+                if i + 1 < segments.len() {
+                    assert!(len >= limit - limit / 16, "{}", len);
+                }
+                out.extend(raw::decompress_vec(segment).unwrap());
+            }
+            assert!(out == input);
+        }
+
+        let mut input = Vec::new();
+        let mut x = 1u32;
+        for _ in 0..300_000 {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            input.push(x as u8);
+        }
+        roll(&input, 10_000);
+        roll(&input, 100_000);
+        let text = (0..200_000)
+            .map(|i| format!("line {}\n", i))
+            .collect::<String>();
+        roll(text.as_bytes(), 10_000);
+    }
+
+    #[test]
+    fn unconsumed() {
+        let mut c = BrotliEncoder::new(Vec::new(), 6);
+        c.write_all(b"hello").unwrap();
+        let mut data = c.finish().unwrap();
+        let len = data.len();
+        data.extend_from_slice(b"trailer");
+
+        let mut d = BrotliDecoder::new(Vec::new());
+        let mut written = 0;
+        while !d.is_finished() {
+            written += d.write(&data[written..]).unwrap();
+        }
+        assert_eq!(written, len);
+        assert_eq!(d.total_in(), len as u64);
+        assert_eq!(d.write(&data[written..]).unwrap(), 0);
+        assert_eq!(d.finish().unwrap(), b"hello");
+    }
+
+    #[test]
+    fn with_capacity() {
+        let input = b"hello world! ".repeat(1000);
+        let mut c = BrotliEncoder::with_capacity(16, Vec::new(), 6);
+        c.write_all(&input).unwrap();
+        let data = c.finish().unwrap();
+
+        let mut d = BrotliDecoder::with_capacity(16, Vec::new());
+        d.write_all(&data).unwrap();
+        assert_eq!(d.finish().unwrap(), input);
+
+        let mut d = BrotliDecoder::with_capacity(0, Vec::new());
+        d.write_all(&data).unwrap();
+        assert_eq!(d.finish().unwrap(), input);
+    }
+
+    #[test]
+    fn with_buffer() {
+        let input = b"hello world! ".repeat(1000);
+        let mut c = BrotliEncoder::with_buffer(b"stale".to_vec(), Vec::new(), 6);
+        c.write_all(&input).unwrap();
+        c.try_finish().unwrap();
+        let (data, buf) = c.into_parts();
+        assert!(buf.is_empty());
+
+        let mut d = BrotliDecoder::with_buffer(buf, Vec::new());
+        d.write_all(&data).unwrap();
+        let (out, buf) = d.finish_into_parts().unwrap();
+        assert_eq!(out, input);
+        assert!(buf.is_empty());
+        assert!(buf.capacity() >= 5);
+    }
+
+    #[test]
+    fn max_output() {
+        let data = raw::compress_vec(&CompressParams::new(), &[0; 100_000]).unwrap();
+
+        let mut d = BrotliDecoder::new(Vec::new());
+        d.set_max_output(Some(100_000));
+        d.write_all(&data).unwrap();
+        assert_eq!(d.finish().unwrap().len(), 100_000);
+
+        let mut d = BrotliDecoder::new(Vec::new());
+        d.set_max_output(Some(99_999));
+        d.write_all(&data).unwrap();
+        let err = d.flush().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = d.flush().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(d.write(&[0]).is_err());
+        assert!(d.finish().is_err());
+        assert!(d.get_ref().len() <= 99_999);
+    }
+
+    #[test]
+    fn finish_would_block() {
+        // Accepts 1000 bytes per call, and blocks once after the first 10k.
+        #[derive(Debug)]
+        struct Stall(usize, bool, Vec<u8>);
+
+        impl Write for Stall {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.2.len() >= self.0 && !self.1 {
+                    self.1 = true;
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                let n = cmp::min(buf.len(), 1000);
+                self.2.extend_from_slice(&buf[..n]);
+                Ok(n)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        // The small window leaves part of the output to be decoded only once
+        // the decompressor is asked to finish.
+        let input = vec![0; 1 << 20];
+        let mut params = CompressParams::new();
+        params.quality(1).lgwin(16);
+        let data = raw::compress_vec(&params, &input).unwrap();
+
+        let mut d = BrotliDecoder::new(Stall(usize::MAX, false, Vec::new()));
+        d.write_all(&data).unwrap();
+        let written = d.get_ref().2.len();
+        assert!(d.total_out() < input.len() as u64);
+        d.get_mut().0 = written + 10_000;
+        let err = d.finish().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(d.finish().unwrap().2 == input);
+
+        let mut d = BrotliDecoder::new(Stall(usize::MAX, true, Vec::new()));
+        d.set_max_output(Some(input.len() as u64 - 1));
+        d.write_all(&data).unwrap();
+        let err = d.finish().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(d.get_ref().2.len() < input.len());
+    }
+
+    #[test]
+    fn long_runs() {
+        let mut params = CompressParams::new();
+        params.quality(1).lgwin(16);
+        let data = raw::compress_vec(&params, &vec![0; 20 << 20]).unwrap();
+
+        let mut d = BrotliDecoder::new(Vec::new());
+        d.write_all(&data).unwrap();
+        let out = d.finish().unwrap();
+        assert_eq!(out.len(), 20 << 20);
+        assert!(out.iter().all(|&b| b == 0));
+
+        let mut d = BrotliDecoder::new(Vec::new());
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let n = d.write_vectored(&[IoSlice::new(rest)]).unwrap();
+            assert!(n > 0);
+            rest = &rest[n..];
+        }
+        assert_eq!(d.finish().unwrap().len(), 20 << 20);
+
+        let mut d = BrotliDecoder::new(Vec::new());
+        d.set_max_output(Some(1 << 20));
+        let err = d.write_all(&data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn tolerant() {
+        let mut c = BrotliEncoder::new(Vec::new(), 6);
+        c.write_all(b"hello").unwrap();
+        c.flush().unwrap();
+        let flushed = c.get_ref().len();
+        c.write_all(b" world").unwrap();
+        let data = c.finish().unwrap();
+
+        let mut d = BrotliDecoder::new(Vec::new());
+        d.set_tolerant(true);
+        d.write_all(&data[..flushed]).unwrap();
+        assert_eq!(d.finish().unwrap(), b"hello");
+        assert!(d.is_truncated());
+    }
 
     #[test]
     fn smoke() {
         let d = BrotliDecoder::new(Vec::new());
         let mut c = BrotliEncoder::new(d, 6);
         c.write_all(b"12834").unwrap();
-        let s = repeat("12345").take(100000).collect::<String>();
+        let s = "12345".repeat(100000);
         c.write_all(s.as_bytes()).unwrap();
         let data = c.finish().unwrap().finish().unwrap();
         assert_eq!(&data[0..5], b"12834");
@@ -320,11 +2332,43 @@ mod tests {
     fn write_empty() {
         let d = BrotliDecoder::new(Vec::new());
         let mut c = BrotliEncoder::new(d, 6);
-        c.write(b"").unwrap();
+        assert_eq!(c.write(b"").unwrap(), 0);
         let data = c.finish().unwrap().finish().unwrap();
         assert_eq!(&data[..], b"");
     }
 
+    #[test]
+    fn dictionary() {
+        let dict = Dictionary::new(b"a dictionary shared by both ends").unwrap();
+        let d = BrotliDecoder::with_dictionary(Vec::new(), &dict);
+        let mut c = BrotliEncoder::with_dictionary(d, &CompressParams::new(), &dict);
+        c.write_all(b"a message compressed against a dictionary")
+            .unwrap();
+        let data = c.finish().unwrap().finish().unwrap();
+        assert_eq!(&data[..], b"a message compressed against a dictionary");
+    }
+
+    #[test]
+    fn decompress_params() {
+        let mut params = DecompressParams::new();
+        params.disable_ring_buffer_reallocation(true);
+        let d = BrotliDecoder::from_params(Vec::new(), &params);
+        let mut c = BrotliEncoder::new(d, 6);
+        let s = "12345".repeat(1000);
+        c.write_all(s.as_bytes()).unwrap();
+        let data = c.finish().unwrap().finish().unwrap();
+        assert!(s.as_bytes() == &*data);
+    }
+
+    #[test]
+    fn invalid_params() {
+        let mut params = CompressParams::new();
+        params.npostfix(1).ndirect(3);
+        let mut c = BrotliEncoder::from_params(Vec::new(), &params);
+        c.write_all(b"hello").unwrap_err();
+        c.finish().unwrap_err();
+    }
+
     #[test]
     fn qc() {
         ::quickcheck::quickcheck(test as fn(_) -> _);
@@ -0,0 +1,95 @@
+extern crate brotli2;
+
+use brotli2::raw::Dictionary;
+use brotli2::{BrotliDecoderBuilder, BrotliEncoderBuilder, CompressParams};
+use std::io::prelude::*;
+
+const DICT: &[u8] = b"the quick brown fox jumps over the lazy dog";
+
+fn input() -> Vec<u8> {
+    (0..500)
+        .flat_map(|i| format!("{} the quick brown fox {}\n", i, DICT.len()).into_bytes())
+        .collect()
+}
+
+#[test]
+fn roundtrip_all_kinds() {
+    let dict = Dictionary::new(DICT).unwrap();
+    let mut encoders = BrotliEncoderBuilder::new();
+    encoders
+        .params(CompressParams::new().quality(5))
+        .capacity(100)
+        .dictionary(&dict);
+    let mut decoders = BrotliDecoderBuilder::new();
+    decoders.capacity(100).dictionary(&dict).strict(true);
+    let input = input();
+
+    let mut w = encoders.build_write(Vec::new());
+    w.write_all(&input).unwrap();
+    let written = w.finish().unwrap();
+
+    let mut read = Vec::new();
+    encoders
+        .build_read(&input[..])
+        .read_to_end(&mut read)
+        .unwrap();
+    let mut bufread = Vec::new();
+    encoders
+        .build_bufread(&input[..])
+        .read_to_end(&mut bufread)
+        .unwrap();
+    assert_eq!(read, bufread);
+
+    for compressed in [&written, &read] {
+        let mut out = Vec::new();
+        decoders
+            .build_read(&compressed[..])
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, input);
+
+        let mut out = Vec::new();
+        decoders
+            .build_bufread(&compressed[..])
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, input);
+
+        let mut d = decoders.build_write(Vec::new());
+        d.write_all(compressed).unwrap();
+        assert_eq!(d.finish().unwrap(), input);
+    }
+
+    // Without the dictionary the streams cannot be decoded correctly.
+    let mut out = Vec::new();
+    let res = BrotliDecoderBuilder::new()
+        .build_read(&written[..])
+        .read_to_end(&mut out);
+    assert!(res.is_err() || out != input);
+}
+
+#[test]
+fn decoder_options() {
+    let input = input();
+    let mut w = BrotliEncoderBuilder::new().build_write(Vec::new());
+    w.write_all(&input).unwrap();
+    let compressed = w.finish().unwrap();
+
+    let mut trailing = compressed.clone();
+    trailing.extend_from_slice(b"junk");
+    let mut out = Vec::new();
+    assert!(BrotliDecoderBuilder::new()
+        .strict(true)
+        .build_read(&trailing[..])
+        .read_to_end(&mut out)
+        .is_err());
+
+    let truncated = &compressed[..compressed.len() / 2];
+    let mut d = BrotliDecoderBuilder::new()
+        .tolerant(true)
+        .build_bufread(truncated);
+    let mut out = Vec::new();
+    d.read_to_end(&mut out).unwrap();
+    assert!(d.is_truncated());
+    assert_eq!(out, &input[..out.len()]);
+}
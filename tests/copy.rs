@@ -0,0 +1,151 @@
+extern crate brotli2;
+
+use brotli2::{Cancelled, CompressParams};
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[test]
+fn roundtrip() {
+    let input = (0..10_000)
+        .map(|i| format!("line {}\n", i))
+        .collect::<String>();
+    let mut params = CompressParams::new();
+    params.quality(5);
+    let mut compressed = Vec::new();
+    let n = brotli2::copy_encode(input.as_bytes(), &mut compressed, &params).unwrap();
+    assert_eq!(n, input.len() as u64);
+    assert!(compressed.len() < input.len());
+
+    let mut out = Vec::new();
+    let n = brotli2::copy_decode(&compressed[..], &mut out).unwrap();
+    assert_eq!(n, input.len() as u64);
+    assert_eq!(out, input.as_bytes());
+}
+
+#[test]
+fn empty() {
+    let mut compressed = Vec::new();
+    assert_eq!(
+        brotli2::copy_encode(io::empty(), &mut compressed, &CompressParams::new()).unwrap(),
+        0
+    );
+    assert_eq!(
+        brotli2::copy_decode(&compressed[..], io::sink()).unwrap(),
+        0
+    );
+}
+
+#[test]
+fn truncated() {
+    let mut compressed = Vec::new();
+    brotli2::copy_encode(&[7; 1000][..], &mut compressed, &CompressParams::new()).unwrap();
+    let truncated = &compressed[..compressed.len() - 1];
+    assert!(brotli2::copy_decode(truncated, io::sink()).is_err());
+}
+
+#[test]
+fn progress() {
+    // Hardly compressible, so that the compressed stream is long too.
+    let mut x = 1u32;
+    let input: Vec<u8> = (0..200_000)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x as u8
+        })
+        .collect();
+    let mut compressed = Vec::new();
+    let mut reported = Vec::new();
+    {
+        let mut options = brotli2::CopyOptions::new();
+        options.progress(1 << 14, |total_in, total_out| {
+            reported.push((total_in, total_out))
+        });
+        let params = CompressParams::new();
+        brotli2::copy_encode_with(&input[..], &mut compressed, &params, &mut options).unwrap();
+    }
+    assert_eq!(
+        reported.last(),
+        Some(&(input.len() as u64, compressed.len() as u64))
+    );
+
+    let mut reported = Vec::new();
+    {
+        let mut options = brotli2::CopyOptions::new();
+        options.progress(1 << 14, |total_in, total_out| {
+            reported.push((total_in, total_out))
+        });
+        brotli2::copy_decode_with(&compressed[..], io::sink(), &mut options).unwrap();
+    }
+    assert!(reported.len() > 1);
+    assert!(reported.windows(2).all(|w| w[0].0 < w[1].0));
+    assert_eq!(
+        reported.last(),
+        Some(&(compressed.len() as u64, input.len() as u64))
+    );
+}
+
+#[test]
+fn cancel() {
+    let input = vec![0; 1 << 20];
+    let cancelled = AtomicBool::new(false);
+    let mut compressed = Vec::new();
+    let err = {
+        let mut options = brotli2::CopyOptions::new();
+        options
+            .progress(1, |_, _| cancelled.store(true, Ordering::Relaxed))
+            .cancel_on(&cancelled);
+        let params = CompressParams::new();
+        brotli2::copy_encode_with(&input[..], &mut compressed, &params, &mut options).unwrap_err()
+    };
+    assert!(Cancelled::is_cancelled(&err));
+    assert!(!Cancelled::is_cancelled(&io::ErrorKind::Other.into()));
+
+    compressed.clear();
+    brotli2::copy_encode(&input[..], &mut compressed, &CompressParams::new()).unwrap();
+    let mut options = brotli2::CopyOptions::new();
+    options.cancel_when(|| true);
+    let err = brotli2::copy_decode_with(&compressed[..], io::sink(), &mut options).unwrap_err();
+    assert!(Cancelled::is_cancelled(&err));
+    assert_eq!(err.to_string(), "brotli copy was cancelled");
+}
+
+#[test]
+fn cancel_while_reading() {
+    // Zeros compress so well that the whole input is read before the first
+    // chunk of output is ready, so cancellation has to be checked between
+    // reads of the input.
+    struct Source<'a> {
+        remaining: u64,
+        read: u64,
+        cancelled: &'a AtomicBool,
+    }
+
+    impl<'a> Read for Source<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = std::cmp::min(buf.len() as u64, self.remaining) as usize;
+            buf[..len].iter_mut().for_each(|b| *b = 0);
+            self.remaining -= len as u64;
+            self.read += len as u64;
+            self.cancelled.store(true, Ordering::Relaxed);
+            Ok(len)
+        }
+    }
+
+    let cancelled = AtomicBool::new(false);
+    let mut source = Source {
+        remaining: 64 << 20,
+        read: 0,
+        cancelled: &cancelled,
+    };
+    let mut options = brotli2::CopyOptions::new();
+    options.cancel_on(&cancelled);
+    let mut compressed = Vec::new();
+    let params = CompressParams::new();
+    let err =
+        brotli2::copy_encode_with(&mut source, &mut compressed, &params, &mut options).unwrap_err();
+    assert!(Cancelled::is_cancelled(&err));
+    assert!(compressed.is_empty());
+    assert!(source.read < 64 << 20);
+}
@@ -4,7 +4,7 @@ use brotli2::write::BrotliDecoder;
 use std::io::prelude::*;
 
 // This is a BR file generated by head -c10 /dev/urandom | bro --output file.br
-const DATA: &'static [u8] = &[
+const DATA: &[u8] = &[
     139, 4, 128, 227, 139, 226, 91, 233, 134, 14, 218, 140, 196, 3,
 ];
 
@@ -27,6 +27,5 @@ fn finish_writer_incomplete_input_error() {
     decoder.write_all(&DATA[..PREFIX_LEN]).unwrap();
     decoder
         .finish()
-        .err()
-        .expect("finish should error because of incomplete input");
+        .expect_err("finish should error because of incomplete input");
 }
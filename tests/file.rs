@@ -0,0 +1,94 @@
+extern crate brotli2;
+
+use brotli2::CompressParams;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("brotli2-file-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn leftovers(dir: &PathBuf) -> Vec<String> {
+    let mut names = fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+#[test]
+fn roundtrip() {
+    let dir = scratch("roundtrip");
+    let input = (0..20_000)
+        .map(|i| format!("line {}\n", i))
+        .collect::<String>();
+    fs::write(dir.join("data.txt"), &input).unwrap();
+
+    let n = brotli2::compress_file(dir.join("data.txt"), dir.join("data.txt.br"), None).unwrap();
+    assert_eq!(n, input.len() as u64);
+    let n = brotli2::decompress_file(dir.join("data.txt.br"), dir.join("out.txt")).unwrap();
+    assert_eq!(n, input.len() as u64);
+    assert_eq!(fs::read(dir.join("out.txt")).unwrap(), input.as_bytes());
+
+    let mut params = CompressParams::new();
+    params.quality(1);
+    brotli2::compress_file(dir.join("data.txt"), dir.join("data.txt.br"), Some(&params)).unwrap();
+    brotli2::decompress_file(dir.join("data.txt.br"), dir.join("out.txt")).unwrap();
+    assert_eq!(fs::read(dir.join("out.txt")).unwrap(), input.as_bytes());
+
+    assert_eq!(leftovers(&dir), ["data.txt", "data.txt.br", "out.txt"]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn failure_keeps_destination() {
+    let dir = scratch("failure");
+    let mut compressed = Vec::new();
+    brotli2::copy_encode(&[7; 4096][..], &mut compressed, &CompressParams::new()).unwrap();
+    compressed.truncate(compressed.len() / 2);
+    fs::write(dir.join("bad.br"), &compressed).unwrap();
+    fs::write(dir.join("out"), b"original").unwrap();
+
+    assert!(brotli2::decompress_file(dir.join("bad.br"), dir.join("out")).is_err());
+    assert_eq!(fs::read(dir.join("out")).unwrap(), b"original");
+    assert!(brotli2::compress_file(dir.join("missing"), dir.join("out"), None).is_err());
+    assert_eq!(fs::read(dir.join("out")).unwrap(), b"original");
+
+    assert_eq!(leftovers(&dir), ["bad.br", "out"]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(all(feature = "mmap", unix))]
+#[test]
+fn mapped() {
+    let dir = scratch("mapped");
+    let input = (0..20_000)
+        .map(|i| format!("line {}\n", i))
+        .collect::<String>();
+    fs::write(dir.join("data.txt"), &input).unwrap();
+    fs::write(dir.join("empty"), b"").unwrap();
+
+    unsafe {
+        let n =
+            brotli2::compress_file_mapped(dir.join("data.txt"), dir.join("data.br"), None).unwrap();
+        assert_eq!(n, input.len() as u64);
+        let n = brotli2::decompress_file_mapped(dir.join("data.br"), dir.join("out.txt")).unwrap();
+        assert_eq!(n, input.len() as u64);
+        brotli2::compress_file_mapped(dir.join("empty"), dir.join("empty.br"), None).unwrap();
+    }
+    assert_eq!(fs::read(dir.join("out.txt")).unwrap(), input.as_bytes());
+    brotli2::decompress_file(dir.join("empty.br"), dir.join("empty")).unwrap();
+    assert!(fs::read(dir.join("empty")).unwrap().is_empty());
+
+    let compressed = fs::read(dir.join("data.br")).unwrap();
+    fs::write(dir.join("data.br"), &compressed[..compressed.len() / 2]).unwrap();
+    let res = unsafe { brotli2::decompress_file_mapped(dir.join("data.br"), dir.join("out.txt")) };
+    assert!(res.is_err());
+    assert_eq!(fs::read(dir.join("out.txt")).unwrap(), input.as_bytes());
+    fs::remove_dir_all(&dir).unwrap();
+}
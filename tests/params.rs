@@ -0,0 +1,258 @@
+#[cfg(feature = "fuzzing")]
+extern crate arbitrary;
+extern crate brotli2;
+#[cfg(feature = "fuzzing")]
+extern crate rand;
+#[cfg(feature = "serde")]
+extern crate serde_json;
+
+use brotli2::raw::Compress;
+use brotli2::{CompressMode, CompressParams, ParamError};
+
+#[test]
+fn validate_defaults() {
+    let params = CompressParams::new().validate().unwrap();
+    assert_eq!(params.get_lgwin(), CompressParams::new().get_lgwin());
+    let mut c = Compress::new();
+    c.set_params(&params).unwrap();
+}
+
+#[test]
+fn validate_ranges() {
+    let mut params = CompressParams::new();
+    params.quality(12);
+    assert_eq!(
+        params.validate().unwrap_err(),
+        ParamError::OutOfRange("quality")
+    );
+
+    let mut params = CompressParams::new();
+    params.lgblock(25);
+    assert_eq!(
+        params.validate().unwrap_err(),
+        ParamError::OutOfRange("lgblock")
+    );
+
+    // Stream offsets beyond what the encoder accepts all behave the same,
+    // and are clamped rather than rejected.
+    let mut params = CompressParams::new();
+    params.stream_offset(u64::MAX);
+    params.validate().unwrap();
+    Compress::new().set_params(&params).unwrap();
+}
+
+#[test]
+fn validate_conflicts() {
+    let mut params = CompressParams::new();
+    params.quality(2).lgblock(16);
+    let err = params.validate().unwrap_err();
+    assert_eq!(err, ParamError::Conflict("lgblock", "quality"));
+    assert_eq!(err.param(), "lgblock");
+
+    let mut params = CompressParams::new();
+    params.quality(1).lgwin(26);
+    assert_eq!(
+        params.validate().unwrap_err(),
+        ParamError::Conflict("lgwin", "quality")
+    );
+    // libbrotli accepts these and adjusts them, so only `validate` rejects
+    // them.
+    Compress::new().set_params(&params).unwrap();
+    params.quality(2).lgwin(22).lgblock(16);
+    Compress::new().set_params(&params).unwrap();
+
+    params.quality(3).lgwin(26).lgblock(0);
+    assert!(params.validate().is_ok());
+}
+
+#[test]
+fn presets_are_valid() {
+    for params in &[
+        CompressParams::fastest(),
+        CompressParams::balanced(),
+        CompressParams::best(),
+        CompressParams::text(),
+    ] {
+        params.validate().unwrap();
+    }
+    assert_eq!(CompressParams::best().get_lgwin(), 24);
+}
+
+#[test]
+fn quality_and_mode_getters() {
+    let params = CompressParams::text();
+    assert_eq!(params.get_quality(), 11);
+    assert_eq!(params.get_mode(), CompressMode::Text);
+    assert_eq!(CompressParams::new().get_mode(), CompressMode::Generic);
+    assert_eq!(CompressParams::from_level(20).get_quality(), 11);
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn serde_roundtrip() {
+    let json = serde_json::to_string(&CompressParams::text()).unwrap();
+    assert!(json.contains("\"mode\":\"text\""));
+    let params: CompressParams = serde_json::from_str(&json).unwrap();
+    assert_eq!(params.get_mode(), CompressMode::Text);
+    assert_eq!(params.get_quality(), 11);
+
+    let params: CompressParams = serde_json::from_str(r#"{"quality": 4}"#).unwrap();
+    assert_eq!(params.get_quality(), 4);
+    assert_eq!(params.get_lgwin(), CompressParams::new().get_lgwin());
+    assert!(serde_json::from_str::<CompressParams>(r#"{"mode": "binary"}"#).is_err());
+}
+
+#[test]
+#[cfg(feature = "fuzzing")]
+fn arbitrary_params_are_valid() {
+    use arbitrary::{Arbitrary, Unstructured};
+    use rand::RngCore;
+
+    let mut data = [0; 64];
+    let mut standalone = 0;
+    let mut d = brotli2::raw::Decompress::new();
+    d.set_params(brotli2::DecompressParams::new().large_window(true))
+        .unwrap();
+    for i in 0..1000 {
+        rand::thread_rng().fill_bytes(&mut data);
+        let params = CompressParams::arbitrary(&mut Unstructured::new(&data)).unwrap();
+        params.validate().unwrap();
+        Compress::new().set_params(&params).unwrap();
+        if i < 300 {
+            let compressed = brotli2::raw::compress_vec(&params, b"hello, hello").unwrap();
+            if d.decompress_msg(&compressed).is_ok() {
+                standalone += 1;
+            }
+        }
+    }
+    // Only the rare parameters with a stream offset produce streams that do
+    // not decode on their own.
+    assert!(standalone > 250, "{} of 300 streams decode", standalone);
+}
+
+#[test]
+fn params_as_keys() {
+    use std::collections::HashSet;
+
+    let mut set = HashSet::new();
+    assert!(set.insert(CompressParams::best()));
+    assert!(set.insert(CompressParams::text()));
+    assert!(!set.insert(CompressParams::best()));
+    assert_eq!(
+        CompressParams::from_level(11).lgwin(24),
+        &CompressParams::best()
+    );
+}
+
+#[test]
+fn ranges() {
+    use brotli2::{BlockSize, Quality, WindowSize};
+
+    assert_eq!(
+        Quality::range(),
+        brotli2::MIN_QUALITY..=brotli2::MAX_QUALITY
+    );
+    assert_eq!(*WindowSize::range().end(), brotli2::MAX_LGWIN);
+    assert_eq!(*WindowSize::large_range().end(), brotli2::LARGE_MAX_LGWIN);
+    for lgblock in BlockSize::range() {
+        assert!(BlockSize::new(lgblock).is_some());
+    }
+    assert!(BlockSize::new(brotli2::MAX_LGBLOCK + 1).is_none());
+}
+
+#[test]
+fn lgwin_for_input() {
+    let mut params = CompressParams::new();
+    assert_eq!(params.lgwin_for_input(0).get_lgwin(), 10);
+    assert_eq!(params.lgwin_for_input(1008).get_lgwin(), 10);
+    assert_eq!(params.lgwin_for_input(1009).get_lgwin(), 11);
+    assert_eq!(params.lgwin_for_input(usize::MAX).get_lgwin(), 24);
+}
+
+#[test]
+fn for_mime() {
+    let text = CompressParams::text();
+    assert_eq!(CompressParams::for_mime("text/css"), text);
+    assert_eq!(
+        CompressParams::for_mime("Application/JSON; charset=utf-8"),
+        text
+    );
+    assert_eq!(CompressParams::for_mime("image/svg+xml"), text);
+    assert_eq!(
+        CompressParams::for_mime("font/ttf").get_mode(),
+        CompressMode::Font
+    );
+    assert_eq!(
+        CompressParams::for_mime("font/woff2"),
+        CompressParams::fastest()
+    );
+    assert_eq!(
+        CompressParams::for_mime("video/mp4"),
+        CompressParams::fastest()
+    );
+    assert_eq!(
+        CompressParams::for_mime("application/wasm"),
+        CompressParams::balanced()
+    );
+    assert_eq!(CompressParams::for_mime(""), CompressParams::balanced());
+}
+
+// Compresses `input` through a streaming encoder, which, unlike the one-shot
+// functions, knows nothing about the input size unless it is hinted.
+fn compress_streaming(params: &CompressParams, input: &[u8]) -> Vec<u8> {
+    use std::io::Write;
+
+    let mut e = brotli2::write::BrotliEncoder::from_params(Vec::new(), params);
+    for chunk in input.chunks(1 << 16) {
+        e.write_all(chunk).unwrap();
+    }
+    e.finish().unwrap()
+}
+
+// Words in a random order, which compress well but not trivially.
+fn text(len: usize) -> Vec<u8> {
+    let words = [
+        "the ", "quick ", "brown ", "fox ", "jumps ", "over ", "a ", "lazy ", "dog. ", "And ",
+        "then ", "more\n",
+    ];
+    let mut x = 1u32;
+    let mut text = Vec::with_capacity(len);
+    while text.len() < len {
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        text.extend_from_slice(words[x as usize % words.len()].as_bytes());
+    }
+    text.truncate(len);
+    text
+}
+
+#[test]
+fn size_hint_changes_output() {
+    let input = text(2 << 20);
+    let mut params = CompressParams::new();
+    params.quality(4);
+    let plain = compress_streaming(&params, &input);
+    params.size_hint(input.len());
+    let hinted = compress_streaming(&params, &input);
+    assert_ne!(plain, hinted);
+    assert_eq!(brotli2::raw::decompress_vec(&plain).unwrap(), input);
+    assert_eq!(brotli2::raw::decompress_vec(&hinted).unwrap(), input);
+}
+
+#[test]
+fn disable_literal_context_modeling_changes_output() {
+    // Below quality 10 libbrotli only models literal contexts for input it
+    // judges to benefit, which this does not.
+    let input = text(1 << 18);
+    let mut params = CompressParams::new();
+    params.quality(10);
+    let modeled = compress_streaming(&params, &input);
+    params.disable_literal_context_modeling(true);
+    let unmodeled = compress_streaming(&params, &input);
+    assert_ne!(modeled, unmodeled);
+    // Context modeling is what makes text compress better.
+    assert!(modeled.len() < unmodeled.len());
+    assert_eq!(brotli2::raw::decompress_vec(&modeled).unwrap(), input);
+    assert_eq!(brotli2::raw::decompress_vec(&unmodeled).unwrap(), input);
+}